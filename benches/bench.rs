@@ -1,8 +1,19 @@
-use criterion::{criterion_group, criterion_main, Criterion};
+use criterion::{criterion_group, BenchmarkId, Criterion};
 use duct::cmd;
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
 
 const BIN: &str = env!("CARGO_BIN_EXE_gitpr");
 
+/// Slowest allowed ratio of fast path (`-S`, `--fs-only`) to simple mode (`-s`) run time
+const MAX_FAST_PATH_RATIO: f64 = 0.75;
+
+/// Runs of each mode timed for the threshold check
+const RATIO_RUNS: u32 = 50;
+
 fn default() {
     cmd!(BIN).read().unwrap();
 }
@@ -11,10 +22,101 @@ fn simple() {
     cmd!(BIN, "-s").read().unwrap();
 }
 
+fn run_in(dir: &Path, flag: &str) {
+    cmd!(BIN, flag, "--dir", dir).read().unwrap();
+}
+
+/// Fixture repos under a temp dir, removed when dropped, even if a check fails
+struct Fixtures {
+    root:  PathBuf,
+    repos: Vec<(&'static str, PathBuf)>,
+}
+
+impl Drop for Fixtures {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
+
+/// Create clean and dirty fixture repos under a temp dir
+fn fixtures() -> Fixtures {
+    let root = env::temp_dir().join(format!("gitpr-bench-{}", std::process::id()));
+    let mut repos = vec![];
+    for name in &["clean", "dirty"] {
+        let dir = root.join(name);
+        fs::create_dir_all(&dir).unwrap();
+        let git = |args: &[&str]| {
+            cmd("git", args)
+                .dir(&dir)
+                .env("GIT_AUTHOR_NAME", "bench")
+                .env("GIT_AUTHOR_EMAIL", "bench@example.com")
+                .env("GIT_COMMITTER_NAME", "bench")
+                .env("GIT_COMMITTER_EMAIL", "bench@example.com")
+                .stdout_null()
+                .run()
+                .unwrap();
+        };
+        git(&["init", "-q"]);
+        for i in 0..100 {
+            fs::write(dir.join(format!("file{}.txt", i)), "line\n").unwrap();
+        }
+        git(&["add", "."]);
+        git(&["commit", "-q", "-m", "init"]);
+        if *name == "dirty" {
+            fs::write(dir.join("file0.txt"), "changed\n").unwrap();
+            fs::write(dir.join("new.txt"), "new\n").unwrap();
+        }
+        repos.push((*name, dir));
+    }
+    Fixtures { root, repos }
+}
+
+/// Total time of `RATIO_RUNS` runs with `flag`
+fn time_runs(dir: &Path, flag: &str) -> Duration {
+    let start = Instant::now();
+    for _ in 0..RATIO_RUNS {
+        run_in(dir, flag);
+    }
+    start.elapsed()
+}
+
 fn criterion_benchmark(c: &mut Criterion) {
     c.bench_function("default", |b| b.iter(|| default()));
     c.bench_function("simple", |b| b.iter(|| simple()));
 }
 
-criterion_group!(benches, criterion_benchmark);
-criterion_main!(benches);
+fn fast_path_benchmark(c: &mut Criterion) {
+    let fixtures = fixtures();
+    let repos = &fixtures.repos;
+    let mut group = c.benchmark_group("fast_path");
+    for (name, dir) in repos {
+        group.bench_with_input(BenchmarkId::new("simple", name), dir, |b, dir| {
+            b.iter(|| run_in(dir, "-s"))
+        });
+        group.bench_with_input(BenchmarkId::new("fs_only", name), dir, |b, dir| {
+            b.iter(|| run_in(dir, "-S"))
+        });
+    }
+    group.finish();
+
+    for (name, dir) in repos {
+        let simple = time_runs(dir, "-s");
+        let fast = time_runs(dir, "-S");
+        let ratio = fast.as_secs_f64() / simple.as_secs_f64();
+        println!("fast_path/{}: {:.2}x simple mode", name, ratio);
+        assert!(
+            ratio <= MAX_FAST_PATH_RATIO,
+            "fast path is {:.2}x simple mode on {} repo; max is {:.2}x",
+            ratio,
+            name,
+            MAX_FAST_PATH_RATIO
+        );
+    }
+}
+
+criterion_group!(benches, criterion_benchmark, fast_path_benchmark);
+
+fn main() {
+    benches();
+    Criterion::default().configure_from_args().final_summary();
+}
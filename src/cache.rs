@@ -0,0 +1,133 @@
+//! On-disk cache of the last output rendered per repo and command line, so a
+//! prompt skips git when nothing changed since the previous one
+use crate::{
+    config::{config_path, REPO_CONFIG},
+    Arg,
+};
+use gitpr::{
+    cache_dir, find_git_dir,
+    output::Escape,
+    repo::{common_dir, find_work_tree, metadata_fingerprint, write_cache},
+    style::ColorDepth,
+    Result,
+};
+use std::{
+    collections::hash_map::DefaultHasher,
+    env, fs,
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Cache entry for the current command line in the current repo
+#[derive(Debug)]
+pub struct Cache {
+    file:        PathBuf,
+    fingerprint: String,
+}
+
+impl Cache {
+    /// Entry for this run, or `None` outside a repo. Call after the current
+    /// dir and environment are set up for the run. Colored and uncolored
+    /// renders are kept apart, since `--color auto` colors output for a
+    /// terminal but not a pipe, and so are renders for terminals with other
+    /// capabilities
+    pub fn open(args: &Arg) -> Option<Cache> {
+        let dir = env::current_dir().ok()?;
+        let git_dir = find_git_dir(&dir)?;
+
+        let mut vars = env::vars()
+            .filter(|(k, _)| {
+                k.starts_with("GITPR_")
+                    || matches!(
+                        k.as_str(),
+                        "NO_COLOR"
+                            | "COLORTERM"
+                            | "TERM"
+                            | "GIT_CEILING_DIRECTORIES"
+                            | "GIT_DIR"
+                            | "GIT_WORK_TREE"
+                    )
+            })
+            .collect::<Vec<_>>();
+        vars.sort();
+        let mut hasher = DefaultHasher::new();
+        dir.hash(&mut hasher);
+        env::args().collect::<Vec<_>>().hash(&mut hasher);
+        vars.hash(&mut hasher);
+        args.colored.hash(&mut hasher);
+        args.format.hash(&mut hasher);
+        capabilities(args).hash(&mut hasher);
+        let file = cache_dir()?.join(format!("{:016x}", hasher.finish()));
+
+        // Config changes output as much as the repo does
+        let mut times = metadata_fingerprint(&git_dir);
+        times.extend(config_mtimes(args.config.as_deref(), &dir, &git_dir));
+        let fingerprint = times
+            .iter()
+            .map(
+                |t| match t.and_then(|t| t.duration_since(UNIX_EPOCH).ok()) {
+                    Some(d) => d.as_nanos().to_string(),
+                    None => "-".to_string(),
+                },
+            )
+            .collect::<Vec<_>>()
+            .join(" ");
+        Some(Cache { file, fingerprint })
+    }
+
+    /// Cached output, its age and whether the repo was dirty, if git metadata
+    /// is unchanged since it was written
+    pub fn read(&self) -> Option<(String, Duration, bool)> {
+        let text = fs::read_to_string(&self.file).ok()?;
+        let (fingerprint, rest) = text.split_once('\n')?;
+        if fingerprint != self.fingerprint {
+            return None;
+        }
+        let (state, output) = rest.split_once('\n')?;
+        let dirty = match state {
+            "dirty" => true,
+            "clean" => false,
+            _ => return None,
+        };
+        let age = fs::metadata(&self.file)
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|t| t.elapsed().ok())
+            .unwrap_or_default();
+        Some((output.to_string(), age, dirty))
+    }
+
+    /// Store output and whether the repo was dirty, replacing the file at once
+    /// so a concurrent prompt never reads a partial entry
+    pub fn write(&self, output: &str, dirty: bool) -> Result {
+        let state = if dirty { "dirty" } else { "clean" };
+        write_cache(
+            &self.file,
+            &format!("{}\n{}\n{}", self.fingerprint, state, output),
+        )
+    }
+}
+
+/// Capabilities of the terminal output is rendered for: its color depth, as
+/// given or detected from the environment, and how escapes are wrapped for the
+/// shell. Cache keys include them, so escapes rendered for a truecolor terminal
+/// are never replayed in a basic one
+pub fn capabilities(args: &Arg) -> (ColorDepth, Escape) {
+    (
+        args.color_depth.unwrap_or_else(ColorDepth::detect),
+        args.escape,
+    )
+}
+
+/// Modification times of the config files that apply in `dir`, a dir of the
+/// repo with git dir `git_dir`: the user config file, or `config` if given,
+/// the repo's `.gitpr.toml` and its git config
+pub fn config_mtimes(config: Option<&Path>, dir: &Path, git_dir: &Path) -> Vec<Option<SystemTime>> {
+    let mtime = |p: PathBuf| fs::metadata(p).and_then(|m| m.modified()).ok();
+    vec![
+        config_path(config).and_then(mtime),
+        find_work_tree(dir).and_then(|d| mtime(d.join(REPO_CONFIG))),
+        mtime(common_dir(git_dir).join("config")),
+    ]
+}
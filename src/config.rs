@@ -0,0 +1,1082 @@
+//! Load and validate the optional TOML config file, and per-repo overrides
+use crate::Arg;
+use anyhow::{format_err, Context};
+use clap::ArgMatches;
+use gitpr::{
+    gitconfig::{parse_bool, GitConfig},
+    glyph::GLYPH_NAMES,
+    repo::{find_work_tree, THRESHOLD_ELEMENTS},
+    style::{parse_style, STYLE_ELEMENTS, THEMES},
+    Result,
+};
+use std::{
+    env, fmt,
+    path::{Path, PathBuf},
+};
+use toml::Value;
+
+/// Expected type of a config value
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    Bool,
+    /// Non-negative integer
+    Int,
+    /// Any string
+    Str,
+    /// String containing format tokens
+    Format,
+    /// String naming an output mode
+    Output,
+    /// String naming a shell escape mode
+    Escape,
+    /// String naming when to color output
+    Color,
+    /// String naming a terminal color depth
+    ColorDepth,
+    /// String naming an untracked files mode
+    Untracked,
+    /// String naming a diff style
+    DiffStyle,
+    /// Table of element names to style specs
+    Style,
+    /// Table of element names to glyph strings
+    Glyph,
+    /// Table of strings to strings
+    Aliases,
+    /// Table of element names to minimum counts
+    Thresholds,
+    /// Array of strings
+    List,
+    /// Table of profile names to tables of config keys
+    Profile,
+}
+
+/// Known top-level keys and their types
+const KEYS: &[(&str, Kind)] = &[
+    ("alert", Kind::Bool),
+    ("auto_fetch", Kind::Int),
+    ("base_branch", Kind::Str),
+    ("cache", Kind::Bool),
+    ("ceiling", Kind::List),
+    ("color", Kind::Color),
+    ("color_depth", Kind::ColorDepth),
+    ("diff_style", Kind::DiffStyle),
+    ("divergence_age", Kind::Bool),
+    ("escape", Kind::Escape),
+    ("expected_email", Kind::Str),
+    ("fast_dirty", Kind::Bool),
+    ("fetch_stale_hours", Kind::Int),
+    ("format", Kind::Format),
+    ("git_locale", Kind::Str),
+    ("hide_below", Kind::Thresholds),
+    ("glyph", Kind::Glyph),
+    ("indicators_only", Kind::Bool),
+    ("line_status", Kind::Bool),
+    ("max_width", Kind::Int),
+    ("no_color", Kind::Bool),
+    ("no_dirty_state", Kind::Bool),
+    ("no_trim", Kind::Bool),
+    ("output", Kind::Output),
+    ("profile", Kind::Profile),
+    ("protected_branches", Kind::List),
+    ("quiet_errors", Kind::Bool),
+    ("renames", Kind::Bool),
+    ("stash_warn_days", Kind::Int),
+    ("theme", Kind::Str),
+    ("style", Kind::Style),
+    ("timeout", Kind::Int),
+    ("timings", Kind::Bool),
+    ("unmerged_detail", Kind::Bool),
+    ("untracked", Kind::Untracked),
+    ("untracked_exclude", Kind::List),
+    ("upstream_alias", Kind::Aliases),
+    ("upstream_fork_org", Kind::Bool),
+    ("upstream_hide_same_name", Kind::Bool),
+];
+
+/// Per-repo config file at the top of the work tree
+pub const REPO_CONFIG: &str = ".gitpr.toml";
+
+/// Keys a repo may set, in `.gitpr.toml` or `gitpr.*` git config. Only what
+/// changes the look of the prompt: a cloned repo can't be trusted with
+/// fetching, caching, timeouts, logging, search limits or escaping
+const REPO_KEYS: &[&str] = &[
+    "alert",
+    "base_branch",
+    "color",
+    "color_depth",
+    "diff_style",
+    "divergence_age",
+    "expected_email",
+    "fast_dirty",
+    "format",
+    "hide_below",
+    "glyph",
+    "indicators_only",
+    "line_status",
+    "max_width",
+    "no_color",
+    "no_dirty_state",
+    "no_trim",
+    "protected_branches",
+    "quiet_errors",
+    "renames",
+    "stash_warn_days",
+    "theme",
+    "style",
+    "unmerged_detail",
+    "untracked",
+    "untracked_exclude",
+    "upstream_alias",
+    "upstream_fork_org",
+    "upstream_hide_same_name",
+];
+
+/// Output modes accepted by `output`
+const OUTPUT_MODES: &[&str] = &[
+    "prompt",
+    "waybar",
+    "polybar",
+    "pango",
+    "i3blocks",
+    "plain-verbose",
+    "json",
+    "tmux",
+    "vim",
+    "powerline",
+    "json-segments",
+    "kv",
+    "fish-vars",
+    "porcelain",
+];
+
+/// Modes accepted by `untracked`
+const UNTRACKED_MODES: &[&str] = &["no", "normal", "all", "auto"];
+
+/// Styles accepted by `diff_style`
+const DIFF_STYLES: &[&str] = &["lines", "files", "both"];
+
+/// Choices accepted by `color`
+const COLOR_CHOICES: &[&str] = &["auto", "always", "never"];
+
+/// Depths accepted by `color_depth`
+const COLOR_DEPTHS: &[&str] = &["16", "256", "truecolor"];
+
+/// Shells accepted by `escape`
+const ESCAPE_MODES: &[&str] = &["zsh", "bash", "powershell", "none"];
+
+/// Problem found in config file, with path to the offending key
+#[derive(Debug, PartialEq)]
+struct ConfigError {
+    path:    String,
+    message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "`{}`: {}", self.path, self.message)
+    }
+}
+
+/// Validated config file contents
+#[derive(Debug)]
+pub struct Config {
+    table: toml::value::Table,
+}
+
+impl Config {
+    /// Load config from `--config` path, or the default location if it exists
+    pub fn load(path: Option<&Path>) -> Result<Option<Config>> {
+        let path = match config_path(path) {
+            Some(p) => p,
+            None => return Ok(None),
+        };
+        let text = std::fs::read_to_string(&path)
+            .with_context(|| format!("cannot read config file {}", path.display()))?;
+        Config::parse(&text)
+            .with_context(|| format!("invalid config file {}", path.display()))
+            .map(Some)
+    }
+
+    /// Load per-repo overrides for the repo containing `dir`: `.gitpr.toml`
+    /// at the top of its work tree, then `gitpr.*` values of `git_config`.
+    /// Keys outside [`REPO_KEYS`] belong in the user config file only
+    pub fn load_repo(dir: &Path, git_config: &GitConfig) -> Result<Option<Config>> {
+        let mut config = match find_work_tree(dir).map(|d| d.join(REPO_CONFIG)) {
+            Some(path) if path.is_file() => {
+                let text = std::fs::read_to_string(&path)
+                    .with_context(|| format!("cannot read config file {}", path.display()))?;
+                Some(
+                    Config::parse(&text)
+                        .with_context(|| format!("invalid config file {}", path.display()))?,
+                )
+            }
+            _ => None,
+        };
+        if git_config.section("gitpr").next().is_some() {
+            let git = Config::parse_git(git_config).context("invalid `gitpr.*` git config")?;
+            match &mut config {
+                Some(c) => c.overlay(&git),
+                None => config = Some(git),
+            }
+        }
+        let denied = config.as_ref().map_or_else(Vec::new, |c| {
+            c.table
+                .keys()
+                .filter(|k| !REPO_KEYS.contains(&k.as_str()))
+                .map(String::as_str)
+                .collect()
+        });
+        if !denied.is_empty() {
+            return Err(format_err!(
+                "{} can only be set in the user config file, not in {} or git config",
+                denied.join(", "),
+                REPO_CONFIG
+            ));
+        }
+        Ok(config)
+    }
+
+    /// Parse and validate config text, reporting all problems at once
+    fn parse(text: &str) -> Result<Config> {
+        let table = match text.parse::<Value>()? {
+            Value::Table(t) => t,
+            _ => return Err(format_err!("config must be a table")),
+        };
+        Config::validated(table)
+    }
+
+    /// Build config from `gitpr.*` git config values. Keys are `gitpr.<key>`
+    /// with `-` for `_`, ex: `gitpr.indicators-only`, or `gitpr.<table>.<entry>`
+    /// for tables, ex: `gitpr.style.branch`. Repeated keys make a list
+    fn parse_git(git_config: &GitConfig) -> Result<Config> {
+        let mut table = toml::value::Table::new();
+        for (name, value) in git_config.section("gitpr") {
+            let (key, field) = match name.split_once('.') {
+                Some((key, field)) => (key.replace('-', "_"), Some(field)),
+                None => (name.replace('-', "_"), None),
+            };
+            let kind = KEYS.iter().find(|(k, _)| *k == key).map(|&(_, kind)| kind);
+            let parsed = match kind {
+                // Git allows no `_` in names, so element names use `-`
+                Some(Kind::Style) | Some(Kind::Glyph) => field.map(|f| f.replace('-', "_")),
+                _ => field.map(String::from),
+            };
+            let value = match (kind, parsed) {
+                (_, Some(field)) => {
+                    let entries = table
+                        .entry(key)
+                        .or_insert_with(|| Value::Table(Default::default()));
+                    let entry = match kind {
+                        Some(Kind::Thresholds) => value
+                            .parse()
+                            .map(Value::Integer)
+                            .unwrap_or_else(|_| Value::String(value.to_string())),
+                        _ => Value::String(value.to_string()),
+                    };
+                    if let Value::Table(t) = entries {
+                        t.insert(field, entry);
+                    }
+                    continue;
+                }
+                (Some(Kind::List), None) => {
+                    let list = table.entry(key).or_insert_with(|| Value::Array(vec![]));
+                    if let Value::Array(a) = list {
+                        a.push(Value::String(value.to_string()));
+                    }
+                    continue;
+                }
+                (kind, None) => scalar_value(kind, value),
+            };
+            table.insert(key, value);
+        }
+        Config::validated(table)
+    }
+
+    /// Build config from `GITPR_<KEY>` environment variables, ex:
+    /// `GITPR_FORMAT`, `GITPR_INDICATORS_ONLY=1`, `GITPR_THEME`
+    pub fn from_env() -> Result<Config> {
+        Config::from_vars(|name| env::var_os(name).map(|v| v.to_string_lossy().into_owned()))
+    }
+
+    /// Build config from variables looked up by `var`. Empty ones count as
+    /// unset, and lists are separated like `PATH`. Tables are left out;
+    /// `GITPR_GLYPH_<NAME>` sets glyphs and `GITPR_PROFILE` the profile
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Config> {
+        let mut table = toml::value::Table::new();
+        for &(key, kind) in KEYS {
+            let name = format!("GITPR_{}", key.to_uppercase());
+            let value = match var(&name).filter(|v| !v.is_empty()) {
+                Some(v) => v,
+                None => continue,
+            };
+            let value = match kind {
+                Kind::Style | Kind::Glyph | Kind::Aliases | Kind::Thresholds | Kind::Profile => {
+                    continue
+                }
+                Kind::List => Value::Array(
+                    env::split_paths(&value)
+                        .map(|p| Value::String(p.to_string_lossy().into_owned()))
+                        .collect(),
+                ),
+                kind => scalar_value(Some(kind), &value),
+            };
+            table.insert(key.to_string(), value);
+        }
+        let mut errors = validate(&table);
+        for e in &mut errors {
+            e.path = format!("GITPR_{}", e.path.to_uppercase());
+        }
+        check_errors(&errors).context("invalid `GITPR_*` environment variable")?;
+        Ok(Config { table })
+    }
+
+    /// Settings implied by git config made for git and `__git_ps1`, so a repo
+    /// where they turn off slow parts of `git status` is fast here too:
+    /// `bash.showUntrackedFiles` and `status.showUntrackedFiles` set
+    /// `untracked`, and `bash.showDirtyState` sets `no_dirty_state`
+    pub fn from_git_prompt_settings(git_config: &GitConfig) -> Config {
+        let mut table = toml::value::Table::new();
+        let untracked = match git_config.get("status.showUntrackedFiles") {
+            _ if git_config.get_bool("bash.showUntrackedFiles") == Some(false) => Some("no"),
+            Some(v) if parse_bool(v) == Some(false) => Some("no"),
+            Some("normal") => Some("normal"),
+            Some("all") => Some("all"),
+            _ => None,
+        };
+        if let Some(u) = untracked {
+            table.insert("untracked".to_string(), Value::String(u.to_string()));
+        }
+        if git_config.get_bool("bash.showDirtyState") == Some(false) {
+            table.insert("no_dirty_state".to_string(), Value::Boolean(true));
+        }
+        Config { table }
+    }
+
+    /// Config with `table`, if it passes validation
+    fn validated(table: toml::value::Table) -> Result<Config> {
+        check_errors(&validate(&table))?;
+        Ok(Config { table })
+    }
+
+    /// Override top-level keys with those of `[profile.<name>]`. Its `style`,
+    /// `glyph` and `upstream_alias` tables add to the top-level ones
+    pub fn select_profile(&mut self, name: &str) -> Result {
+        let profiles = match self.table.remove("profile") {
+            Some(Value::Table(t)) => t,
+            _ => toml::value::Table::new(),
+        };
+        match profiles.get(name) {
+            Some(Value::Table(profile)) => self.overlay(&Config {
+                table: profile.clone(),
+            }),
+            _ => {
+                return Err(format_err!(
+                    "no profile \"{}\" in config file{}; profiles: {}",
+                    name,
+                    suggest(name, profiles.keys().map(String::as_str))
+                        .map(|m| format!(" (did you mean \"{}\"?)", m))
+                        .unwrap_or_default(),
+                    profiles.keys().cloned().collect::<Vec<_>>().join(", ")
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    /// Override keys with those of `other`, a profile or per-repo config.
+    /// Entries of tables are added to the tables they override
+    pub fn overlay(&mut self, other: &Config) {
+        for (key, value) in &other.table {
+            match (self.table.get_mut(key), value) {
+                (Some(Value::Table(top)), Value::Table(entries)) => {
+                    top.extend(entries.iter().map(|(k, v)| (k.clone(), v.clone())))
+                }
+                _ => {
+                    self.table.insert(key.clone(), value.clone());
+                }
+            }
+        }
+    }
+
+    /// Dirs of the `ceiling` list
+    pub fn ceiling(&self) -> Vec<PathBuf> {
+        match self.table.get("ceiling") {
+            Some(Value::Array(dirs)) => dirs
+                .iter()
+                .filter_map(Value::as_str)
+                .map(PathBuf::from)
+                .collect(),
+            _ => vec![],
+        }
+    }
+
+    /// Fill in options not given on the command line
+    pub fn apply(&self, args: &mut Arg, matches: &ArgMatches) -> Result {
+        let from_cli = |name: &str| matches.occurrences_of(name) > 0;
+        let get_bool = |key: &str| self.table.get(key).and_then(Value::as_bool);
+        let get_str = |key: &str| self.table.get(key).and_then(Value::as_str);
+
+        macro_rules! apply_bool {
+            ($($field:ident),*) => {
+                $(if !from_cli(stringify!($field)) {
+                    if let Some(b) = get_bool(stringify!($field)) {
+                        args.$field = b;
+                    }
+                })*
+            };
+        }
+        apply_bool!(
+            alert,
+            cache,
+            divergence_age,
+            fast_dirty,
+            indicators_only,
+            line_status,
+            no_color,
+            no_dirty_state,
+            no_trim,
+            quiet_errors,
+            renames,
+            timings,
+            unmerged_detail
+        );
+
+        if !from_cli("format") {
+            if let Some(f) = get_str("format") {
+                args.format = f.to_string();
+            }
+        }
+        if !from_cli("base_branch") {
+            if let Some(b) = get_str("base_branch") {
+                args.base_branch = Some(b.to_string());
+            }
+        }
+        if !from_cli("expected_email") {
+            if let Some(e) = get_str("expected_email") {
+                args.expected_email = Some(e.to_string());
+            }
+        }
+        if !from_cli("git_locale") {
+            if let Some(l) = get_str("git_locale") {
+                args.git_locale = l.to_string();
+            }
+        }
+        if !from_cli("stash_warn_days") {
+            if let Some(days) = self
+                .table
+                .get("stash_warn_days")
+                .and_then(Value::as_integer)
+            {
+                args.stash_warn_days = days as u64;
+            }
+        }
+        if !from_cli("auto_fetch") {
+            if let Some(minutes) = self.table.get("auto_fetch").and_then(Value::as_integer) {
+                args.auto_fetch = Some(Some(minutes as u64));
+            }
+        }
+        if !from_cli("fetch_stale_hours") {
+            if let Some(hours) = self
+                .table
+                .get("fetch_stale_hours")
+                .and_then(Value::as_integer)
+            {
+                args.fetch_stale_hours = hours as u64;
+            }
+        }
+        if !from_cli("timeout") {
+            if let Some(ms) = self.table.get("timeout").and_then(Value::as_integer) {
+                args.timeout = Some(ms as u64);
+            }
+        }
+        if !from_cli("max_width") {
+            if let Some(n) = self.table.get("max_width").and_then(Value::as_integer) {
+                args.max_width = Some(n as usize);
+            }
+        }
+        if !from_cli("output") {
+            if let Some(o) = get_str("output") {
+                args.output = o.parse()?;
+            }
+        }
+        if !from_cli("escape") {
+            if let Some(e) = get_str("escape") {
+                args.escape = e.parse()?;
+            }
+        }
+        if !from_cli("theme") {
+            if let Some(t) = get_str("theme") {
+                args.theme = Some(t.to_string());
+            }
+        }
+        if !from_cli("color") {
+            if let Some(c) = get_str("color") {
+                args.color = c.parse()?;
+            }
+        }
+        if !from_cli("color_depth") {
+            if let Some(d) = get_str("color_depth") {
+                args.color_depth = Some(d.parse()?);
+            }
+        }
+        if !from_cli("untracked") {
+            if let Some(u) = get_str("untracked") {
+                args.untracked = u.parse()?;
+            }
+        }
+        if !from_cli("diff_style") {
+            if let Some(d) = get_str("diff_style") {
+                args.diff_style = d.parse()?;
+            }
+        }
+        args.ceiling.extend(self.ceiling());
+        if let Some(Value::Array(globs)) = self.table.get("protected_branches") {
+            args.protected_branch
+                .extend(globs.iter().filter_map(Value::as_str).map(String::from));
+        }
+        if let Some(Value::Array(globs)) = self.table.get("untracked_exclude") {
+            args.untracked_exclude
+                .extend(globs.iter().filter_map(Value::as_str).map(String::from));
+        }
+        // Config styles come first so `--style` overrides them
+        if let Some(Value::Table(styles)) = self.table.get("style") {
+            let mut merged = styles
+                .iter()
+                .filter_map(|(k, v)| v.as_str().map(|s| format!("{}={}", k, s)))
+                .collect::<Vec<_>>();
+            merged.append(&mut args.style);
+            args.style = merged;
+        }
+        // Config thresholds come first so `--hide-below` overrides them
+        if let Some(Value::Table(thresholds)) = self.table.get("hide_below") {
+            let mut merged = thresholds
+                .iter()
+                .filter_map(|(k, v)| v.as_integer().map(|n| format!("{}={}", k, n)))
+                .collect::<Vec<_>>();
+            merged.append(&mut args.hide_below);
+            args.hide_below = merged;
+        }
+        if let Some(Value::Table(aliases)) = self.table.get("upstream_alias") {
+            args.upstream.aliases = aliases
+                .iter()
+                .filter_map(|(k, v)| v.as_str().map(|s| (k.clone(), s.to_string())))
+                .collect();
+        }
+        args.upstream.hide_same_name = get_bool("upstream_hide_same_name").unwrap_or(false);
+        args.upstream.fork_org = get_bool("upstream_fork_org").unwrap_or(false);
+        if let Some(Value::Table(glyphs)) = self.table.get("glyph") {
+            args.glyph = glyphs
+                .iter()
+                .filter_map(|(k, v)| v.as_str().map(|s| format!("{}={}", k, s)))
+                .collect();
+        }
+        Ok(())
+    }
+}
+
+/// Value of a non-table key given as text, read as `kind`. Text that isn't
+/// the right type is kept, so validation reports it
+fn scalar_value(kind: Option<Kind>, value: &str) -> Value {
+    match kind {
+        Some(Kind::Bool) => parse_bool(value)
+            .map(Value::Boolean)
+            .unwrap_or_else(|| Value::String(value.to_string())),
+        Some(Kind::Int) => value
+            .parse()
+            .map(Value::Integer)
+            .unwrap_or_else(|_| Value::String(value.to_string())),
+        _ => Value::String(value.to_string()),
+    }
+}
+
+/// Error listing all `errors`, if there are any
+fn check_errors(errors: &[ConfigError]) -> Result {
+    if errors.is_empty() {
+        return Ok(());
+    }
+    let list = errors
+        .iter()
+        .map(|e| format!("  - {}", e))
+        .collect::<Vec<_>>()
+        .join("\n");
+    Err(format_err!("{} problem(s) found:\n{}", errors.len(), list))
+}
+
+/// Style settings of theme `name`, in `--style` form: a built-in theme, a
+/// TOML file path, or `themes/<name>.toml` in the config dir. A theme file
+/// has a `[style]` table like the config file, and may build on another theme
+/// named by `inherits`
+pub fn theme_styles(name: &str) -> Result<Vec<String>> {
+    load_theme(name, 0)
+}
+
+/// Theme `name`, reached through `depth` themes inheriting from it
+fn load_theme(name: &str, depth: usize) -> Result<Vec<String>> {
+    if let Some((_, settings)) = THEMES.iter().find(|(n, _)| *n == name) {
+        return Ok(settings.iter().map(|s| s.to_string()).collect());
+    }
+    if depth > 8 {
+        return Err(format_err!("theme \"{}\" inherits too deeply", name));
+    }
+    let path = theme_path(name).ok_or_else(|| {
+        let names = THEMES.iter().map(|(n, _)| *n).collect::<Vec<_>>();
+        format_err!(
+            "unknown theme \"{}\"; expected a theme file or one of: {}",
+            name,
+            names.join(", ")
+        )
+    })?;
+    let text = std::fs::read_to_string(&path)
+        .with_context(|| format!("cannot read theme file {}", path.display()))?;
+    let table = match text.parse::<Value>() {
+        Ok(Value::Table(t)) => t,
+        Ok(_) => return Err(format_err!("theme file {} must be a table", path.display())),
+        Err(e) => return Err(e).context(format!("invalid theme file {}", path.display())),
+    };
+    check_errors(&validate_theme(&table))
+        .with_context(|| format!("invalid theme file {}", path.display()))?;
+    let mut styles = match table.get("inherits").and_then(Value::as_str) {
+        Some(base) => load_theme(base, depth + 1)?,
+        None => vec![],
+    };
+    if let Some(Value::Table(t)) = table.get("style") {
+        styles.extend(
+            t.iter()
+                .filter_map(|(k, v)| v.as_str().map(|s| format!("{}={}", k, s))),
+        );
+    }
+    Ok(styles)
+}
+
+/// File of theme `name`: itself if it's a path to a `.toml` file, else
+/// `themes/<name>.toml` in the config dir, if it exists
+fn theme_path(name: &str) -> Option<PathBuf> {
+    let path = Path::new(name);
+    if path.extension().is_some_and(|e| e == "toml") || path.components().count() > 1 {
+        return Some(path.to_path_buf());
+    }
+    let path = default_path()?
+        .parent()?
+        .join("themes")
+        .join(format!("{}.toml", name));
+    Some(path).filter(|p| p.is_file())
+}
+
+/// Check theme file keys: `inherits` and the `style` table
+fn validate_theme(table: &toml::value::Table) -> Vec<ConfigError> {
+    let mut errors = vec![];
+    for (key, value) in table {
+        match (key.as_str(), value) {
+            ("inherits", Value::String(_)) => (),
+            ("style", Value::Table(styles)) => errors.extend(validate_styles(styles)),
+            ("inherits", v) => errors.push(ConfigError {
+                path:    key.clone(),
+                message: format!("expected string, found {}", v.type_str()),
+            }),
+            ("style", v) => errors.push(ConfigError {
+                path:    key.clone(),
+                message: format!("expected table, found {}", v.type_str()),
+            }),
+            _ => errors.push(ConfigError {
+                path:    key.clone(),
+                message: "unknown theme key; expected `inherits` or `style`".to_string(),
+            }),
+        }
+    }
+    errors
+}
+
+/// Config file in use: `--config` path, or the default location if it exists
+pub fn config_path(path: Option<&Path>) -> Option<PathBuf> {
+    match path {
+        Some(p) => Some(p.to_path_buf()),
+        None => default_path().filter(|p| p.is_file()),
+    }
+}
+
+/// Config file location: `$XDG_CONFIG_HOME/gitpr/config.toml`, falling back to
+/// `~/.config/gitpr/config.toml`
+fn default_path() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")))
+        .map(|d| d.join("gitpr").join("config.toml"))
+}
+
+/// Check every key in table against the schema
+fn validate(table: &toml::value::Table) -> Vec<ConfigError> {
+    let mut errors = vec![];
+    for (key, value) in table {
+        let kind = match KEYS.iter().find(|(k, _)| k == key) {
+            Some((_, kind)) => *kind,
+            None => {
+                let names = KEYS.iter().map(|(k, _)| *k);
+                errors.push(ConfigError {
+                    path:    key.clone(),
+                    message: match suggest(key, names) {
+                        Some(s) => format!("unknown key (did you mean `{}`?)", s),
+                        None => "unknown key".to_string(),
+                    },
+                });
+                continue;
+            }
+        };
+        if let (Kind::Style, Value::Table(styles)) = (kind, value) {
+            errors.extend(validate_styles(styles));
+            continue;
+        }
+        if let (Kind::Glyph, Value::Table(glyphs)) = (kind, value) {
+            errors.extend(validate_glyphs(glyphs));
+            continue;
+        }
+        if let (Kind::Profile, Value::Table(profiles)) = (kind, value) {
+            errors.extend(validate_profiles(profiles));
+            continue;
+        }
+        if let (Kind::Thresholds, Value::Table(thresholds)) = (kind, value) {
+            errors.extend(validate_thresholds(thresholds));
+            continue;
+        }
+        if let Err(message) = check_value(kind, value) {
+            errors.push(ConfigError {
+                path: key.clone(),
+                message,
+            });
+        }
+    }
+    errors
+}
+
+/// Check each profile is a table of valid top-level keys, other than `profile`
+fn validate_profiles(profiles: &toml::value::Table) -> Vec<ConfigError> {
+    let mut errors = vec![];
+    for (name, profile) in profiles {
+        let path = format!("profile.{}", name);
+        let profile = match profile {
+            Value::Table(t) => t,
+            v => {
+                errors.push(ConfigError {
+                    message: format!("expected table, found {}", v.type_str()),
+                    path,
+                });
+                continue;
+            }
+        };
+        for mut e in validate(profile) {
+            e.path = format!("{}.{}", path, e.path);
+            errors.push(e);
+        }
+        if profile.contains_key("profile") {
+            errors.push(ConfigError {
+                path:    format!("{}.profile", path),
+                message: "profiles can't be nested".to_string(),
+            });
+        }
+    }
+    errors
+}
+
+/// Check each entry of `style` table names an element and parses as a style
+fn validate_styles(styles: &toml::value::Table) -> Vec<ConfigError> {
+    let mut errors = vec![];
+    for (element, spec) in styles {
+        let path = format!("style.{}", element);
+        if !STYLE_ELEMENTS.contains(&element.as_str()) {
+            errors.push(ConfigError {
+                message: match suggest(element, STYLE_ELEMENTS.iter().copied()) {
+                    Some(s) => format!("unknown style element (did you mean `{}`?)", s),
+                    None => "unknown style element".to_string(),
+                },
+                path,
+            });
+            continue;
+        }
+        let result = match spec {
+            Value::String(s) => parse_style(s).map(|_| ()).map_err(|e| e.to_string()),
+            v => Err(format!("expected string, found {}", v.type_str())),
+        };
+        if let Err(message) = result {
+            errors.push(ConfigError { path, message });
+        }
+    }
+    errors
+}
+
+/// Check each entry of `glyph` table names an element and is a string
+fn validate_glyphs(glyphs: &toml::value::Table) -> Vec<ConfigError> {
+    let mut errors = vec![];
+    for (name, glyph) in glyphs {
+        let path = format!("glyph.{}", name);
+        if !GLYPH_NAMES.contains(&name.as_str()) {
+            errors.push(ConfigError {
+                message: match suggest(name, GLYPH_NAMES.iter().copied()) {
+                    Some(s) => format!("unknown glyph (did you mean `{}`?)", s),
+                    None => "unknown glyph".to_string(),
+                },
+                path,
+            });
+        } else if !glyph.is_str() {
+            errors.push(ConfigError {
+                message: format!("expected string, found {}", glyph.type_str()),
+                path,
+            });
+        }
+    }
+    errors
+}
+
+/// Check each entry of `hide_below` table names a count and is a non-negative
+/// integer
+fn validate_thresholds(thresholds: &toml::value::Table) -> Vec<ConfigError> {
+    let mut errors = vec![];
+    for (element, min) in thresholds {
+        let path = format!("hide_below.{}", element);
+        if !THRESHOLD_ELEMENTS.contains(&element.as_str()) {
+            errors.push(ConfigError {
+                message: match suggest(element, THRESHOLD_ELEMENTS.iter().copied()) {
+                    Some(s) => format!("unknown threshold element (did you mean `{}`?)", s),
+                    None => "unknown threshold element".to_string(),
+                },
+                path,
+            });
+        } else if let Err(message) = check_value(Kind::Int, min) {
+            errors.push(ConfigError { path, message });
+        }
+    }
+    errors
+}
+
+/// Check a single value against its expected kind
+fn check_value(kind: Kind, value: &Value) -> std::result::Result<(), String> {
+    match (kind, value) {
+        (Kind::Bool, Value::Boolean(_)) => Ok(()),
+        (Kind::Bool, v) => Err(format!("expected true or false, found {}", v.type_str())),
+        (Kind::Int, Value::Integer(i)) if *i >= 0 => Ok(()),
+        (Kind::Int, v) => Err(format!("expected non-negative integer, found {}", v)),
+        (Kind::Str, Value::String(_)) => Ok(()),
+        (Kind::Format, Value::String(s)) => check_format(s),
+        (Kind::Output, Value::String(s)) => check_choice(s, "output mode", OUTPUT_MODES),
+        (Kind::Escape, Value::String(s)) => check_choice(s, "escape mode", ESCAPE_MODES),
+        (Kind::Color, Value::String(s)) => check_choice(s, "color choice", COLOR_CHOICES),
+        (Kind::ColorDepth, Value::String(s)) => check_choice(s, "color depth", COLOR_DEPTHS),
+        (Kind::Untracked, Value::String(s)) => check_choice(s, "untracked mode", UNTRACKED_MODES),
+        (Kind::DiffStyle, Value::String(s)) => check_choice(s, "diff style", DIFF_STYLES),
+        (Kind::Aliases, Value::Table(t)) => match t.values().find(|v| !v.is_str()) {
+            Some(v) => Err(format!("expected table of strings, found {}", v.type_str())),
+            None => Ok(()),
+        },
+        (Kind::Style, v)
+        | (Kind::Glyph, v)
+        | (Kind::Aliases, v)
+        | (Kind::Thresholds, v)
+        | (Kind::Profile, v) => Err(format!("expected table, found {}", v.type_str())),
+        (Kind::List, Value::Array(a)) => match a.iter().find(|v| !v.is_str()) {
+            Some(v) => Err(format!("expected array of strings, found {}", v.type_str())),
+            None => Ok(()),
+        },
+        (Kind::List, v) => Err(format!("expected array, found {}", v.type_str())),
+        (_, v) => Err(format!("expected string, found {}", v.type_str())),
+    }
+}
+
+/// Check that string is one of `choices`
+fn check_choice(s: &str, what: &str, choices: &[&str]) -> std::result::Result<(), String> {
+    if choices.contains(&s) {
+        return Ok(());
+    }
+    Err(format!(
+        "invalid {} \"{}\"{}; expected one of: {}",
+        what,
+        s,
+        suggest(s, choices.iter().copied())
+            .map(|m| format!(" (did you mean \"{}\"?)", m))
+            .unwrap_or_default(),
+        choices.join(", ")
+    ))
+}
+
+/// Check that format string parses
+fn check_format(fmt: &str) -> std::result::Result<(), String> {
+    gitpr::format::parse(fmt)
+        .map(|_| ())
+        .map_err(|e| format!("{}; see `gitpr --help` for valid tokens", e))
+}
+
+/// Suggest closest candidate to a misspelled name
+fn suggest<'a>(name: &str, candidates: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    candidates
+        .map(|c| (edit_distance(name, c), c))
+        .filter(|(d, _)| *d <= 2)
+        .min_by_key(|(d, _)| *d)
+        .map(|(_, c)| c)
+}
+
+/// Levenshtein distance between two strings
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == *cb { 0 } else { 1 };
+            cur.push((prev[j] + cost).min(prev[j + 1] + 1).min(cur[j] + 1));
+        }
+        prev = cur;
+    }
+    prev[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn reports_all_problems() {
+        let table = match "formt = '%b'\nalert = 1\noutput = 'waybr'\nformat = '%b %q'"
+            .parse::<Value>()
+            .unwrap()
+        {
+            Value::Table(t) => t,
+            _ => unreachable!(),
+        };
+        let errors = validate(&table);
+        let paths = errors.iter().map(|e| e.path.as_str()).collect::<Vec<_>>();
+        assert_eq!(paths, vec!["alert", "format", "formt", "output"]);
+        assert!(errors[2].message.contains("did you mean `format`?"));
+        assert!(errors[3].message.contains("did you mean \"waybar\"?"));
+    }
+
+    #[test]
+    fn theme_file_keys() {
+        let table = match "inherits = 'nord'\ncolor = 'red'\n[style]\nbranch = 'blu'"
+            .parse::<Value>()
+            .unwrap()
+        {
+            Value::Table(t) => t,
+            _ => unreachable!(),
+        };
+        let errors = validate_theme(&table);
+        let paths = errors.iter().map(|e| e.path.as_str()).collect::<Vec<_>>();
+        assert_eq!(paths, vec!["color", "style.branch"]);
+        assert_eq!(load_theme("mono", 0).unwrap()[1], "branch=255");
+    }
+
+    #[test]
+    fn valid_config() {
+        assert!(Config::parse("format = '%b %a'\nno_trim = true").is_ok());
+        assert!(Config::parse("[style]\nbranch = 'blue,bold'\nuntracked = '245'").is_ok());
+        assert!(Config::parse("[style]\nbrnch = 'blue'").is_err());
+        assert!(Config::parse("[glyph]\nahead = '↑'\nstash = '≡'").is_ok());
+        assert!(Config::parse("[glyph]\nahed = '↑'").is_err());
+        assert!(Config::parse("[hide_below]\nuntracked = 3\nstash = 2").is_ok());
+        assert!(Config::parse("[hide_below]\nuntracked = '3'").is_err());
+        assert!(Config::parse("[hide_below]\nuntraked = 3").is_err());
+        assert!(
+            Config::parse("[profile.ssh]\nformat = '%b'\n[profile.ssh.style]\nbranch = 'red'")
+                .is_ok()
+        );
+        assert!(Config::parse("[profile.ssh]\nformt = '%b'").is_err());
+        assert!(Config::parse("[profile.ssh.profile.x]\nformat = '%b'").is_err());
+    }
+
+    #[test]
+    fn repo_display_keys_only() -> Result {
+        let repo = |out: &str| Config::load_repo(Path::new("/"), &GitConfig::parse(out));
+        assert!(repo("gitpr.format\n%b\0gitpr.style.branch\nred\0")?.is_some());
+        for entry in &[
+            "auto-fetch\n60",
+            "cache\ntrue",
+            "timings\ntrue",
+            "ceiling\n/a",
+        ] {
+            let err = repo(&format!("gitpr.{}\0", entry)).unwrap_err();
+            assert!(err.to_string().contains("user config file"), "{}", entry);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn git_config_keys() -> Result {
+        let cfg = Config::parse_git(&GitConfig::parse(concat!(
+            "gitpr.format\n%b\0",
+            "gitpr.indicators-only\nyes\0",
+            "gitpr.timeout\n50\0",
+            "gitpr.style.modified-unstaged\nred\0",
+            "gitpr.ceiling\n/a\0",
+            "gitpr.ceiling\n/b\0",
+            "bash.showdirtystate\nfalse\0",
+        )))?;
+        let expected = Config::parse(
+            "format = '%b'\nindicators_only = true\ntimeout = 50\nceiling = ['/a', \
+             '/b']\n[style]\nmodified_unstaged = 'red'",
+        )?;
+        assert_eq!(cfg.table, expected.table);
+        assert!(Config::parse_git(&GitConfig::parse("gitpr.timeout\nsoon\0")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn env_vars() -> Result {
+        let vars = |pairs: &'static [(&'static str, &'static str)]| {
+            Config::from_vars(move |name| {
+                pairs
+                    .iter()
+                    .find(|(n, _)| *n == name)
+                    .map(|(_, v)| v.to_string())
+            })
+        };
+        let cfg = vars(&[
+            ("GITPR_FORMAT", "%b %x"),
+            ("GITPR_INDICATORS_ONLY", "1"),
+            ("GITPR_TIMEOUT", "50"),
+            ("GITPR_THEME", "nord"),
+            ("GITPR_ALERT", ""),
+        ])?;
+        let expected = Config::parse(
+            "format = '%b %x'\nindicators_only = true\ntimeout = 50\ntheme = 'nord'",
+        )?;
+        assert_eq!(cfg.table, expected.table);
+        let err = vars(&[("GITPR_TIMEOUT", "soon")]).unwrap_err();
+        assert!(format!("{:#}", err).contains("`GITPR_TIMEOUT`"));
+        Ok(())
+    }
+
+    #[test]
+    fn git_prompt_settings() -> Result {
+        let settings = |out: &str| Config::from_git_prompt_settings(&GitConfig::parse(out)).table;
+        assert_eq!(
+            settings("status.showuntrackedfiles\nno\0bash.showdirtystate\nfalse\0"),
+            Config::parse("untracked = 'no'\nno_dirty_state = true")?.table
+        );
+        assert_eq!(
+            settings("status.showuntrackedfiles\nall\0bash.showuntrackedfiles\nfalse\0"),
+            Config::parse("untracked = 'no'")?.table
+        );
+        assert_eq!(settings("bash.showdirtystate\ntrue\0"), Default::default());
+        Ok(())
+    }
+
+    #[test]
+    fn profile_overrides() -> Result {
+        let mut cfg = Config::parse(
+            "format = '%b %a %d'\nalert = true\n[style]\nbranch = 'blue'\ncommit = \
+             'green'\n[profile.ssh]\nformat = '%b'\n[profile.ssh.style]\nbranch = 'red'",
+        )?;
+        let err = Config {
+            table: cfg.table.clone(),
+        }
+        .select_profile("sssh");
+        assert!(err
+            .unwrap_err()
+            .to_string()
+            .contains("did you mean \"ssh\"?"));
+        cfg.select_profile("ssh")?;
+        let expected = Config::parse(
+            "format = '%b'\nalert = true\n[style]\nbranch = 'red'\ncommit = 'green'",
+        )?;
+        assert_eq!(cfg.table, expected.table);
+        Ok(())
+    }
+}
@@ -0,0 +1,332 @@
+//! Background process caching rendered status per repo, queried over a Unix
+//! socket or named pipe so prompts don't have to wait on git
+use crate::{
+    cache::{capabilities, config_mtimes},
+    configure, run,
+    timings::Timer,
+    transport::{connect, Listener, Stream},
+    Arg,
+};
+use anyhow::format_err;
+use clap::{ArgMatches, FromArgMatches, IntoApp};
+use gitpr::{
+    find_git_dir, output::mark_cached_json, repo::metadata_fingerprint, OutputMode, Result,
+};
+use log::debug;
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    env,
+    io::{self, IsTerminal},
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Longest a cached render is reused, since edits to worktree files and theme
+/// files don't touch any of the watched git metadata or config files
+const MAX_AGE: Duration = Duration::from_secs(2);
+
+/// Longest the client waits for the daemon before rendering itself
+const CLIENT_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Longest the daemon waits on a client to send its request or read the
+/// response; well under `CLIENT_TIMEOUT`, so prompts queued behind a stalled
+/// client are still answered
+const SERVER_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// Version of the request and response format. Each is a JSON object on one
+/// line: requests of `version`, `method` and `params`, and responses of `version`,
+/// `capabilities` and a `result` or an `error` with a `code` and `message`.
+/// Either side refuses another version, so the client renders itself
+const PROTOCOL_VERSION: u64 = 1;
+
+/// Methods this daemon serves, sent with each response so a client can tell
+/// a missing method from a failed one
+const CAPABILITIES: &[&str] = &["render", "reload"];
+
+/// Error codes, as in JSON-RPC
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const VERSION_MISMATCH: i64 = -32000;
+const RENDER_FAILED: i64 = -32001;
+
+/// True if environment variable `key` affects rendering, so the client sends
+/// its value and the daemon uses it in place of its own
+fn forwarded(key: &str) -> bool {
+    key.starts_with("GITPR_")
+        || key.starts_with("GIT_")
+        || matches!(
+            key,
+            "NO_COLOR" | "CLICOLOR_FORCE" | "COLORTERM" | "TERM" | "WT_SESSION"
+        )
+}
+
+/// Render cached for one command line in one directory
+struct Entry {
+    fingerprint: Vec<Option<SystemTime>>,
+    rendered:    Instant,
+    output:      String,
+    /// Rendered with `--output json`, so stale fields can be marked
+    json:        bool,
+}
+
+/// Renders cached by the daemon
+struct Renders {
+    /// Entries by command line, directory and terminal
+    entries:     HashMap<String, Entry>,
+    /// When each repo, by git dir, was last rendered
+    refreshed:   HashMap<PathBuf, Instant>,
+    /// Least time between renders of a repo
+    min_refresh: Duration,
+}
+
+/// Serve requests on `path` until killed, or until no request came for `idle`.
+/// A client that fails or stalls is dropped, and the next one served. Each
+/// repo is rendered at most once per `min_refresh`, so one changing constantly,
+/// ex: during a build, doesn't keep the daemon running git
+pub fn serve(path: &Path, idle: Option<Duration>, min_refresh: Duration) -> Result {
+    let mut listener = Listener::bind(path, SERVER_TIMEOUT)?;
+    let mut cache = Renders {
+        entries: HashMap::new(),
+        refreshed: HashMap::new(),
+        min_refresh,
+    };
+    loop {
+        if let Some(idle) = idle {
+            if !listener.wait(idle)? {
+                debug!("no request for {:?}, exiting", idle);
+                return Ok(());
+            }
+        }
+        let result = listener
+            .accept()
+            .map_err(anyhow::Error::from)
+            .and_then(|stream| handle(stream, &mut cache));
+        if let Err(e) = result {
+            debug!("client failed: {:#}", e);
+        }
+    }
+}
+
+/// Read one request from `stream` and write the response. A `render` request
+/// is answered with output for the client's command line, and a `reload`
+/// request drops all cached renders
+fn handle(mut stream: Stream, cache: &mut Renders) -> Result {
+    let request = stream.read_line()?;
+    let outcome = match serde_json::from_str::<Value>(&request) {
+        Err(e) => Err((INVALID_REQUEST, format!("invalid request: {}", e))),
+        Ok(request) if request["version"] != PROTOCOL_VERSION => Err((
+            VERSION_MISMATCH,
+            format!(
+                "daemon speaks protocol version {}, not {}",
+                PROTOCOL_VERSION, request["version"]
+            ),
+        )),
+        Ok(request) => match request["method"].as_str() {
+            Some("render") => respond(&request["params"], cache)
+                .map(|(output, complete)| json!({ "output": output, "complete": complete }))
+                .map_err(|e| (RENDER_FAILED, format!("{:#}", e))),
+            Some("reload") => {
+                cache.entries.clear();
+                cache.refreshed.clear();
+                Ok(Value::Null)
+            }
+            _ => Err((
+                METHOD_NOT_FOUND,
+                format!("unknown method {}", request["method"]),
+            )),
+        },
+    };
+    let mut response = json!({ "version": PROTOCOL_VERSION, "capabilities": CAPABILITIES });
+    match outcome {
+        Ok(result) => response["result"] = result,
+        Err((code, message)) => response["error"] = json!({ "code": code, "message": message }),
+    }
+    stream.write_line(&response.to_string())?;
+    Ok(())
+}
+
+/// Render output for the params of a client request, reusing the cached render while the
+/// repo's git metadata and config files are unchanged. Also returns false if
+/// the output is partial because of `--timeout`
+fn respond(request: &Value, cache: &mut Renders) -> Result<(String, bool)> {
+    let dir = PathBuf::from(request["dir"].as_str().unwrap_or_default());
+    let argv = request["args"]
+        .as_array()
+        .map(|a| a.iter().filter_map(Value::as_str).collect::<Vec<_>>())
+        .unwrap_or_default();
+    let vars = request["env"]
+        .as_object()
+        .map(|o| {
+            o.iter()
+                .filter_map(|(k, v)| v.as_str().map(|v| (k.clone(), v.to_string())))
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+
+    let cwd = request["cwd"].as_str().map(PathBuf::from);
+    let tty = request["tty"].as_bool().unwrap_or(false);
+
+    // Render with the client's command line, directory and environment, not
+    // the daemon's or an earlier client's. Relative paths in the command line
+    // are from where the client ran
+    for (k, _) in env::vars_os() {
+        if k.to_str().is_some_and(forwarded) {
+            env::remove_var(k);
+        }
+    }
+    for (k, v) in vars.iter().filter(|(k, _)| forwarded(k)) {
+        env::set_var(k, v);
+    }
+    if let Some(cwd) = &cwd {
+        env::set_current_dir(cwd)?;
+    }
+    cached_or_render(&argv, &dir, cwd.as_deref(), &vars, tty, cache)
+}
+
+/// Output cached for the request, or a new render
+fn cached_or_render(
+    argv: &[&str],
+    dir: &Path,
+    cwd: Option<&Path>,
+    vars: &[(String, String)],
+    tty: bool,
+    cache: &mut Renders,
+) -> Result<(String, bool)> {
+    // Config is read afresh for each render, so changing it takes effect as
+    // soon as its cached renders are dropped. The capabilities it sets are the
+    // same for every terminal, so those from the command line and the client's
+    // environment tell terminals apart
+    let matches = Arg::into_app().try_get_matches_from(argv)?;
+    let args = Arg::from_arg_matches(&matches);
+    let key = format!(
+        "{}\0{:?}\0{:?}\0{:?}\0{}\0{:?}",
+        dir.display(),
+        cwd,
+        argv,
+        vars,
+        tty,
+        capabilities(&args)
+    );
+    let config = args.config;
+    let git_dir = find_git_dir(dir);
+    let fingerprint = git_dir.as_ref().map(|git_dir| {
+        let mut times = metadata_fingerprint(git_dir);
+        times.extend(config_mtimes(config.as_deref(), dir, git_dir));
+        times
+    });
+    // A repo rendered for any command line within `min_refresh` keeps its
+    // renders, changed or not, and is rendered again once that passes
+    let limited = git_dir
+        .as_ref()
+        .and_then(|d| cache.refreshed.get(d))
+        .is_some_and(|t| t.elapsed() < cache.min_refresh);
+    if let (Some(entry), Some(fp)) = (cache.entries.get(&key), &fingerprint) {
+        if limited || (entry.fingerprint == *fp && entry.rendered.elapsed() < MAX_AGE) {
+            if entry.json {
+                return Ok((
+                    mark_cached_json(&entry.output, entry.rendered.elapsed())?,
+                    true,
+                ));
+            }
+            return Ok((entry.output.clone(), true));
+        }
+    }
+
+    let (output, complete, mode) = render(&matches, dir, tty)?;
+    if let Some(git_dir) = git_dir {
+        cache.refreshed.insert(git_dir, Instant::now());
+    }
+    if let (Some(fp), true) = (fingerprint, complete) {
+        cache.entries.insert(
+            key,
+            Entry {
+                fingerprint: fp,
+                rendered:    Instant::now(),
+                output:      output.clone(),
+                json:        mode == OutputMode::Json,
+            },
+        );
+    }
+    Ok((output, complete))
+}
+
+/// Run status pipeline for the client's command line `matches` in `dir`, which
+/// the client has already resolved from `--dir`. Also returns false if the
+/// output is partial because of `--timeout`, and the output mode. `tty` is
+/// true if the client writes to a terminal
+fn render(matches: &ArgMatches, dir: &Path, tty: bool) -> Result<(String, bool, OutputMode)> {
+    let mut args = Arg::from_arg_matches(matches);
+    args.dir = vec![dir.to_path_buf()];
+    configure(&mut args, matches, tty)?;
+    let mut out = vec![];
+    let complete = run(&args, &mut out, &mut Timer::new())?.complete;
+    Ok((String::from_utf8(out)?, complete, args.output))
+}
+
+/// Ask a running daemon to render this command line, returning the output and
+/// false if it is partial because of `--timeout`. Returns `None` if no daemon
+/// answers in time or it can't render, so the caller renders itself
+pub fn query(path: &Path, dir: &Path) -> Option<(String, bool)> {
+    let cwd = env::current_dir().ok()?;
+    let vars = env::vars()
+        .filter(|(k, _)| forwarded(k))
+        .collect::<HashMap<_, _>>();
+    let params = json!({
+        "dir": cwd.join(dir).to_string_lossy(),
+        "cwd": cwd.to_string_lossy(),
+        "tty": io::stdout().is_terminal(),
+        "args": env::args().collect::<Vec<_>>(),
+        "env": vars,
+    });
+    let result = match call(path, "render", params) {
+        Ok(result) => result,
+        Err(e) => {
+            debug!("daemon: {:#}", e);
+            return None;
+        }
+    };
+    let complete = result["complete"].as_bool().unwrap_or(true);
+    result["output"]
+        .as_str()
+        .map(|out| (out.to_string(), complete))
+}
+
+/// Ask the daemon listening on `path` to drop its cached renders, so changes
+/// to theme files show at the next prompt
+pub fn reload(path: &Path) -> Result {
+    call(path, "reload", Value::Null).map(|_| ())
+}
+
+/// Send request for `method` to the daemon listening on `path`, returning its
+/// result. Fails if no daemon answers in time, or it speaks another protocol
+/// version or lacks `method`
+fn call(path: &Path, method: &str, params: Value) -> Result<Value> {
+    let mut stream = connect(path, CLIENT_TIMEOUT)?;
+    let request = json!({ "version": PROTOCOL_VERSION, "method": method, "params": params });
+    stream.write_line(&request.to_string())?;
+    let mut response: Value = serde_json::from_str(&stream.read_line()?)?;
+    // Daemons from before versioning send no version
+    if response["version"] != PROTOCOL_VERSION {
+        return Err(format_err!(
+            "daemon speaks protocol version {}, not {}; restart it",
+            response["version"],
+            PROTOCOL_VERSION
+        ));
+    }
+    if let Some(message) = response["error"]["message"].as_str() {
+        let capabilities = response["capabilities"]
+            .as_array()
+            .map(|c| c.iter().filter_map(Value::as_str).collect::<Vec<_>>())
+            .unwrap_or_default();
+        if !capabilities.contains(&method) {
+            return Err(format_err!(
+                "daemon can't {}, only {}; restart it",
+                method,
+                capabilities.join(", ")
+            ));
+        }
+        return Err(format_err!("daemon error: {}", message));
+    }
+    Ok(response["result"].take())
+}
@@ -0,0 +1,547 @@
+//! Encoding of styled prompt text for each output target
+use crate::{format::Width, output::Element, style::nearest_256, Result};
+use std::{
+    fmt::Display,
+    io::{self, Write},
+};
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+use writecolor::Style;
+
+/// Writes styles and text of rendered tokens in the syntax of one output
+/// target. `fmt_*` methods write through a `StyledBuf` wrapping an encoder, so
+/// every token works in every output mode
+pub trait OutputEncoder {
+    /// Start text painted with `style` as part of `element`
+    fn start(&mut self, out: &mut Vec<u8>, element: Element, style: &Style) -> Result;
+
+    /// End styled text begun with `start`
+    fn end(&mut self, out: &mut Vec<u8>) -> Result;
+
+    /// Write literal text, escaping anything the target would interpret
+    fn text(&mut self, out: &mut Vec<u8>, text: &str) -> Result {
+        out.extend_from_slice(text.as_bytes());
+        Ok(())
+    }
+
+    /// Write whatever the target needs after the last token
+    fn finish(&mut self, _out: &mut Vec<u8>) -> Result {
+        Ok(())
+    }
+}
+
+/// Raw ANSI escape sequences
+#[derive(Debug, Default)]
+pub struct Ansi;
+
+impl OutputEncoder for Ansi {
+    fn start(&mut self, out: &mut Vec<u8>, _element: Element, style: &Style) -> Result {
+        style.write_to(out)?;
+        Ok(())
+    }
+
+    fn end(&mut self, out: &mut Vec<u8>) -> Result {
+        Style::reset().write_to(out)?;
+        Ok(())
+    }
+}
+
+/// ANSI sequences wrapped in `%{...%}` for zsh `PROMPT`, with literal `%`
+/// doubled so branch names aren't read as prompt sequences
+#[derive(Debug, Default)]
+pub struct Zsh;
+
+impl OutputEncoder for Zsh {
+    fn start(&mut self, out: &mut Vec<u8>, _element: Element, style: &Style) -> Result {
+        wrap_ansi(out, &style.to_string(), "%{", "%}")
+    }
+
+    fn end(&mut self, out: &mut Vec<u8>) -> Result {
+        wrap_ansi(out, &Style::reset().to_string(), "%{", "%}")
+    }
+
+    fn text(&mut self, out: &mut Vec<u8>, text: &str) -> Result {
+        out.extend_from_slice(text.replace('%', "%%").as_bytes());
+        Ok(())
+    }
+}
+
+/// ANSI sequences wrapped in `\[...\]` for bash `PS1`, with literal `\`, `$`
+/// and backticks escaped so branch names aren't expanded or run as commands
+#[derive(Debug, Default)]
+pub struct Bash;
+
+impl OutputEncoder for Bash {
+    fn start(&mut self, out: &mut Vec<u8>, _element: Element, style: &Style) -> Result {
+        wrap_ansi(out, &style.to_string(), "\\[", "\\]")
+    }
+
+    fn end(&mut self, out: &mut Vec<u8>) -> Result {
+        wrap_ansi(out, &Style::reset().to_string(), "\\[", "\\]")
+    }
+
+    fn text(&mut self, out: &mut Vec<u8>, text: &str) -> Result {
+        out.extend_from_slice(bash_text(text).as_bytes());
+        Ok(())
+    }
+}
+
+/// `text` escaped to show as itself in bash `PS1`. Bash decodes backslash
+/// escapes in PS1, then expands it like a double-quoted string, so each of
+/// `\`, `$` and backtick needs a backslash that survives both passes
+pub fn bash_text(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\\\\\"),
+            '$' | '`' => {
+                escaped.push_str("\\\\");
+                escaped.push(c);
+            }
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Write `seq` between shell zero-width markers, or nothing if it's empty
+fn wrap_ansi(out: &mut Vec<u8>, seq: &str, open: &str, close: &str) -> Result {
+    if !seq.is_empty() {
+        write!(out, "{}{}{}", open, seq, close)?;
+    }
+    Ok(())
+}
+
+/// tmux status line `#[fg=colourN]` style tags, with literal `#` doubled
+#[derive(Debug, Default)]
+pub struct Tmux;
+
+impl OutputEncoder for Tmux {
+    fn start(&mut self, out: &mut Vec<u8>, _element: Element, style: &Style) -> Result {
+        let colors = SgrColors::of(style);
+        let mut attrs = vec![];
+        if let Some(fg) = colors.fg {
+            attrs.push(format!("fg=colour{}", fg));
+        }
+        if let Some(bg) = colors.bg {
+            attrs.push(format!("bg=colour{}", bg));
+        }
+        if colors.bold {
+            attrs.push("bold".to_string());
+        }
+        if !attrs.is_empty() {
+            write!(out, "#[{}]", attrs.join(","))?;
+        }
+        Ok(())
+    }
+
+    fn end(&mut self, out: &mut Vec<u8>) -> Result {
+        if !Style::reset().to_string().is_empty() {
+            write!(out, "#[default]")?;
+        }
+        Ok(())
+    }
+
+    fn text(&mut self, out: &mut Vec<u8>, text: &str) -> Result {
+        out.extend_from_slice(text.replace('#', "##").as_bytes());
+        Ok(())
+    }
+}
+
+/// Vim `statusline` highlight groups named after elements, ex: `%#GitprBranch#`,
+/// with literal `%` doubled. Colors come from the user's `:highlight` commands
+#[derive(Debug, Default)]
+pub struct Vim;
+
+impl OutputEncoder for Vim {
+    fn start(&mut self, out: &mut Vec<u8>, element: Element, _style: &Style) -> Result {
+        write!(out, "%#Gitpr{:?}#", element)?;
+        Ok(())
+    }
+
+    fn end(&mut self, out: &mut Vec<u8>) -> Result {
+        write!(out, "%*")?;
+        Ok(())
+    }
+
+    fn text(&mut self, out: &mut Vec<u8>, text: &str) -> Result {
+        out.extend_from_slice(text.replace('%', "%%").as_bytes());
+        Ok(())
+    }
+}
+
+/// Powerline blocks: each element's color becomes the background of its
+/// block, with `` separators between blocks of different colors
+#[derive(Debug, Default)]
+pub struct Powerline {
+    /// Background of the open block
+    bg: Option<u8>,
+}
+
+impl Powerline {
+    /// Separator between blocks
+    const SEPARATOR: &'static str = "\u{e0b0}";
+}
+
+impl OutputEncoder for Powerline {
+    fn start(&mut self, out: &mut Vec<u8>, element: Element, style: &Style) -> Result {
+        // Literal text stays inside the open block
+        if element == Element::Plain {
+            return Ok(());
+        }
+        // Styles with a background keep their colors; others are drawn as
+        // black text on their foreground color
+        let colors = SgrColors::of(style);
+        let (fg, bg) = match (colors.fg, colors.bg) {
+            (fg, Some(bg)) => (fg.unwrap_or(0), bg),
+            (Some(fg), None) => (0, fg),
+            (None, None) => return Ok(()),
+        };
+        match self.bg {
+            Some(prev) if prev == bg => (),
+            Some(prev) => write!(out, "\x1b[38;5;{};48;5;{}m{}", prev, bg, Self::SEPARATOR)?,
+            None => write!(out, "\x1b[48;5;{}m", bg)?,
+        }
+        write!(out, "\x1b[38;5;{}m", fg)?;
+        self.bg = Some(bg);
+        Ok(())
+    }
+
+    fn end(&mut self, _out: &mut Vec<u8>) -> Result {
+        Ok(())
+    }
+
+    fn finish(&mut self, out: &mut Vec<u8>) -> Result {
+        if let Some(bg) = self.bg.take() {
+            write!(out, "\x1b[0;38;5;{}m{}\x1b[0m", bg, Self::SEPARATOR)?;
+        }
+        Ok(())
+    }
+}
+
+/// JSON array of `{"element": ..., "text": ...}` objects, one for each run of
+/// text, for scripts drawing their own prompt
+#[derive(Debug, Default)]
+pub struct JsonSegments {
+    segments: Vec<(Element, String)>,
+    /// Element of the styled run being written, if any
+    open:     Option<Element>,
+}
+
+impl OutputEncoder for JsonSegments {
+    fn start(&mut self, _out: &mut Vec<u8>, element: Element, _style: &Style) -> Result {
+        if self.open != Some(element) {
+            self.segments.push((element, String::new()));
+            self.open = Some(element);
+        }
+        Ok(())
+    }
+
+    fn end(&mut self, _out: &mut Vec<u8>) -> Result {
+        self.open = None;
+        Ok(())
+    }
+
+    fn text(&mut self, _out: &mut Vec<u8>, text: &str) -> Result {
+        if self.open.is_none() {
+            self.segments.push((Element::Plain, String::new()));
+            self.open = Some(Element::Plain);
+        }
+        if let Some((_, s)) = self.segments.last_mut() {
+            s.push_str(text);
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self, out: &mut Vec<u8>) -> Result {
+        let segments = self
+            .segments
+            .drain(..)
+            .filter(|(_, text)| !text.is_empty())
+            .map(|(element, text)| serde_json::json!({ "element": element.name(), "text": text }))
+            .collect::<Vec<_>>();
+        write!(out, "{}", serde_json::Value::Array(segments))?;
+        Ok(())
+    }
+}
+
+/// Colors of a style, read back from its ANSI sequence
+#[derive(Debug, Default, PartialEq)]
+struct SgrColors {
+    fg:   Option<u8>,
+    bg:   Option<u8>,
+    bold: bool,
+}
+
+impl SgrColors {
+    /// Colors of `style` as 256-color numbers; empty if color is disabled
+    fn of(style: &Style) -> Self {
+        Self::parse(&style.to_string())
+    }
+
+    /// Read SGR parameters of CSI sequences in `seq`, ex: "\x1b[1;38;5;12m".
+    /// 24-bit colors are mapped to the nearest 256-color value
+    fn parse(seq: &str) -> Self {
+        let mut colors = Self::default();
+        for params in seq.split('\x1b').filter_map(|s| s.strip_prefix('[')) {
+            let params = params.trim_end_matches('m');
+            let mut nums = params.split(';').map(|p| p.parse::<u8>().unwrap_or(0));
+            while let Some(n) = nums.next() {
+                match n {
+                    0 => colors = Self::default(),
+                    1 => colors.bold = true,
+                    30..=37 => colors.fg = Some(n - 30),
+                    90..=97 => colors.fg = Some(n - 90 + 8),
+                    40..=47 => colors.bg = Some(n - 40),
+                    100..=107 => colors.bg = Some(n - 100 + 8),
+                    38 | 48 => {
+                        let color = match nums.next() {
+                            Some(5) => nums.next(),
+                            Some(2) => match (nums.next(), nums.next(), nums.next()) {
+                                (Some(r), Some(g), Some(b)) => Some(nearest_256(r, g, b)),
+                                _ => None,
+                            },
+                            _ => None,
+                        };
+                        if n == 38 {
+                            colors.fg = color;
+                        } else {
+                            colors.bg = color;
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        }
+        colors
+    }
+}
+
+/// Piece of a token's output, kept until the token is complete so its text can
+/// be fitted to a width before encoding
+#[derive(Debug)]
+enum Op {
+    Start(Style),
+    End,
+    Text(String),
+}
+
+/// Buffer `fmt_*` methods write a token into, encoding styles and text for the
+/// output target
+pub struct StyledBuf<'a> {
+    encoder: &'a mut dyn OutputEncoder,
+    element: Element,
+    width:   Width,
+    ops:     Vec<Op>,
+}
+
+impl<'a> StyledBuf<'a> {
+    /// Padding character; a figure space, so whitespace trimming keeps it
+    const PAD: char = '\u{2007}';
+
+    /// Empty buffer for output of `element`
+    pub fn new(encoder: &'a mut dyn OutputEncoder, element: Element) -> Self {
+        Self {
+            encoder,
+            element,
+            width: Width::default(),
+            ops: vec![],
+        }
+    }
+
+    /// Pad or truncate text to `width` once the token is written
+    pub fn with_width(mut self, width: Width) -> Self {
+        self.width = width;
+        self
+    }
+
+    /// Start text painted with `style`
+    pub fn style(&mut self, style: &Style) -> Result {
+        self.ops.push(Op::Start(*style));
+        Ok(())
+    }
+
+    /// End styled text
+    pub fn reset(&mut self) -> Result {
+        self.ops.push(Op::End);
+        Ok(())
+    }
+
+    /// Write `text` painted with `style`, or nothing if `text` is empty
+    pub fn paint(&mut self, style: &Style, text: impl Display) -> Result {
+        let text = text.to_string();
+        if text.is_empty() {
+            return Ok(());
+        }
+        self.style(style)?;
+        write!(self, "{}", text)?;
+        self.reset()
+    }
+
+    /// Encoded output, fitted to width. Tokens without text aren't padded, so
+    /// they still take up no space
+    pub fn into_inner(self) -> Result<Vec<u8>> {
+        Ok(self.into_parts()?.0)
+    }
+
+    /// Encoded output and its text without styles, fitted to width
+    pub fn into_parts(mut self) -> Result<(Vec<u8>, String)> {
+        self.fit();
+        let mut out = vec![];
+        let mut plain = String::new();
+        for op in &self.ops {
+            match op {
+                Op::Start(style) => self.encoder.start(&mut out, self.element, style)?,
+                Op::End => self.encoder.end(&mut out)?,
+                Op::Text(text) => {
+                    self.encoder.text(&mut out, text)?;
+                    plain.push_str(text);
+                }
+            }
+        }
+        Ok((out, plain))
+    }
+
+    /// Truncate text wider than `width.max` columns with "…", then pad text
+    /// narrower than `width.min`
+    fn fit(&mut self) {
+        let len = |ops: &[Op]| {
+            ops.iter()
+                .map(|op| match op {
+                    Op::Text(t) => t.width(),
+                    _ => 0,
+                })
+                .sum::<usize>()
+        };
+        let total = len(&self.ops);
+        if total == 0 {
+            return;
+        }
+        if let Some(max) = self.width.max.filter(|&max| total > max) {
+            // Room for text before the ellipsis
+            let mut room = max.saturating_sub(1);
+            let mut cut = false;
+            for op in &mut self.ops {
+                if let Op::Text(text) = op {
+                    if cut {
+                        text.clear();
+                        continue;
+                    }
+                    let n = text.width();
+                    if n <= room {
+                        room -= n;
+                        continue;
+                    }
+                    *text = text
+                        .chars()
+                        .take_while(|c| match room.checked_sub(c.width().unwrap_or(0)) {
+                            Some(left) => {
+                                room = left;
+                                true
+                            }
+                            None => false,
+                        })
+                        .collect();
+                    if max > 0 {
+                        text.push('…');
+                    }
+                    cut = true;
+                }
+            }
+        }
+        if let Some(min) = self.width.min {
+            let pad = min.saturating_sub(len(&self.ops));
+            if pad > 0 {
+                let pad = Op::Text(Self::PAD.to_string().repeat(pad));
+                if self.width.left_align {
+                    self.ops.push(pad);
+                } else {
+                    self.ops.insert(0, pad);
+                }
+            }
+        }
+    }
+}
+
+impl Write for StyledBuf<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.ops
+            .push(Op::Text(String::from_utf8_lossy(buf).into_owned()));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn sgr_colors() {
+        assert_eq!(
+            SgrColors::parse("\x1b[34m\x1b[1m"),
+            SgrColors {
+                fg:   Some(4),
+                bg:   None,
+                bold: true,
+            }
+        );
+        assert_eq!(
+            SgrColors::parse("\x1b[38;5;245;48;2;255;135;0m"),
+            SgrColors {
+                fg:   Some(245),
+                bg:   Some(208),
+                bold: false,
+            }
+        );
+        assert_eq!(SgrColors::parse(""), SgrColors::default());
+    }
+
+    #[test]
+    fn fitted_width() -> Result {
+        let fit = |text: &str, width: Width| -> Result<String> {
+            let mut encoder = Ansi;
+            let mut buf = StyledBuf::new(&mut encoder, Element::Branch).with_width(width);
+            write!(buf, "{}", text)?;
+            Ok(String::from_utf8(buf.into_inner()?)?)
+        };
+        let max = Width {
+            max: Some(5),
+            ..Width::default()
+        };
+        assert_eq!(fit("feature/long", max)?, "feat…");
+        assert_eq!(fit("main", max)?, "main");
+        assert_eq!(fit("機能ブランチ", max)?, "機能…");
+        let min = Width {
+            min: Some(6),
+            left_align: true,
+            ..Width::default()
+        };
+        assert_eq!(fit("main", min)?, "main\u{2007}\u{2007}");
+        assert_eq!(fit("", min)?, "");
+        Ok(())
+    }
+
+    #[test]
+    fn escaped_text() -> Result {
+        let mut out = vec![];
+        Zsh.text(&mut out, "50%")?;
+        Tmux.text(&mut out, " #1")?;
+        assert_eq!(String::from_utf8(out)?, "50%% ##1");
+        Ok(())
+    }
+
+    #[test]
+    fn bash_hostile_branch() -> Result {
+        let mut out = vec![];
+        Bash.text(&mut out, "$(rm -rf ~)`id`\\w")?;
+        assert_eq!(
+            String::from_utf8(out)?,
+            "\\\\$(rm -rf ~)\\\\`id\\\\`\\\\\\\\w"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,348 @@
+//! Parse format string into tokens, literal text, conditional sections and
+//! groups
+use crate::{Result, FORMAT_TOKENS, TOKEN_NAMES};
+use anyhow::format_err;
+use std::str::Chars;
+
+/// Parsed piece of format string
+#[derive(Debug, PartialEq)]
+pub enum Node {
+    /// Text printed as-is
+    Literal(String),
+    /// Token, ex: `%b`, or its long form `%{branch}`, with width modifiers,
+    /// ex: `%-10.20b`
+    Token(char, Width),
+    /// `%[?X then %: otherwise %]`: render `then` if token `X` produces
+    /// output, else `otherwise`. `X` may be a long form, ex: `%[?{ahead}`
+    Cond {
+        token:     char,
+        then:      Vec<Node>,
+        otherwise: Vec<Node>,
+    },
+    /// `%(...%)`: render contents only if any token inside produces output,
+    /// so separators around it disappear with it, ex: `%( [%a]%)`. Like a
+    /// `Cond` without `otherwise` whose condition is every token inside
+    Group(Vec<Node>),
+}
+
+/// Column limits of a token from printf-like modifiers between `%` and the
+/// token, ex: `%10b`, `%-10b`, `%.20b`
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Width {
+    /// Pad to at least this many columns
+    pub min:        Option<usize>,
+    /// Truncate to at most this many columns, ending with "…"
+    pub max:        Option<usize>,
+    /// Pad on the right (`-`) instead of the left
+    pub left_align: bool,
+}
+
+/// What stopped a run of nodes
+enum End {
+    /// `%:`
+    Else,
+    /// `%]`
+    Close,
+    /// `%)`
+    CloseGroup,
+    /// End of format string
+    Eof,
+}
+
+/// Parse format string
+pub fn parse(fmt: &str) -> Result<Vec<Node>> {
+    let (nodes, end) = parse_until(&mut fmt.chars())?;
+    match end {
+        End::Eof => Ok(nodes),
+        End::Else => Err(format_err!("\"%:\" outside of conditional section")),
+        End::Close => Err(format_err!("\"%]\" without matching \"%[\"")),
+        End::CloseGroup => Err(format_err!("\"%)\" without matching \"%(\"")),
+    }
+}
+
+/// All tokens in nodes, including section conditions, in order of appearance
+pub fn tokens(nodes: &[Node]) -> Vec<char> {
+    let mut tokens = vec![];
+    for node in nodes {
+        match node {
+            Node::Literal(_) => (),
+            Node::Token(c, _) => tokens.push(*c),
+            Node::Cond {
+                token,
+                then,
+                otherwise,
+            } => {
+                tokens.push(*token);
+                tokens.extend(self::tokens(then));
+                tokens.extend(self::tokens(otherwise));
+            }
+            Node::Group(nodes) => tokens.extend(self::tokens(nodes)),
+        }
+    }
+    tokens
+}
+
+/// Parse nodes until end of string or the end of the enclosing section
+fn parse_until(chars: &mut Chars) -> Result<(Vec<Node>, End)> {
+    let mut nodes = vec![];
+    let mut literal = String::new();
+    let flush = |nodes: &mut Vec<Node>, literal: &mut String| {
+        if !literal.is_empty() {
+            nodes.push(Node::Literal(std::mem::take(literal)));
+        }
+    };
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            literal.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('%') => literal.push('%'),
+            Some(':') => {
+                flush(&mut nodes, &mut literal);
+                return Ok((nodes, End::Else));
+            }
+            Some(']') => {
+                flush(&mut nodes, &mut literal);
+                return Ok((nodes, End::Close));
+            }
+            Some(')') => {
+                flush(&mut nodes, &mut literal);
+                return Ok((nodes, End::CloseGroup));
+            }
+            Some('(') => {
+                flush(&mut nodes, &mut literal);
+                nodes.push(parse_group(chars)?);
+            }
+            Some('[') => {
+                flush(&mut nodes, &mut literal);
+                nodes.push(parse_section(chars)?);
+            }
+            Some('{') => {
+                flush(&mut nodes, &mut literal);
+                nodes.push(Node::Token(parse_name(chars)?, Width::default()));
+            }
+            Some(t) if t == '-' || t == '.' || t.is_ascii_digit() => {
+                flush(&mut nodes, &mut literal);
+                nodes.push(parse_width(t, chars)?);
+            }
+            Some(t) if FORMAT_TOKENS.contains(&t) => {
+                flush(&mut nodes, &mut literal);
+                nodes.push(Node::Token(t, Width::default()));
+            }
+            Some(t) => return Err(format_err!("invalid format string token \"%{}\"", t)),
+            None => return Err(format_err!("format string ends with lone \"%\"")),
+        }
+    }
+    flush(&mut nodes, &mut literal);
+    Ok((nodes, End::Eof))
+}
+
+/// Parse conditional section after its opening `%[`
+fn parse_section(chars: &mut Chars) -> Result<Node> {
+    let token = match (chars.next(), chars.next()) {
+        (Some('?'), Some('{')) => parse_name(chars)?,
+        (Some('?'), Some(t)) if t != '%' && FORMAT_TOKENS.contains(&t) => t,
+        (Some('?'), Some(t)) => {
+            return Err(format_err!("invalid condition token \"{}\" in \"%[?\"", t))
+        }
+        _ => {
+            return Err(format_err!(
+                "conditional section must start with \"%[?\" and a token"
+            ))
+        }
+    };
+    let unterminated = || format_err!("conditional section \"%[?{}\" is missing \"%]\"", token);
+    let (then, end) = parse_until(chars)?;
+    let otherwise = match end {
+        End::Close => vec![],
+        End::Else => match parse_until(chars)? {
+            (otherwise, End::Close) => otherwise,
+            (_, End::Else) => return Err(format_err!("more than one \"%:\" in section")),
+            (_, End::CloseGroup) => return Err(format_err!("\"%)\" inside \"%[?{}\"", token)),
+            (_, End::Eof) => return Err(unterminated()),
+        },
+        End::CloseGroup => return Err(format_err!("\"%)\" inside \"%[?{}\"", token)),
+        End::Eof => return Err(unterminated()),
+    };
+    Ok(Node::Cond {
+        token,
+        then,
+        otherwise,
+    })
+}
+
+/// Parse width modifiers starting with `first` and the token they apply to
+fn parse_width(first: char, chars: &mut Chars) -> Result<Node> {
+    let mut width = Width::default();
+    let mut spec = String::new();
+    let mut next = Some(first);
+    if next == Some('-') {
+        width.left_align = true;
+        spec.push('-');
+        next = chars.next();
+    }
+    // Read digits starting at `next`, leaving `next` at the char after them
+    fn number(next: &mut Option<char>, chars: &mut Chars, spec: &mut String) -> Option<usize> {
+        let start = spec.len();
+        while let Some(d) = next.filter(char::is_ascii_digit) {
+            spec.push(d);
+            *next = chars.next();
+        }
+        spec[start..].parse().ok()
+    }
+    width.min = number(&mut next, chars, &mut spec);
+    if next == Some('.') {
+        spec.push('.');
+        next = chars.next();
+        width.max = number(&mut next, chars, &mut spec);
+        if width.max.is_none() {
+            return Err(format_err!("missing precision after \"%{}\"", spec));
+        }
+    }
+    let token = match next {
+        Some('{') => parse_name(chars)?,
+        Some(t) if t != '%' && FORMAT_TOKENS.contains(&t) => t,
+        Some(t) => {
+            return Err(format_err!(
+                "invalid format string token \"%{}{}\"",
+                spec,
+                t
+            ))
+        }
+        None => return Err(format_err!("format string ends with \"%{}\"", spec)),
+    };
+    Ok(Node::Token(token, width))
+}
+
+/// Parse group after its opening `%(`
+fn parse_group(chars: &mut Chars) -> Result<Node> {
+    match parse_until(chars)? {
+        (nodes, End::CloseGroup) => Ok(Node::Group(nodes)),
+        (_, End::Else) => Err(format_err!("\"%:\" outside of conditional section")),
+        (_, End::Close) => Err(format_err!("\"%]\" inside \"%(\"")),
+        (_, End::Eof) => Err(format_err!("group \"%(\" is missing \"%)\"")),
+    }
+}
+
+/// Parse long token name after its opening `{`, returning its single-letter
+/// token
+fn parse_name(chars: &mut Chars) -> Result<char> {
+    let mut name = String::new();
+    loop {
+        match chars.next() {
+            Some('}') => break,
+            Some(c) => name.push(c),
+            None => return Err(format_err!("token name \"%{{{}\" is missing \"}}\"", name)),
+        }
+    }
+    TOKEN_NAMES
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|&(_, t)| t)
+        .ok_or_else(|| {
+            format_err!(
+                "unknown token name \"%{{{}}}\"; expected one of: {}",
+                name,
+                TOKEN_NAMES
+                    .iter()
+                    .map(|(n, _)| *n)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn cond_with_else() -> Result {
+        let nodes = parse("%b %[?a⇡%a%:✓%] 100%%")?;
+        assert_eq!(
+            nodes,
+            vec![
+                Node::Token('b', Width::default()),
+                Node::Literal(" ".into()),
+                Node::Cond {
+                    token:     'a',
+                    then:      vec![
+                        Node::Literal("⇡".into()),
+                        Node::Token('a', Width::default())
+                    ],
+                    otherwise: vec![Node::Literal("✓".into())],
+                },
+                Node::Literal(" 100%".into()),
+            ]
+        );
+        assert_eq!(tokens(&nodes), vec!['b', 'a', 'a']);
+        Ok(())
+    }
+
+    #[test]
+    fn groups() -> Result {
+        let nodes = parse("%b%( [%a]%)")?;
+        assert_eq!(
+            nodes[1],
+            Node::Group(vec![
+                Node::Literal(" [".into()),
+                Node::Token('a', Width::default()),
+                Node::Literal("]".into()),
+            ])
+        );
+        assert_eq!(tokens(&nodes), vec!['b', 'a']);
+        assert!(parse("%( %a").is_err());
+        assert!(parse("%a%)").is_err());
+        assert!(parse("%[?a%(%]%)").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn widths() -> Result {
+        assert_eq!(
+            parse("%-10.20{branch}%8c")?,
+            vec![
+                Node::Token(
+                    'b',
+                    Width {
+                        min:        Some(10),
+                        max:        Some(20),
+                        left_align: true,
+                    }
+                ),
+                Node::Token(
+                    'c',
+                    Width {
+                        min: Some(8),
+                        ..Width::default()
+                    }
+                ),
+            ]
+        );
+        assert!(parse("%.b").is_err());
+        assert!(parse("%10").is_err());
+        assert!(parse("%10%").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn long_names() -> Result {
+        assert_eq!(parse("%{branch}%[?{ahead}x%]")?, parse("%b%[?ax%]")?);
+        assert_eq!(parse("%{ahead_behind}%{unmerged}")?, parse("%a%U")?);
+        assert!(parse("%{branch").is_err());
+        assert!(parse("%{nope}").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn bad_sections() {
+        assert!(parse("%[?a%a").is_err());
+        assert!(parse("%a%]").is_err());
+        assert!(parse("%a%:").is_err());
+        assert!(parse("%[?a%:x%:y%]").is_err());
+        assert!(parse("%[a%]").is_err());
+        assert!(parse("%q").is_err());
+    }
+}
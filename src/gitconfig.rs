@@ -0,0 +1,75 @@
+//! Values read from `git config`, so settings made for git and `__git_ps1`
+//! apply to the prompt too
+use crate::git;
+use std::path::Path;
+
+/// Entries of some sections of the git config in effect for a repo
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct GitConfig {
+    /// Names as git prints them, ex: "status.showuntrackedfiles", with values
+    /// in the order git reads them
+    entries: Vec<(String, String)>,
+}
+
+impl GitConfig {
+    /// Entries of `sections` in the config of the repo containing `dir`, read
+    /// with one `git config` command. Empty if git is missing or fails
+    pub fn read(dir: &Path, sections: &[&str]) -> Self {
+        let pattern = format!(r"^({})\.", sections.join("|"));
+        let out = git!("config", "-z", "--get-regexp", pattern)
+            .dir(dir)
+            .stdin_null()
+            .stderr_null()
+            .unchecked()
+            .read()
+            .unwrap_or_default();
+        Self::parse(&out)
+    }
+
+    /// Parse `git config -z` output: name, newline and value, ending in NUL
+    pub fn parse(out: &str) -> Self {
+        let entries = out
+            .split('\0')
+            .filter(|e| !e.is_empty())
+            .map(|e| {
+                let (name, value) = e.split_once('\n').unwrap_or((e, ""));
+                (name.to_string(), value.to_string())
+            })
+            .collect();
+        Self { entries }
+    }
+
+    /// All entries whose names start with `section.`, with that part removed
+    pub fn section<'a>(&'a self, section: &'a str) -> impl Iterator<Item = (&'a str, &'a str)> {
+        self.entries.iter().filter_map(move |(name, value)| {
+            name.strip_prefix(section)
+                .and_then(|n| n.strip_prefix('.'))
+                .map(|n| (n, value.as_str()))
+        })
+    }
+
+    /// Value of `name` that takes effect, the last one set. Section and key
+    /// names aren't case sensitive, ex: "status.showUntrackedFiles"
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .rev()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Value of `name` read as a git boolean; `None` if unset or not boolean
+    pub fn get_bool(&self, name: &str) -> Option<bool> {
+        parse_bool(self.get(name)?)
+    }
+}
+
+/// Read a git boolean: "true", "yes", "on", "1" or a key with no value, or
+/// "false", "no", "off", "0"
+pub fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_lowercase().as_str() {
+        "true" | "yes" | "on" | "1" | "" => Some(true),
+        "false" | "no" | "off" | "0" => Some(false),
+        _ => None,
+    }
+}
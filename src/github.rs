@@ -0,0 +1,212 @@
+//! Pull request of the current branch and its CI checks, from the `gh` CLI
+use crate::{
+    cache_dir,
+    encoder::StyledBuf,
+    glyph::GlyphSet,
+    repo::{common_dir, wait_until, Vcs},
+    Repo, Result,
+};
+use duct::cmd;
+use serde_json::{json, Value};
+use std::{
+    collections::hash_map::DefaultHasher,
+    convert::TryFrom,
+    fs,
+    hash::{Hash, Hasher},
+    io::Write,
+    path::PathBuf,
+    process,
+    time::{Duration, Instant},
+};
+use writecolor::Style;
+
+/// Longest a cached answer is reused while checks are still running
+const PENDING_TTL: Duration = Duration::from_secs(60);
+
+/// Longest any other cached answer is reused, including "no pull request"
+const TTL: Duration = Duration::from_secs(5 * 60);
+
+/// Longest the prompt waits for `gh`
+const GH_TIMEOUT: Duration = Duration::from_millis(1500);
+
+/// Combined state of a pull request's CI checks
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Checks {
+    /// No checks reported
+    None,
+    Pending,
+    Pass,
+    Fail,
+}
+
+impl Checks {
+    /// Combine `statusCheckRollup` items from `gh pr view`. Check runs report
+    /// `status` and `conclusion`; commit statuses report `state`
+    fn from_rollup(rollup: &[Value]) -> Self {
+        let mut checks = Checks::None;
+        for item in rollup {
+            let state = item["conclusion"]
+                .as_str()
+                .filter(|c| !c.is_empty())
+                .or_else(|| item["state"].as_str())
+                .unwrap_or_default();
+            let done = item["status"].as_str().is_none_or(|s| s == "COMPLETED");
+            match state {
+                "FAILURE" | "ERROR" | "TIMED_OUT" | "CANCELLED" | "ACTION_REQUIRED"
+                | "STARTUP_FAILURE" => return Checks::Fail,
+                "SUCCESS" | "NEUTRAL" | "SKIPPED" if done => {
+                    if checks == Checks::None {
+                        checks = Checks::Pass;
+                    }
+                }
+                _ => checks = Checks::Pending,
+            }
+        }
+        checks
+    }
+}
+
+/// Open pull request of the current branch
+#[derive(Debug, Clone, PartialEq)]
+pub struct PullRequest {
+    pub number: u32,
+    pub checks: Checks,
+}
+
+impl PullRequest {
+    /// Parse `gh pr view --json number,statusCheckRollup` output
+    fn parse(json: &str) -> Option<Self> {
+        let value: Value = serde_json::from_str(json).ok()?;
+        Some(PullRequest {
+            number: u32::try_from(value["number"].as_u64()?).ok()?,
+            checks: Checks::from_rollup(value["statusCheckRollup"].as_array()?),
+        })
+    }
+}
+
+impl Repo {
+    /// Look up pull request of the current branch with `gh`, reusing the
+    /// answer cached for the branch for a few minutes. If `gh` is missing or
+    /// too slow, the stale answer is kept until the next attempt is due
+    pub fn gh_pull_request(&mut self) -> Result {
+        if self.vcs != Vcs::Git || self.pull_request.is_some() || self.detached {
+            return Ok(());
+        }
+        let branch = match &self.branch {
+            Some(b) => b.clone(),
+            None => return Ok(()),
+        };
+        let git_dir = PathBuf::from(self.git_root_dir()?);
+        let mut hasher = DefaultHasher::new();
+        common_dir(&git_dir).hash(&mut hasher);
+        branch.hash(&mut hasher);
+        let file = match cache_dir() {
+            Some(d) => d.join(format!("pr-{:016x}.json", hasher.finish())),
+            None => return Ok(()),
+        };
+
+        let cached = fs::read_to_string(&file).ok();
+        let cached_pr = cached.as_deref().and_then(PullRequest::parse);
+        let age = fs::metadata(&file)
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|t| t.elapsed().ok());
+        let ttl = match &cached_pr {
+            Some(pr) if pr.checks == Checks::Pending => PENDING_TTL,
+            _ => TTL,
+        };
+        if age.is_some_and(|age| age < ttl) {
+            self.pull_request = cached_pr;
+            return Ok(());
+        }
+
+        let deadline = Instant::now() + GH_TIMEOUT;
+        let gh = cmd!(
+            "gh",
+            "pr",
+            "view",
+            &branch,
+            "--json",
+            "number,statusCheckRollup"
+        )
+        .env("GH_PROMPT_DISABLED", "1")
+        .stdin_null()
+        .stdout_capture()
+        .stderr_null()
+        .unchecked()
+        .start();
+        let out = match gh {
+            Ok(handle) => wait_until(
+                &handle,
+                Some(self.deadline.map_or(deadline, |d| d.min(deadline))),
+            )?,
+            Err(_) => None,
+        };
+        // `gh` fails when the branch has no pull request
+        let text = match out {
+            Some(out) if out.status.success() => String::from_utf8(out.stdout)?,
+            Some(_) => json!(null).to_string(),
+            None => cached.unwrap_or_else(|| json!(null).to_string()),
+        };
+        self.pull_request = PullRequest::parse(&text);
+        // Rewritten even when unchanged, so a failing `gh` waits for the TTL
+        fs::create_dir_all(file.parent().unwrap_or(&file))?;
+        let tmp = file.with_extension(process::id().to_string());
+        fs::write(&tmp, text)?;
+        fs::rename(&tmp, &file)?;
+        Ok(())
+    }
+
+    /// Write pull request number and state of its checks to buffer, ex: "#42✓"
+    pub fn fmt_pull_request(
+        &self,
+        buf: &mut StyledBuf<'_>,
+        style: &Style,
+        glyphs: &GlyphSet,
+    ) -> Result {
+        if let Some(pr) = &self.pull_request {
+            buf.style(style)?;
+            write!(buf, "#{}", pr.number)?;
+            let glyph = match pr.checks {
+                Checks::None => "",
+                Checks::Pending => &glyphs.checks_pending,
+                Checks::Pass => &glyphs.checks_pass,
+                Checks::Fail => &glyphs.checks_fail,
+            };
+            buf.write_all(glyph.as_bytes())?;
+            buf.reset()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn check_rollup() {
+        let pr = |rollup: &str| {
+            PullRequest::parse(&format!(
+                r#"{{"number": 42, "statusCheckRollup": {}}}"#,
+                rollup
+            ))
+            .map(|pr| pr.checks)
+        };
+        assert_eq!(pr("[]"), Some(Checks::None));
+        assert_eq!(
+            pr(r#"[{"status": "COMPLETED", "conclusion": "SUCCESS"}, {"state": "SUCCESS"}]"#),
+            Some(Checks::Pass)
+        );
+        assert_eq!(
+            pr(r#"[{"status": "IN_PROGRESS", "conclusion": ""}, {"state": "SUCCESS"}]"#),
+            Some(Checks::Pending)
+        );
+        assert_eq!(
+            pr(r#"[{"status": "IN_PROGRESS", "conclusion": ""}, {"state": "ERROR"}]"#),
+            Some(Checks::Fail)
+        );
+        assert_eq!(PullRequest::parse("null"), None);
+    }
+}
@@ -0,0 +1,543 @@
+//! Gather repo status with gitoxide, so no system git or libgit2 is needed
+use crate::{
+    repo::{ceiling_dirs, normalize_url, search_dirs, Backend, RemoteBranch, Untracked},
+    Repo, Result,
+};
+use anyhow::format_err;
+use gix::{
+    bstr::ByteSlice,
+    diff::{
+        blob::{diff, intern::InternedInput, sink::Counter, Algorithm},
+        index::{Action, ChangeRef},
+    },
+    dir::entry::Kind,
+    remote::Direction,
+    status::{
+        index_worktree,
+        plumbing::{
+            index_as_worktree::{Conflict, EntryStatus},
+            index_as_worktree_with_renames::Summary,
+        },
+        tree_index::TrackRenames,
+        UntrackedFiles,
+    },
+    ObjectId,
+};
+use std::{
+    convert::{Infallible, TryFrom},
+    fs,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+impl Repo {
+    /// Gather status of repo in current directory, matching what
+    /// `git status --porcelain=2 --branch` reports. Tokens needing more than
+    /// status are computed later by the `gix_*` methods.
+    pub fn from_gix(untracked: Untracked) -> Result<Self> {
+        let repo = discover()?;
+        let mut ri = Repo {
+            backend: Backend::Gix,
+            git_dir: Some(repo.git_dir().to_string_lossy().to_string()),
+            promisor: Some(is_promisor(&repo)),
+            sparse: Some(
+                repo.config_snapshot()
+                    .boolean("core.sparseCheckout")
+                    .unwrap_or(false),
+            ),
+            ..Default::default()
+        };
+
+        let head_id = repo.head_id().ok().map(|id| id.detach());
+        ri.commit = Some(head_id.map_or_else(|| String::from("(initial)"), |id| id.to_string()));
+        let head_name = repo.head_name()?;
+        ri.detached = head_name.is_none() && head_id.is_some();
+        ri.branch = match &head_name {
+            Some(name) => Some(name.shorten().to_string()),
+            None => Some(
+                head_id
+                    .and_then(|id| exact_tag(&repo, id))
+                    .unwrap_or_else(|| String::from("unknown")),
+            ),
+        };
+        if let (Some(name), Some(local)) = (&head_name, head_id) {
+            if let Some(tracking) =
+                repo.branch_remote_tracking_ref_name(name.as_ref(), gix::remote::Direction::Fetch)
+            {
+                let tracking = tracking?;
+                ri.upstream = Some(tracking.shorten().to_string());
+                if let Ok(mut upstream) = repo.find_reference(tracking.as_ref()) {
+                    let remote = upstream.peel_to_id()?.detach();
+                    ri.ahead = count_commits(&repo, local, remote)?;
+                    ri.behind = count_commits(&repo, remote, local)?;
+                    if ri.behind > 0 {
+                        ri.behind_age = oldest_age(&repo, remote, local)?;
+                    }
+                }
+            }
+        }
+
+        let status = repo
+            .status(gix::progress::Discard)?
+            .untracked_files(match untracked {
+                Untracked::No => UntrackedFiles::None,
+                Untracked::All => UntrackedFiles::Files,
+                Untracked::Normal | Untracked::Auto => UntrackedFiles::Collapsed,
+            })
+            .into_iter(None)?;
+        for item in status {
+            match item? {
+                gix::status::Item::TreeIndex(change) => {
+                    match change {
+                        ChangeRef::Addition { .. } => ri.staged.added += 1,
+                        ChangeRef::Deletion { .. } => ri.staged.deleted += 1,
+                        ChangeRef::Modification { .. } => ri.staged.modified += 1,
+                        ChangeRef::Rewrite { copy: true, .. } => ri.staged.copied += 1,
+                        ChangeRef::Rewrite { copy: false, .. } => ri.staged.renamed += 1,
+                    }
+                    ri.staged
+                        .files
+                        .push(change.location().to_str_lossy().into_owned());
+                }
+                gix::status::Item::IndexWorktree(item) => {
+                    let mut path = item.rela_path().to_str_lossy().into_owned();
+                    // Collapsed untracked directories end in "/", as in git
+                    if let index_worktree::Item::DirectoryContents { entry, .. } = &item {
+                        if let Some(Kind::Directory) | Some(Kind::Repository) = entry.disk_kind {
+                            path.push('/');
+                        }
+                    }
+                    match item.summary() {
+                        Some(Summary::Added) => {
+                            ri.untracked += 1;
+                            ri.untracked_files.push(path);
+                            continue;
+                        }
+                        Some(Summary::Conflict) => {
+                            ri.unmerged += 1;
+                            if let index_worktree::Item::Modification {
+                                status: EntryStatus::Conflict { summary, .. },
+                                ..
+                            } = &item
+                            {
+                                match summary {
+                                    Conflict::BothModified => ri.conflicts.both_modified += 1,
+                                    Conflict::BothAdded => ri.conflicts.both_added += 1,
+                                    Conflict::DeletedByUs => ri.conflicts.deleted_by_us += 1,
+                                    Conflict::DeletedByThem => ri.conflicts.deleted_by_them += 1,
+                                    _ => (),
+                                }
+                            }
+                            continue;
+                        }
+                        Some(Summary::Modified) | Some(Summary::TypeChange) => {
+                            ri.unstaged.modified += 1
+                        }
+                        Some(Summary::Removed) => ri.unstaged.deleted += 1,
+                        Some(Summary::Renamed) => ri.unstaged.renamed += 1,
+                        Some(Summary::Copied) => ri.unstaged.copied += 1,
+                        Some(Summary::IntentToAdd) => ri.unstaged.added += 1,
+                        None => continue,
+                    }
+                    ri.unstaged.files.push(path);
+                }
+            }
+        }
+        // Keep git's path order for `--list`
+        ri.staged.files.sort();
+        ri.unstaged.files.sort();
+        ri.untracked_files.sort();
+        Ok(ri)
+    }
+
+    /// Count lines inserted and deleted in worktree files compared to the
+    /// index, like `git diff --numstat`
+    pub(crate) fn gix_diff_numstat(&mut self) -> Result {
+        let repo = discover()?;
+        let workdir = match repo.workdir() {
+            Some(dir) => dir.to_path_buf(),
+            None => return Ok(()),
+        };
+        let status = repo
+            .status(gix::progress::Discard)?
+            .untracked_files(UntrackedFiles::None)
+            .into_index_worktree_iter(None)?;
+        for item in status {
+            if let index_worktree::Item::Modification {
+                entry, rela_path, ..
+            } = item?
+            {
+                let before = repo.find_object(entry.id)?.detach().data;
+                let after =
+                    fs::read(workdir.join(rela_path.to_str_lossy().as_ref())).unwrap_or_default();
+                let (insertions, deletions) = count_lines(&before, &after);
+                self.insertions += insertions;
+                self.deletions += deletions;
+            }
+        }
+        Ok(())
+    }
+
+    /// Count lines inserted and deleted in the index compared to HEAD, like
+    /// `git diff --cached --numstat`
+    pub(crate) fn gix_staged_numstat(&mut self) -> Result {
+        let repo = discover()?;
+        let tree = match repo.head_tree_id() {
+            Ok(id) => id.detach(),
+            Err(_) => ObjectId::empty_tree(repo.object_hash()),
+        };
+        let index = repo.index_or_empty()?;
+        let mut changes = Vec::new();
+        repo.tree_index_status(
+            &tree,
+            &index,
+            None,
+            TrackRenames::Disabled,
+            |change, _, _| {
+                changes.push(match change {
+                    ChangeRef::Addition { id, .. } => (None, Some(id.into_owned())),
+                    ChangeRef::Deletion { id, .. } => (Some(id.into_owned()), None),
+                    ChangeRef::Modification {
+                        previous_id, id, ..
+                    } => (Some(previous_id.into_owned()), Some(id.into_owned())),
+                    ChangeRef::Rewrite { source_id, id, .. } => {
+                        (Some(source_id.into_owned()), Some(id.into_owned()))
+                    }
+                });
+                Ok::<_, Infallible>(Action::Continue)
+            },
+        )?;
+        let blob = |id: Option<ObjectId>| -> Result<Vec<u8>> {
+            Ok(match id {
+                Some(id) => repo.find_object(id)?.detach().data,
+                None => Vec::new(),
+            })
+        };
+        for (before, after) in changes {
+            let (insertions, deletions) = count_lines(&blob(before)?, &blob(after)?);
+            self.staged_insertions += insertions;
+            self.staged_deletions += deletions;
+        }
+        Ok(())
+    }
+
+    /// Set `nested` if repo lives inside another repo's worktree without
+    /// being its submodule
+    pub(crate) fn gix_nested(&mut self) -> Result {
+        let repo = discover()?;
+        let root = match repo.workdir() {
+            Some(dir) => dir.canonicalize()?,
+            None => return Ok(()),
+        };
+        if root.join(".git").is_file() {
+            // Submodule or linked worktree
+            return Ok(());
+        }
+        let outer = match search_dirs(&root).skip(1).find(|d| d.join(".git").exists()) {
+            Some(d) => d,
+            None => return Ok(()),
+        };
+        let rel = root
+            .strip_prefix(outer)?
+            .to_string_lossy()
+            .replace('\\', "/");
+        self.nested = !submodule_paths(&outer.join(".gitmodules")).contains(&rel);
+        Ok(())
+    }
+
+    /// Find nearest tag and number of commits since, like
+    /// `git describe --tags --long`
+    pub(crate) fn gix_tag_distance(&mut self) -> Result {
+        let repo = discover()?;
+        let head = match repo.head_commit() {
+            Ok(commit) => commit,
+            Err(_) => return Ok(()),
+        };
+        if let Some(res) = head
+            .describe()
+            .names(gix::commit::describe::SelectRef::AllTags)
+            .try_resolve()?
+        {
+            if let Some(name) = res.outcome.name {
+                self.tag_dist = Some(res.outcome.depth);
+                self.tag = Some(name.to_str_lossy().into_owned());
+            }
+        }
+        Ok(())
+    }
+
+    /// Get fetch URL of the upstream branch's remote
+    pub(crate) fn gix_remote_url(&mut self) -> Result {
+        let repo = discover()?;
+        let head = match repo.head_name()? {
+            Some(name) => name,
+            None => return Ok(()),
+        };
+        if let Some(remote) = repo.branch_remote(head.shorten(), Direction::Fetch) {
+            if let Some(url) = remote?.url(Direction::Fetch) {
+                self.remote = Some(normalize_url(&url.to_bstring().to_str_lossy()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Default branch: remote HEAD of `origin`, else local `main` or `master`
+    pub(crate) fn gix_default_branch(&self) -> Result<Option<String>> {
+        let repo = discover()?;
+        if let Some(head) = repo.try_find_reference("refs/remotes/origin/HEAD")? {
+            if let Some(target) = head.target().try_name() {
+                return Ok(Some(target.shorten().to_str_lossy().into_owned()));
+            }
+        }
+        for name in &["main", "master"] {
+            if repo
+                .try_find_reference(format!("refs/heads/{}", name).as_str())?
+                .is_some()
+            {
+                return Ok(Some(name.to_string()));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Count local branches, and those fully merged into the default branch
+    /// other than itself
+    pub(crate) fn gix_count_branches(&self) -> Result<(u32, u32)> {
+        let repo = discover()?;
+        let mut branches = vec![];
+        for reference in repo.references()?.local_branches()? {
+            let reference = reference.map_err(|e| format_err!("{}", e))?;
+            branches.push(reference.name().shorten().to_str_lossy().into_owned());
+        }
+        let local = u32::try_from(branches.len())?;
+        let default = match self.gix_default_branch()? {
+            Some(d) => d,
+            None => return Ok((local, 0)),
+        };
+        let default_id = repo
+            .rev_parse_single(format!("{}^{{commit}}", default).as_str())?
+            .detach();
+        let name = default.strip_prefix("origin/").unwrap_or(&default);
+        let mut merged = 0;
+        for branch in branches.iter().filter(|b| *b != name) {
+            let id = repo
+                .find_reference(format!("refs/heads/{}", branch).as_str())?
+                .peel_to_id()?
+                .detach();
+            // Merged if its tip is reachable from the default branch
+            if repo
+                .merge_base(id, default_id)
+                .is_ok_and(|base| base.detach() == id)
+            {
+                merged += 1;
+            }
+        }
+        Ok((local, merged))
+    }
+
+    /// Commit ids of HEAD and of revision `rev`, or `None` if either doesn't
+    /// name a commit
+    pub(crate) fn gix_head_and_commit(&self, rev: &str) -> Result<Option<(String, String)>> {
+        let repo = discover()?;
+        let head = match repo.head_id() {
+            Ok(id) => id,
+            Err(_) => return Ok(None),
+        };
+        match repo.rev_parse_single(format!("{}^{{commit}}", rev).as_str()) {
+            Ok(commit) => Ok(Some((head.to_string(), commit.to_string()))),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Commits reachable from `head` but not `base`, and from `base` but not
+    /// `head`. The walk runs to completion, whatever `--timeout` says
+    pub(crate) fn gix_count_divergence(
+        &self,
+        head: &str,
+        base: &str,
+    ) -> Result<Option<(u32, u32)>> {
+        let repo = discover()?;
+        let head = ObjectId::from_hex(head.as_bytes())?;
+        let base = ObjectId::from_hex(base.as_bytes())?;
+        Ok(Some((
+            count_commits(&repo, head, base)?,
+            count_commits(&repo, base, head)?,
+        )))
+    }
+
+    /// Get push destination of the branch and commits ahead of and behind it,
+    /// left unset if it's the upstream
+    pub(crate) fn gix_push_divergence(&mut self) -> Result {
+        let repo = discover()?;
+        let head = match repo.head_name()? {
+            Some(name) => name,
+            None => return Ok(()),
+        };
+        let push = match repo.branch_remote_tracking_ref_name(head.as_ref(), Direction::Push) {
+            Some(name) => name?.into_owned(),
+            None => return Ok(()),
+        };
+        let short = push.as_ref().shorten().to_str_lossy().into_owned();
+        if self.upstream.as_deref() == Some(short.as_str()) {
+            return Ok(());
+        }
+        // Like git, the destination is shown before it's first fetched
+        if let Some(mut target) = repo.try_find_reference(push.as_ref())? {
+            let target = target.peel_to_id()?.detach();
+            let head = repo.head_id()?.detach();
+            self.push_ahead = count_commits(&repo, head, target)?;
+            self.push_behind = count_commits(&repo, target, head)?;
+        }
+        self.push = Some(short);
+        Ok(())
+    }
+
+    /// Find remote-tracking refs named like `branch` on every remote, and count
+    /// commits ahead and behind for those not at HEAD
+    pub(crate) fn gix_remote_branches(&mut self, branch: &str) -> Result {
+        let repo = discover()?;
+        let head = match repo.head_id() {
+            Ok(id) => id.detach(),
+            Err(_) => return Ok(()),
+        };
+        let suffix = format!("/{}", branch);
+        let mut names = vec![];
+        for reference in repo.references()?.remote_branches()? {
+            let reference = reference.map_err(|e| format_err!("{}", e))?;
+            let name = reference.name().as_bstr().to_str_lossy().into_owned();
+            if name.ends_with(&suffix) {
+                names.push(name);
+            }
+        }
+        let mut remotes = vec![];
+        for name in names {
+            let remote = match name
+                .strip_prefix("refs/remotes/")
+                .and_then(|n| n.strip_suffix(&suffix))
+            {
+                Some(r) if !r.is_empty() => r.to_string(),
+                _ => continue,
+            };
+            let id = repo.find_reference(name.as_str())?.peel_to_id()?.detach();
+            let (ahead, behind) = if id == head {
+                (0, 0)
+            } else {
+                (
+                    count_commits(&repo, head, id)?,
+                    count_commits(&repo, id, head)?,
+                )
+            };
+            remotes.push(RemoteBranch {
+                remote,
+                ahead,
+                behind,
+            });
+        }
+        self.remote_branches = Some(remotes);
+        Ok(())
+    }
+
+    /// Get `user.email` in effect for the repo
+    pub(crate) fn gix_user_email(&mut self) -> Result {
+        let repo = discover()?;
+        self.user_email = repo
+            .config_snapshot()
+            .string("user.email")
+            .map(|email| email.to_str_lossy().into_owned());
+        Ok(())
+    }
+}
+
+/// Lines inserted and deleted going from `before` to `after`. Binary files
+/// count as no lines, like "-" in numstat output
+fn count_lines(before: &[u8], after: &[u8]) -> (u32, u32) {
+    if before.contains(&0) || after.contains(&0) {
+        return (0, 0);
+    }
+    let input = InternedInput::new(before, after);
+    let counter = diff(Algorithm::Histogram, &input, Counter::default());
+    (counter.insertions, counter.removals)
+}
+
+/// Tag pointing exactly at `id`, like `git describe --tags --exact-match`
+fn exact_tag(repo: &gix::Repository, id: ObjectId) -> Option<String> {
+    let res = repo
+        .find_commit(id)
+        .ok()?
+        .describe()
+        .names(gix::commit::describe::SelectRef::AllTags)
+        .max_candidates(1)
+        .try_resolve()
+        .ok()??;
+    match res.outcome.name {
+        Some(name) if res.outcome.depth == 0 => Some(name.to_str_lossy().into_owned()),
+        _ => None,
+    }
+}
+
+/// Number of commits reachable from `tip` but not from `hidden`
+fn count_commits(repo: &gix::Repository, tip: ObjectId, hidden: ObjectId) -> Result<u32> {
+    let mut count = 0;
+    for info in repo.rev_walk([tip]).with_hidden([hidden]).all()? {
+        info?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Age in seconds of the oldest commit reachable from `tip` but not `hidden`
+fn oldest_age(repo: &gix::Repository, tip: ObjectId, hidden: ObjectId) -> Result<Option<u64>> {
+    let mut oldest = None;
+    for info in repo.rev_walk([tip]).with_hidden([hidden]).all()? {
+        let time = info?.object()?.time()?.seconds;
+        oldest = Some(oldest.map_or(time, |t: i64| t.min(time)));
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    Ok(oldest.map(|t| now.saturating_sub(u64::try_from(t).unwrap_or(0))))
+}
+
+/// True if repo is a partial clone with a promisor remote
+fn is_promisor(repo: &gix::Repository) -> bool {
+    let config = repo.config_snapshot();
+    config.string("extensions.partialClone").is_some()
+        || repo.remote_names().iter().any(|name| {
+            config
+                .boolean(format!("remote.{}.promisor", name).as_str())
+                .unwrap_or(false)
+        })
+}
+
+/// Submodule paths listed in a `.gitmodules` file
+fn submodule_paths(gitmodules: &Path) -> Vec<String> {
+    let file = match gix::config::File::from_path_no_includes(
+        gitmodules.to_path_buf(),
+        gix::config::Source::Local,
+    ) {
+        Ok(file) => file,
+        Err(_) => return vec![],
+    };
+    file.sections_by_name("submodule")
+        .into_iter()
+        .flatten()
+        .filter_map(|section| section.value("path"))
+        .map(|path| path.to_str_lossy().into_owned())
+        .collect()
+}
+
+/// Open repo given by `GIT_DIR`, or containing current directory, stopping at
+/// ceiling dirs
+fn discover() -> Result<gix::Repository> {
+    let options = gix::discover::upwards::Options {
+        ceiling_dirs: ceiling_dirs(),
+        match_ceiling_dir_or_error: false,
+        ..Default::default()
+    };
+    Ok(
+        gix::ThreadSafeRepository::discover_with_environment_overrides_opts(
+            ".",
+            options,
+            Default::default(),
+        )?
+        .to_thread_local(),
+    )
+}
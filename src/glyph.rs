@@ -0,0 +1,147 @@
+//! Glyphs shown for prompt elements
+use crate::Result;
+use anyhow::format_err;
+use std::env;
+
+/// Glyph names accepted in the `[glyph]` config table, in `GlyphSet` field
+/// order. Each can also be set with `GITPR_GLYPH_<NAME>`, ex: `GITPR_GLYPH_AHEAD`
+pub const GLYPH_NAMES: &[&str] = &[
+    "ahead",
+    "bare",
+    "behind",
+    "both_added",
+    "both_modified",
+    "branch",
+    "branches",
+    "checks_fail",
+    "checks_pass",
+    "checks_pending",
+    "deleted_by_them",
+    "deleted_by_us",
+    "fetch",
+    "in_sync",
+    "merged",
+    "modified",
+    "nested",
+    "partial_clone",
+    "sparse",
+    "stash",
+    "timeout",
+    "unmerged",
+    "untracked",
+];
+
+/// Glyphs for elements of prompt
+#[derive(Debug, Clone)]
+pub struct GlyphSet {
+    pub ahead:           String,
+    pub bare:            String,
+    pub behind:          String,
+    pub both_added:      String,
+    pub both_modified:   String,
+    pub branch:          String,
+    pub branches:        String,
+    pub checks_fail:     String,
+    pub checks_pass:     String,
+    pub checks_pending:  String,
+    pub deleted_by_them: String,
+    pub deleted_by_us:   String,
+    pub fetch:           String,
+    pub in_sync:         String,
+    pub merged:          String,
+    pub modified:        String,
+    pub nested:          String,
+    pub partial_clone:   String,
+    pub sparse:          String,
+    pub stash:           String,
+    pub timeout:         String,
+    pub unmerged:        String,
+    pub untracked:       String,
+}
+
+impl Default for GlyphSet {
+    fn default() -> Self {
+        Self {
+            ahead:           "⇡".to_string(),
+            bare:            "[bare]".to_string(),
+            behind:          "⇣".to_string(),
+            both_added:      "⊕".to_string(),
+            both_modified:   "≠".to_string(),
+            branch:          "".to_string(),
+            branches:        "⑂".to_string(),
+            checks_fail:     "✗".to_string(),
+            checks_pass:     "✓".to_string(),
+            checks_pending:  "●".to_string(),
+            deleted_by_them: "⊘".to_string(),
+            deleted_by_us:   "⊖".to_string(),
+            fetch:           "⟳".to_string(),
+            in_sync:         "✓".to_string(),
+            merged:          "✂".to_string(),
+            modified:        "Δ".to_string(),
+            nested:          "⊂".to_string(),
+            partial_clone:   "☁".to_string(),
+            sparse:          "◎".to_string(),
+            stash:           "$".to_string(),
+            timeout:         "⧗".to_string(),
+            unmerged:        "‼".to_string(),
+            untracked:       "…".to_string(),
+        }
+    }
+}
+
+impl GlyphSet {
+    /// Override glyph of one element from `name=glyph`, ex: "ahead=↑"
+    pub fn set(&mut self, setting: &str) -> Result {
+        let (name, glyph) = setting
+            .split_once('=')
+            .ok_or_else(|| format_err!("glyph \"{}\" must look like NAME=GLYPH", setting))?;
+        *self.field(name.trim())? = glyph.to_string();
+        Ok(())
+    }
+
+    /// Override glyphs from `GITPR_GLYPH_<NAME>` environment variables
+    pub fn set_from_env(&mut self) -> Result {
+        for name in GLYPH_NAMES {
+            if let Ok(glyph) = env::var(format!("GITPR_GLYPH_{}", name.to_uppercase())) {
+                *self.field(name)? = glyph;
+            }
+        }
+        Ok(())
+    }
+
+    /// Glyph field for element name
+    fn field(&mut self, name: &str) -> Result<&mut String> {
+        Ok(match name {
+            "ahead" => &mut self.ahead,
+            "bare" => &mut self.bare,
+            "behind" => &mut self.behind,
+            "both_added" => &mut self.both_added,
+            "both_modified" => &mut self.both_modified,
+            "branch" => &mut self.branch,
+            "branches" => &mut self.branches,
+            "checks_fail" => &mut self.checks_fail,
+            "checks_pass" => &mut self.checks_pass,
+            "checks_pending" => &mut self.checks_pending,
+            "deleted_by_them" => &mut self.deleted_by_them,
+            "deleted_by_us" => &mut self.deleted_by_us,
+            "fetch" => &mut self.fetch,
+            "in_sync" => &mut self.in_sync,
+            "merged" => &mut self.merged,
+            "modified" => &mut self.modified,
+            "nested" => &mut self.nested,
+            "partial_clone" => &mut self.partial_clone,
+            "sparse" => &mut self.sparse,
+            "stash" => &mut self.stash,
+            "timeout" => &mut self.timeout,
+            "unmerged" => &mut self.unmerged,
+            "untracked" => &mut self.untracked,
+            n => {
+                return Err(format_err!(
+                    "unknown glyph \"{}\"; expected one of: {}",
+                    n,
+                    GLYPH_NAMES.join(", ")
+                ))
+            }
+        })
+    }
+}
@@ -0,0 +1,142 @@
+//! Gather status of Mercurial repos with `hg`
+use crate::{
+    repo::{GatherOptions, Untracked, Vcs},
+    vcs::VcsDriver,
+    Repo, Result,
+};
+use duct::{cmd, Expression};
+use std::{path::Path, time::Instant};
+
+/// Mercurial, read with `hg`
+pub struct Hg;
+
+impl VcsDriver for Hg {
+    fn vcs(&self) -> Vcs {
+        Vcs::Hg
+    }
+
+    fn detect(&self, dir: &Path) -> bool {
+        dir.join(".hg").is_dir()
+    }
+
+    fn collect(&self, opts: &GatherOptions<'_>) -> Result<Repo> {
+        Repo::from_hg(opts.untracked, opts.deadline)
+    }
+
+    fn tokens(&self) -> &'static [char] {
+        &['a', 'b', 'c', 'd', 'g', 'm', 'n', 'P', 'R', 'u', 'U', 'x']
+    }
+}
+
+/// `hg` command with plain output, unaffected by user config and locale
+fn hg(args: &[&str]) -> Expression {
+    cmd("hg", args).env("HGPLAIN", "1").stderr_null()
+}
+
+/// Number of revisions in `revset` relative to the working dir parent
+fn count_revs(ri: &Repo, revset: &str) -> Result<u32> {
+    Ok(ri.read_cmd(hg(&["log", "-r", revset, "-T", "x"]))?.len() as u32)
+}
+
+impl Repo {
+    /// Gather status of Mercurial repo in current directory. The active
+    /// bookmark is shown as the branch if there is one. Without upstream
+    /// tracking, ahead counts unpublished (draft) ancestors and behind counts
+    /// newer revisions on the same branch that aren't checked out
+    pub fn from_hg(untracked: Untracked, deadline: Option<Instant>) -> Result<Self> {
+        let mut ri = Repo {
+            vcs: Vcs::Hg,
+            deadline,
+            ..Default::default()
+        };
+        let head = ri.read_cmd(hg(&[
+            "log",
+            "-r",
+            ".",
+            "-T",
+            "{node}\\n{branch}\\n{activebookmark}\\n",
+        ]))?;
+        ri.parse_hg_head(&head);
+
+        // Mercurial always lists files inside untracked dirs
+        let flags = if untracked == Untracked::No {
+            "-mard"
+        } else {
+            "-mardu"
+        };
+        let status = ri.read_cmd(hg(&["status", flags]))?;
+        ri.parse_hg_status(&status);
+        let unresolved = ri.read_cmd(hg(&["resolve", "--list"]).unchecked())?;
+        ri.unmerged = unresolved.lines().filter(|l| l.starts_with("U ")).count() as u32;
+
+        ri.ahead = count_revs(&ri, "draft() and ::.")?;
+        ri.behind = count_revs(&ri, "descendants(.) and branch(.) and not .")?;
+        Ok(ri)
+    }
+
+    /// Get insertions/deletions from the summary line of `hg diff --stat`
+    pub fn hg_diff_stat(&mut self) -> Result {
+        let stat = self.read_cmd(hg(&["diff", "--stat"]))?;
+        self.parse_diff_stat(&stat);
+        Ok(())
+    }
+
+    /// Parse node, branch and active bookmark lines from `hg log -r .`
+    fn parse_hg_head(&mut self, head: &str) {
+        let mut lines = head.lines();
+        self.commit = lines.next().map(|node| {
+            if node.chars().all(|c| c == '0') {
+                String::from("(initial)")
+            } else {
+                node.to_string()
+            }
+        });
+        let branch = lines.next().filter(|b| !b.is_empty());
+        let bookmark = lines.next().filter(|b| !b.is_empty());
+        self.branch = bookmark.or(branch).map(String::from);
+    }
+
+    /// Parse `hg status` lines, ex: "M src/main.rs". Mercurial has no staging
+    /// area, so all changes count as unstaged
+    pub fn parse_hg_status(&mut self, status: &str) {
+        for line in status.lines() {
+            let path = line.get(2..).unwrap_or_default().to_string();
+            match line.chars().next() {
+                Some('M') => self.unstaged.parse_modified('M'),
+                Some('A') => self.unstaged.parse_modified('A'),
+                // Removed with `hg remove`, or deleted without telling hg
+                Some('R') | Some('!') => self.unstaged.parse_modified('D'),
+                Some('?') => {
+                    self.untracked += 1;
+                    self.untracked_files.push(path);
+                    continue;
+                }
+                _ => continue,
+            }
+            self.unstaged.files.push(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn hg_status() {
+        let mut ri = Repo::default();
+        ri.parse_hg_head("0123abcd\ndefault\nfeature\n");
+        ri.parse_hg_status("M src/main.rs\nA new.rs\nR old.rs\n! gone.rs\n? notes.txt\n");
+        assert_eq!(ri.branch.as_deref(), Some("feature"));
+        assert_eq!(
+            (ri.unstaged.modified, ri.unstaged.added, ri.unstaged.deleted),
+            (1, 1, 2)
+        );
+        assert_eq!(ri.unstaged.files.len(), 4);
+        assert_eq!(ri.untracked_files, vec!["notes.txt"]);
+
+        ri.parse_diff_stat(" a.rs | 4 +++-\n 1 files changed, 3 insertions(+), 1 deletions(-)\n");
+        assert_eq!((ri.insertions, ri.deletions), (3, 1));
+    }
+}
@@ -0,0 +1,150 @@
+//! Shell snippets wiring gitpr into the prompt, printed by `gitpr init`
+use anyhow::format_err;
+use gitpr::{output::fish_quote, Result};
+use std::{env, io::Write, str::FromStr};
+
+/// Shells `gitpr init` prints a snippet for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Shell {
+    Zsh,
+    Bash,
+    Fish,
+    Powershell,
+}
+
+impl FromStr for Shell {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "zsh" => Ok(Shell::Zsh),
+            "bash" => Ok(Shell::Bash),
+            "fish" => Ok(Shell::Fish),
+            "powershell" => Ok(Shell::Powershell),
+            _ => Err(format_err!("invalid shell \"{}\"", s)),
+        }
+    }
+}
+
+/// Runs gitpr in the background from `precmd` and redraws the prompt when its
+/// output arrives, so a slow repo never holds up the prompt. Until then the
+/// status of the previous prompt is shown. Each run gets the next number as
+/// `--async-token`, and frames from older runs are dropped
+const ZSH: &str = r#"# gitpr prompt for zsh; in ~/.zshrc: eval "$(gitpr init zsh)"
+setopt prompt_subst
+typeset -g _gitpr_status='' _gitpr_fd=''
+typeset -gi _gitpr_gen=0
+
+_gitpr_done() {
+  local fd=$1 frame=''
+  IFS= read -r -d '' -u $fd frame
+  zle -F $fd
+  exec {fd}<&-
+  [[ $fd == $_gitpr_fd ]] && _gitpr_fd=''
+  # Frame: token, tab, exit status, tab, output
+  [[ ${frame%%$'\t'*} == $_gitpr_gen ]] || return
+  _gitpr_status=${frame#*$'\t'*$'\t'}
+  zle reset-prompt
+}
+
+_gitpr_precmd() {
+  if [[ -n $_gitpr_fd ]]; then
+    zle -F $_gitpr_fd 2>/dev/null
+    exec {_gitpr_fd}<&-
+  fi
+  (( ++_gitpr_gen ))
+  # Empty outside a repo, leaving the plain prompt
+  exec {_gitpr_fd}< <(GITPR -d "$PWD" --escape zsh --async-token $_gitpr_gen 2>/dev/null)
+  zle -F $_gitpr_fd _gitpr_done
+}
+
+autoload -Uz add-zsh-hook
+add-zsh-hook precmd _gitpr_precmd
+PROMPT='%~ ${_gitpr_status:+$_gitpr_status }%# '
+"#;
+
+/// Bash has no way to redraw the prompt, so gitpr runs from `PROMPT_COMMAND`
+/// before each prompt. Its output is kept in a variable that a single-quoted
+/// PS1 refers to, like `__git_ps1` does with the branch name, so it's never
+/// expanded as part of PS1. `${var@P}` (bash 4.4) decodes its `\[...\]`
+/// escapes, which bash only reads in PS1 itself
+const BASH: &str = r#"# gitpr prompt for bash; in ~/.bashrc: eval "$(gitpr init bash)"
+_gitpr_git=''
+
+_gitpr_prompt() {
+  local last=$?
+  # Empty outside a repo, leaving the plain prompt
+  _gitpr_git=$(GITPR --escape bash 2>/dev/null)
+  _gitpr_git=${_gitpr_git@P}
+  return $last
+}
+
+if [[ $PROMPT_COMMAND != *_gitpr_prompt* ]]; then
+  PROMPT_COMMAND="_gitpr_prompt${PROMPT_COMMAND:+; $PROMPT_COMMAND}"
+fi
+PS1='\w ${_gitpr_git:+$_gitpr_git }\$ '
+"#;
+
+/// Fish prompt functions can't be redrawn from a background job, so gitpr
+/// runs each time the prompt is drawn
+const FISH: &str = r#"# gitpr prompt for fish; in ~/.config/fish/config.fish: gitpr init fish | source
+function fish_prompt
+    set -l last $status
+    # Empty outside a repo, leaving the plain prompt
+    set -l git (GITPR --color always 2>/dev/null | string collect)
+    echo -n (prompt_pwd)
+    test -n "$git"; and echo -n " $git"
+    test $last -ne 0; and echo -n " [$last]"
+    echo -n ' > '
+end
+"#;
+
+/// Replaces the `prompt` function, running gitpr only in file system
+/// locations. Native command output is decoded with the console encoding, so
+/// it's set to UTF-8 while gitpr runs to keep glyphs intact. `$LASTEXITCODE`
+/// is kept for the user's commands
+const POWERSHELL: &str = r#"# gitpr prompt for PowerShell; in $PROFILE:
+# Invoke-Expression (& gitpr init powershell | Out-String)
+function global:prompt {
+    $code = $global:LASTEXITCODE
+    $out = $ExecutionContext.SessionState.Path.CurrentLocation.Path
+    if ($PWD.Provider.Name -eq 'FileSystem') {
+        $enc = [Console]::OutputEncoding
+        try {
+            [Console]::OutputEncoding = [Text.UTF8Encoding]::new()
+            # Empty outside a repo, leaving the plain prompt
+            $git = (& GITPR -d $PWD.ProviderPath --escape powershell 2>$null) -join "`n"
+        } finally {
+            [Console]::OutputEncoding = $enc
+        }
+        if ($git) { $out += " $git" }
+    }
+    $global:LASTEXITCODE = $code
+    "$out$('>' * ($NestedPromptLevel + 1)) "
+}
+"#;
+
+/// Print snippet for `shell`, running this gitpr binary by its full path
+pub fn print<W: Write>(shell: Shell, buf: &mut W) -> Result {
+    let exe = env::current_exe()
+        .map(|p| p.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| String::from("gitpr"));
+    let (snippet, exe) = match shell {
+        Shell::Zsh => (ZSH, sh_quote(&exe)),
+        Shell::Bash => (BASH, sh_quote(&exe)),
+        Shell::Fish => (FISH, fish_quote(&exe)),
+        Shell::Powershell => (POWERSHELL, ps_quote(&exe)),
+    };
+    buf.write_all(snippet.replace("GITPR", &exe).as_bytes())?;
+    Ok(())
+}
+
+/// Quote for PowerShell: single quotes, with `'` doubled
+fn ps_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+/// Quote for POSIX shells: single quotes, with `'` written as `'\''`
+fn sh_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
@@ -0,0 +1,133 @@
+//! Gather status of Jujutsu repos with `jj`
+use crate::{
+    repo::{GatherOptions, Vcs},
+    vcs::VcsDriver,
+    Repo, Result,
+};
+use duct::{cmd, Expression};
+use std::{path::Path, time::Instant};
+
+/// Template for `jj log`: change ID, commit ID and bookmarks of `@`
+const HEAD_TEMPLATE: &str =
+    r#"change_id.short() ++ "\n" ++ commit_id ++ "\n" ++ bookmarks.join(" ") ++ "\n""#;
+
+/// Jujutsu, read with `jj`, including repos colocated with git
+pub struct Jj;
+
+impl VcsDriver for Jj {
+    fn vcs(&self) -> Vcs {
+        Vcs::Jj
+    }
+
+    fn detect(&self, dir: &Path) -> bool {
+        dir.join(".jj").is_dir()
+    }
+
+    fn collect(&self, opts: &GatherOptions<'_>) -> Result<Repo> {
+        Repo::from_jj(opts.deadline)
+    }
+
+    fn tokens(&self) -> &'static [char] {
+        &['b', 'c', 'd', 'g', 'j', 'm', 'n', 'P', 'R', 'U', 'x']
+    }
+}
+
+/// `jj` command without color
+fn jj(args: &[&str]) -> Expression {
+    cmd("jj", ["--color=never"].iter().chain(args)).stderr_null()
+}
+
+impl Repo {
+    /// Gather status of Jujutsu repo in current directory. The working-copy
+    /// commit's first bookmark is shown as the branch; its changes count as
+    /// unstaged, since jj has no staging area
+    pub fn from_jj(deadline: Option<Instant>) -> Result<Self> {
+        let mut ri = Repo {
+            vcs: Vcs::Jj,
+            deadline,
+            ..Default::default()
+        };
+        // Snapshots the working copy, so `jj log` can skip it
+        let status = ri.read_cmd(jj(&["status"]))?;
+        ri.parse_jj_status(&status);
+        let head = ri.read_cmd(jj(&[
+            "log",
+            "--ignore-working-copy",
+            "--no-graph",
+            "-r",
+            "@",
+            "-T",
+            HEAD_TEMPLATE,
+        ]))?;
+        ri.parse_jj_head(&head);
+        Ok(ri)
+    }
+
+    /// Get insertions/deletions of the working-copy commit
+    pub fn jj_diff_stat(&mut self) -> Result {
+        let stat = self.read_cmd(jj(&["diff", "--ignore-working-copy", "--stat"]))?;
+        self.parse_diff_stat(&stat);
+        Ok(())
+    }
+
+    /// Parse change ID, commit ID and bookmarks lines from `jj log`
+    fn parse_jj_head(&mut self, head: &str) {
+        let mut lines = head.lines();
+        self.change_id = lines.next().filter(|id| !id.is_empty()).map(String::from);
+        self.commit = lines.next().filter(|id| !id.is_empty()).map(String::from);
+        // Bookmarks ahead of their remote are marked with "*"
+        self.branch = lines
+            .next()
+            .and_then(|b| b.split_whitespace().next())
+            .map(|b| b.trim_end_matches('*').to_string());
+    }
+
+    /// Parse file lines of `jj status`, ex: "M src/main.rs", and conflicted
+    /// paths, ex: "src/main.rs    2-sided conflict"
+    fn parse_jj_status(&mut self, status: &str) {
+        for line in status.lines() {
+            if line.ends_with("-sided conflict") {
+                self.unmerged += 1;
+                continue;
+            }
+            let mut chars = line.chars();
+            if let (Some(kind @ ('M' | 'A' | 'D' | 'R' | 'C')), Some(' ')) =
+                (chars.next(), chars.next())
+            {
+                self.unstaged.parse_modified(kind);
+                self.unstaged.files.push(line[2..].to_string());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn jj_status() {
+        const STATUS: &str = "\
+Working copy changes:
+M src/main.rs
+A new.rs
+D old.rs
+There are unresolved conflicts at these paths:
+conflict.rs    2-sided conflict
+Working copy : kmtzqxwy 1a2b3c4d (no description set)
+Parent commit: qpvuntsm 9f8e7d6c main | init
+";
+        let mut ri = Repo::default();
+        ri.parse_jj_status(STATUS);
+        ri.parse_jj_head("kmtzqxwy\n1a2b3c4d5e6f\nmain* feature\n");
+        assert_eq!(
+            (ri.unstaged.modified, ri.unstaged.added, ri.unstaged.deleted),
+            (1, 1, 1)
+        );
+        assert_eq!(ri.unstaged.files, vec!["src/main.rs", "new.rs", "old.rs"]);
+        assert_eq!(ri.unmerged, 1);
+        assert_eq!(ri.change_id.as_deref(), Some("kmtzqxwy"));
+        assert_eq!(ri.branch.as_deref(), Some("main"));
+    }
+}
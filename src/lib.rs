@@ -0,0 +1,158 @@
+//! Git repo status for shell prompts and status bars.
+//!
+//! [`Repo`] gathers status of the repo in the current directory; the
+//! [`output`] module renders it with a format string.
+pub mod encoder;
+pub mod format;
+pub mod gitconfig;
+mod github;
+#[cfg(feature = "gix")]
+mod gitoxide;
+pub mod glyph;
+mod hg;
+mod jj;
+#[cfg(feature = "libgit2")]
+mod libgit2;
+pub mod output;
+pub mod repo;
+pub mod style;
+pub mod vcs;
+
+pub use glyph::GlyphSet;
+pub use output::{Options, OutputMode};
+pub use repo::{find_git_dir, Backend, GitArea, Repo};
+use std::{env, path::PathBuf};
+pub use style::StyleSet;
+
+/// `anyhow::Result` with default type of `()`
+pub type Result<T = ()> = anyhow::Result<T>;
+
+/// Cache location: `$XDG_CACHE_HOME/gitpr`, falling back to `~/.cache/gitpr`
+pub fn cache_dir() -> Option<PathBuf> {
+    env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|h| PathBuf::from(h).join(".cache")))
+        .map(|d| d.join("gitpr"))
+}
+
+/// Help message for format string token
+pub const FORMAT_STRING_USAGE: &str = "\
+Tokenized string may contain:
+------------------------------
+%g  branch glyph ()
+%n  VC name, ex: \"git\", \"hg\", \"jj\"
+%b  branch, or tag at HEAD when detached; \"[bare]\" follows in bare repos
+%D  detached HEAD indicator, ex: \"@abc1234\"
+%r  upstream
+%a  commits ahead/behind remote
+%f  time since last fetch, if stale, ex: \"⟳3h\"
+%A  commits ahead/behind base branch, ex: \"main⇡3⇣12\"
+%B  commits ahead/behind push branch (@{push}) if not the upstream,
+    ex: \"fork/main⇡2\"
+%c  current commit hash
+%m  unstaged changes (modified/added/removed)
+%s  staged changes (modified/added/removed)
+%u  untracked files
+%U  unmerged files (merge in progress)
+%p  pull request of branch and its checks, ex: \"#42✓\" (needs `gh`)
+%R  repo name (top dir of working tree), ex: \"gitpr\"
+%P  path of current dir in repo, ex: \"src/bin\"
+%o  operation in progress, ex: \"MERGING\", \"REBASING 2/5\"
+%d  diff lines of unstaged changes, ex: \"+20/-10\"
+%I  diff lines of staged changes, ex: \"+5/-2\"
+%t  stash count and age of newest stash, ex: \"$2 (3d)\"
+%x  dirty state summary like `__git_ps1`: \"*\" unstaged, \"+\" staged,
+    \"$\" stashed, \"%\" untracked, ex: \"*+%\"
+%e  nested repo indicator (repo inside another repo's worktree)
+%l  partial clone indicator (objects fetched lazily from promisor remote)
+%S  sparse checkout indicator (only part of the tree is checked out)
+%v  commits since nearest tag, ex: \"+47\"
+%T  nearest tag and distance, ex: \"v1.2.0-5-gabcdef1\"
+%h  upstream remote URL, ex: \"github.com/user/repo\"
+%M  remotes with a branch named like the current one, and commits
+    ahead/behind it, ex: \"origin✓ fork⇡2\"
+%i  user.email commits would be made with, ex: \"me@work.com\"
+%j  jj change ID, ex: \"kmtzqxwy\"
+%k  local branch count, ex: \"⑂5\"
+%K  local branches merged into default branch, ex: \"✂2\"
+%w  linked worktree name, ex: \"feature\"
+------------------------------
+%{NAME}
+    long form of a token, ex: \"%{branch}\" for \"%b\"; names:
+    ahead or ahead_behind (a), base_divergence (A), branch (b),
+    branch_count (k), branch_glyph (g), change_id (j), commit (c),
+    detached (D), diff (d), dirty or summary (x), fetch_age (f),
+    identity (i), merged_branches (K), nested (e), operation (o),
+    partial_clone (l), pull_request (p), push_divergence (B),
+    remote_branches (M), remote_url (h), repo_name (R), repo_path (P),
+    sparse (S), staged or modified_staged (s), staged_diff (I),
+    stash (t), tag (T), tag_distance (v), unmerged (U), unstaged or
+    modified_unstaged (m), untracked (u), upstream (r), vcs (n),
+    worktree (w)
+------------------------------
+%%  literal percent sign
+%10b, %-10b, %.20b
+    pad token to 10 columns on the left or right (\"-\"), or
+    truncate it to 20 with \"…\"; for %c, the number of hash
+    digits, ex: \"%8c\"
+%[?X then %: else %]
+    conditional section: render \"then\" if token %X produces
+    output, else \"else\" (\"%:\" and else part are optional).
+    Use it to test one token, or to show something in its
+    place, ex: \"%[?a%a%:✓%]\"
+%( group %)
+    shorthand for a section testing every token inside: render
+    group only if any of them produces output, so its brackets
+    or separators disappear too. \"%( [%a]%)\" is the same as
+    \"%[?a [%a]%]\"; \"%( %s%m%)\" needs no section per token
+------------------------------
+";
+
+/// Long token names valid in `%{NAME}`, and the token each stands for. Tokens
+/// with a `--style` element can also be named by it, ex: `%{ahead_behind}`
+pub const TOKEN_NAMES: &[(&str, char)] = &[
+    ("ahead", 'a'),
+    ("ahead_behind", 'a'),
+    ("base_divergence", 'A'),
+    ("branch", 'b'),
+    ("branch_count", 'k'),
+    ("branch_glyph", 'g'),
+    ("change_id", 'j'),
+    ("commit", 'c'),
+    ("detached", 'D'),
+    ("diff", 'd'),
+    ("dirty", 'x'),
+    ("fetch_age", 'f'),
+    ("identity", 'i'),
+    ("merged_branches", 'K'),
+    ("modified_staged", 's'),
+    ("modified_unstaged", 'm'),
+    ("nested", 'e'),
+    ("operation", 'o'),
+    ("partial_clone", 'l'),
+    ("pull_request", 'p'),
+    ("push_divergence", 'B'),
+    ("remote_branches", 'M'),
+    ("remote_url", 'h'),
+    ("repo_name", 'R'),
+    ("repo_path", 'P'),
+    ("sparse", 'S'),
+    ("summary", 'x'),
+    ("staged", 's'),
+    ("staged_diff", 'I'),
+    ("stash", 't'),
+    ("tag", 'T'),
+    ("tag_distance", 'v'),
+    ("unmerged", 'U'),
+    ("unstaged", 'm'),
+    ("untracked", 'u'),
+    ("upstream", 'r'),
+    ("vcs", 'n'),
+    ("worktree", 'w'),
+];
+
+/// Characters valid after `%` in format string
+pub const FORMAT_TOKENS: &[char] = &[
+    'a', 'A', 'b', 'B', 'c', 'd', 'D', 'e', 'f', 'g', 'h', 'i', 'I', 'j', 'k', 'K', 'l', 'm', 'M',
+    'n', 'o', 'p', 'P', 'r', 'R', 's', 'S', 't', 'u', 'U', 'T', 'v', 'w', 'x', '%',
+];
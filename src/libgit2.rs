@@ -0,0 +1,177 @@
+//! Gather repo status in-process with libgit2 instead of spawning `git`
+use crate::{
+    repo::{ceiling_dirs, env_git_dir, find_work_tree, Untracked},
+    Repo, Result,
+};
+use git2::{
+    BranchType, DescribeOptions, ErrorCode, Repository, RepositoryOpenFlags, Status, StatusOptions,
+};
+use std::{
+    convert::TryFrom,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+impl Repo {
+    /// Gather status of repo in current directory, matching what
+    /// `git status --porcelain=2 --branch` reports, and the stash. Git dir is
+    /// filled in too, so lookups of other tokens spawn `git` less
+    pub fn from_libgit2(untracked: Untracked, renames: bool) -> Result<Self> {
+        // Ceiling dirs passed to `open_ext` are ignored if it reads the
+        // environment, so `GIT_DIR` is only honored without ceiling dirs.
+        // libgit2 doesn't read `GIT_WORK_TREE`
+        let ceilings = ceiling_dirs();
+        let repo = if let Some(git_dir) = env_git_dir() {
+            let repo = Repository::open(git_dir)?;
+            if let Some(work_tree) = find_work_tree(&std::env::current_dir()?) {
+                repo.set_workdir(&work_tree, false)?;
+            }
+            repo
+        } else if ceilings.is_empty() {
+            Repository::open_from_env()?
+        } else {
+            Repository::open_ext(".", RepositoryOpenFlags::empty(), &ceilings)?
+        };
+        let mut ri = Repo {
+            git_dir: Some(
+                repo.path()
+                    .to_string_lossy()
+                    .trim_end_matches('/')
+                    .to_string(),
+            ),
+            ..Default::default()
+        };
+
+        match repo.head() {
+            Ok(head) => {
+                ri.commit = head.target().map(|oid| oid.to_string());
+                ri.detached = !head.is_branch();
+                ri.branch = if head.is_branch() {
+                    head.shorthand().map(String::from)
+                } else {
+                    Some(exact_tag(&repo).unwrap_or_else(|| String::from("unknown")))
+                };
+                if let (Some(name), Some(local)) = (head.shorthand(), head.target()) {
+                    if let Ok(upstream) = repo
+                        .find_branch(name, BranchType::Local)
+                        .and_then(|b| b.upstream())
+                    {
+                        ri.upstream = upstream.name()?.map(String::from);
+                        if let Some(remote) = upstream.get().target() {
+                            let (ahead, behind) = repo.graph_ahead_behind(local, remote)?;
+                            ri.ahead = u32::try_from(ahead)?;
+                            ri.behind = u32::try_from(behind)?;
+                        }
+                    }
+                }
+            }
+            // Unborn branch: HEAD points to a ref that doesn't exist yet
+            Err(e) if e.code() == ErrorCode::UnbornBranch => {
+                ri.commit = Some(String::from("(initial)"));
+                ri.branch = repo
+                    .find_reference("HEAD")?
+                    .symbolic_target()
+                    .map(|r| r.trim_start_matches("refs/heads/").to_string());
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        let mut opts = StatusOptions::new();
+        opts.include_untracked(untracked != Untracked::No)
+            .recurse_untracked_dirs(untracked == Untracked::All)
+            .include_ignored(false)
+            .renames_head_to_index(renames);
+        for entry in repo.statuses(Some(&mut opts))?.iter() {
+            let s = entry.status();
+            let path = entry.path().unwrap_or_default().to_string();
+            if s.is_conflicted() {
+                ri.unmerged += 1;
+                continue;
+            }
+            if s.is_wt_new() {
+                ri.untracked += 1;
+                ri.untracked_files.push(path);
+                continue;
+            }
+            if s.intersects(
+                Status::INDEX_NEW
+                    | Status::INDEX_MODIFIED
+                    | Status::INDEX_DELETED
+                    | Status::INDEX_RENAMED
+                    | Status::INDEX_TYPECHANGE,
+            ) {
+                ri.staged.files.push(path.clone());
+            }
+            if s.intersects(
+                Status::WT_MODIFIED
+                    | Status::WT_DELETED
+                    | Status::WT_RENAMED
+                    | Status::WT_TYPECHANGE,
+            ) {
+                ri.unstaged.files.push(path);
+            }
+            if s.intersects(Status::INDEX_MODIFIED | Status::INDEX_TYPECHANGE) {
+                ri.staged.modified += 1;
+            }
+            if s.is_index_new() {
+                ri.staged.added += 1;
+            }
+            if s.is_index_deleted() {
+                ri.staged.deleted += 1;
+            }
+            if s.is_index_renamed() {
+                ri.staged.renamed += 1;
+            }
+            if s.intersects(Status::WT_MODIFIED | Status::WT_TYPECHANGE) {
+                ri.unstaged.modified += 1;
+            }
+            if s.is_wt_deleted() {
+                ri.unstaged.deleted += 1;
+            }
+            if s.is_wt_renamed() {
+                ri.unstaged.renamed += 1;
+            }
+        }
+        if ri.unmerged > 0 {
+            for conflict in repo.index()?.conflicts()? {
+                let c = conflict?;
+                ri.conflicts
+                    .add(c.ancestor.is_some(), c.our.is_some(), c.their.is_some());
+            }
+        }
+        read_stash(&repo, &mut ri)?;
+        Ok(ri)
+    }
+}
+
+/// Count stash entries from the stash reflog, newest first, like
+/// `Repo::git_stash_count`
+fn read_stash(repo: &Repository, ri: &mut Repo) -> Result {
+    let stash = match repo.find_reference("refs/stash") {
+        Ok(r) => r,
+        Err(e) if e.code() == ErrorCode::NotFound => {
+            ri.stashed = Some(0);
+            return Ok(());
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let reflog = repo.reflog("refs/stash")?;
+    let newest = match reflog.get(0) {
+        Some(entry) => entry.committer().when().seconds(),
+        None => stash.peel_to_commit()?.time().seconds(),
+    };
+    ri.stashed = Some(u32::try_from(reflog.len().max(1))?);
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    ri.stash_age = u64::try_from(newest).ok().map(|ts| now.saturating_sub(ts));
+    Ok(())
+}
+
+/// Tag pointing exactly at HEAD, like `git describe --tags --exact-match`
+fn exact_tag(repo: &Repository) -> Option<String> {
+    repo.describe(
+        DescribeOptions::new()
+            .describe_tags()
+            .max_candidates_tags(0),
+    )
+    .and_then(|d| d.format(None))
+    .ok()
+}
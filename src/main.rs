@@ -1,117 +1,95 @@
 //! Print git repo status. Handy for shell prompt.
+mod cache;
+mod config;
+#[cfg(any(unix, windows))]
+mod daemon;
+mod init;
 mod logger;
+mod profile;
+mod scan;
+mod timings;
+#[cfg(any(unix, windows))]
+mod transport;
+mod watch;
 
-// use ansi_term::{ANSIString, ANSIStrings, Style};
-use anyhow::{format_err, Context};
-use clap::{AppSettings, ArgSettings, Clap};
-use duct::cmd;
+use anyhow::format_err;
+use cache::Cache;
+use clap::{AppSettings, ArgMatches, ArgSettings, Clap, FromArgMatches, IntoApp};
+use config::Config;
+use gitpr::{
+    format, git,
+    gitconfig::GitConfig,
+    output::{
+        check_async_token, escape_prompt, fs_only_output, list_files, mark_cached_json,
+        simple_output, write_async_frame, write_batch_entry, write_output, BatchSummary,
+        ColorChoice, DiffStyle, Escape, ListArea,
+    },
+    repo::{
+        in_repo, set_ceiling_dirs, set_git_locale, set_quiet_errors, GatherOptions, Untracked,
+        UpstreamRules,
+    },
+    style::ColorDepth,
+    Backend, GlyphSet, Options, OutputMode, Repo, Result, StyleSet, FORMAT_STRING_USAGE,
+};
+use init::Shell;
 use log::{debug, info};
 use std::{
-    convert::TryFrom,
     default::Default,
     env,
-    io::Write,
-    path::{Path, PathBuf},
-    str,
+    io::{BufRead, IsTerminal, Write},
+    path::PathBuf,
+    time::{Duration, Instant},
 };
-use writecolor::{Color::*, Style};
-
-/// `anyhow::Result` with default type of `()`
-type Result<T = ()> = anyhow::Result<T>;
-
-/// Help message for format string token
-const FORMAT_STRING_USAGE: &str = "\
-Tokenized string may contain:
-------------------------------
-%g  branch glyph ()
-%n  VC name
-%b  branch
-%r  upstream
-%a  commits ahead/behind remote
-%c  current commit hash
-%m  unstaged changes (modified/added/removed)
-%s  staged changes (modified/added/removed)
-%u  untracked files
-%U  unmerged files (merge in progress)
-%d  diff lines, ex: \"+20/-10\"
-%t  stashed files indicator
-------------------------------
-";
-
-/// Color styling for elements of prompt
-#[derive(Debug, Default)]
-struct StyleSet {
-    plain:             Style,
-    ahead_behind:      Style,
-    branch:            Style,
-    branch_glyph:      Style,
-    commit:            Style,
-    diff:              Style,
-    dirty:             Style,
-    modified_unstaged: Style,
-    modified_staged:   Style,
-    stash:             Style,
-    untracked:         Style,
-    unmerged:          Style,
-    upstream:          Style,
-}
+use timings::Timer;
 
-#[allow(dead_code)]
-impl StyleSet {
-    /// Blue ANSI color (intense)
-    const BLUE: u8 = 12;
-    /// Bold silver ANSI color
-    const BOLD_SILVER: u8 = 188;
-    /// Cyan ANSI color (intense)
-    const CYAN: u8 = 14;
-    /// Gray ANSI color
-    const GRAY: u8 = 245;
-
-    /// Full format
-    fn standard() -> Self {
-        Self {
-            branch: Blue.intense(),
-            commit: Black.on(Green),
-            diff: Fixed(Self::BOLD_SILVER).normal(),
-            modified_unstaged: Red.into(),
-            modified_staged: Red.into(),
-            stash: Yellow.into(),
-            untracked: Fixed(Self::GRAY).into(),
-            unmerged: Red.into(),
-            ..StyleSet::default()
-        }
-    }
+/// Exit status for bad options or config
+const EXIT_ERROR: i32 = 1;
 
-    /// Simple git prompt emulation
-    fn simple() -> Self {
-        Self {
-            branch: Fixed(Self::CYAN).into(),
-            dirty: Red.into(),
-            ..StyleSet::default()
-        }
-    }
-}
+/// Exit status outside a repo, when nothing is printed
+const EXIT_NOT_A_REPO: i32 = 2;
 
-/// Options from format string
-#[derive(Debug, Default)]
-struct Opt {
-    show_ahead_behind:      bool,
-    show_branch:            bool,
-    show_branch_glyph:      bool,
-    show_commit:            bool,
-    show_diff:              bool,
-    show_upstream:          bool,
-    show_stashed:           bool,
-    show_staged_modified:   bool,
-    show_unstaged_modified: bool,
-    show_untracked:         bool,
-    show_unmerged:          bool,
-    show_vcs:               bool,
-}
+/// Exit status when getting status of the repo failed
+const EXIT_VCS_ERROR: i32 = 3;
+
+/// Exit status when `--timeout` cut the output short
+const EXIT_TIMED_OUT: i32 = 4;
+
+/// Exit status of a run over several repos when some have changes
+const EXIT_SOME_DIRTY: i32 = 1;
+
+/// Exit status of a run over several repos when some failed or timed out
+const EXIT_SOME_FAILED: i32 = 2;
+
+/// Help sections on environment variables and exit status, for prompt scripts
+const AFTER_HELP: &str = "\
+ENVIRONMENT:
+    GITPR_<KEY> sets any config key that isn't a table, ex:
+    GITPR_FORMAT, GITPR_INDICATORS_ONLY=1, GITPR_TIMEOUT=50,
+    GITPR_THEME=nord; lists are separated like PATH. Command line
+    options override them, and they override config files and
+    `gitpr.*` git config. Also: GITPR_PROFILE, GITPR_GLYPH_<NAME>
+
+EXIT STATUS:
+    0  status printed
+    1  bad options or config file
+    2  not in a repo; nothing printed, and no VCS command run
+    3  getting status failed, ex: git error or corrupt repo
+    4  --timeout passed; partial status printed
+
+    With several --dir, --stdin or scan:
+    0  every repo is clean
+    1  some repos have changes, untracked files or conflicts
+    2  some repos failed or timed out";
 
 /// Command line configuration
 #[derive(Clap, Debug)]
-#[clap(author, about, version, setting = AppSettings::ColoredHelp)]
+#[clap(
+    author,
+    about,
+    version,
+    after_help = AFTER_HELP,
+    setting = AppSettings::ColoredHelp
+)]
 struct Arg {
     /// Debug verbosity (ex: -v, -vv, -vvv)
     #[clap(short, long, parse(from_occurrences))]
@@ -121,13 +99,87 @@ struct Arg {
     #[clap(short, long)]
     quiet: bool,
 
+    /// Print no errors to stderr, neither gitpr's nor git's; the exit status
+    /// still tells what failed
+    #[clap(long)]
+    quiet_errors: bool,
+
+    /// Only tell whether there are staged and unstaged changes, for huge repos
+    /// where `git status` takes seconds
+    ///
+    /// Runs `git diff --quiet` and `git diff --cached --quiet` instead of
+    /// `git status`, so '%m' and '%s' show as indicators only, and JSON counts
+    /// each as one change. Only for the `git` backend
+    #[clap(long)]
+    fast_dirty: bool,
+
+    /// Leave out staged and unstaged changes, like `__git_ps1` without
+    /// `GIT_PS1_SHOWDIRTYSTATE`
+    ///
+    /// Set in a repo with `git config bash.showDirtyState false`. '%m', '%s',
+    /// '%d' and '%I' are left empty, and `git diff` isn't run for them
+    #[clap(long)]
+    no_dirty_state: bool,
+
+    /// Detect renamed and copied files in status
+    ///
+    /// Off by default: `git status` is run with `--no-renames`, which saves
+    /// comparing added and deleted files. Renames then count as one file
+    /// deleted and one added
+    #[clap(long)]
+    renames: bool,
+
+    /// Read `git status` output line by line instead of as NUL-terminated
+    /// entries
+    ///
+    /// A fallback for the `git` backend. Paths are then read from the C-style
+    /// quoting git uses for names with tabs, newlines or quotes
+    #[clap(long)]
+    line_status: bool,
+
     /// Show indicators instead of numeric values.
     ///
     /// Does not apply to '%d' (diff), which always uses numeric values
     #[clap(short, long)]
     indicators_only: bool,
 
-    /// Disable color in output
+    /// What '%d' and '%I' show: line counts, changed file count, or both
+    ///
+    /// `files` shows the count from status, ex: "3Δ", so `git diff` isn't
+    /// run. `both` shows it before the line counts, ex: "3Δ +20/-10"
+    #[clap(
+        long,
+        value_name = "STYLE",
+        default_value = "lines",
+        possible_values = &["lines", "files", "both"]
+    )]
+    diff_style: DiffStyle,
+
+    /// When to color output
+    ///
+    /// `auto` colors output for a terminal, and for shell prompts and status
+    /// lines, which always capture the output: when `--escape` names a shell,
+    /// or the output is `tmux` or `powerline`. A
+    /// non-empty `NO_COLOR` turns it off, and `CLICOLOR_FORCE` other than "0"
+    /// turns it on regardless of the terminal
+    #[clap(
+        long,
+        value_name = "WHEN",
+        default_value = "auto",
+        possible_values = &["auto", "always", "never"]
+    )]
+    color: ColorChoice,
+
+    /// Colors the terminal can show: `16`, `256` or `truecolor`
+    ///
+    /// Styles are mapped to the nearest colors the terminal shows. Detected
+    /// from `COLORTERM` and `TERM` if not given: 24-bit for "truecolor" or
+    /// "24bit" and `-direct` terminals, 16 colors for consoles like "linux",
+    /// and 256 otherwise
+    #[clap(long, value_name = "DEPTH", possible_values = &["16", "256", "truecolor"])]
+    color_depth: Option<ColorDepth>,
+
+    /// Disable color in output; same as `--color never`
     #[clap(short, long)]
     no_color: bool,
 
@@ -138,15 +190,70 @@ struct Arg {
     #[clap(short = "t", long)]
     no_trim: bool,
 
+    /// Fit the prompt in N columns, ex: for a zsh `RPROMPT` that would wrap
+    ///
+    /// Elements are dropped least important first: diff stats, untracked,
+    /// fetch age, branch counts and so on, keeping the branch, staged,
+    /// modified and unmerged counts longest. If it still doesn't fit, the
+    /// branch is truncated with "…". Width counts display columns, so wide
+    /// characters take two
+    #[clap(long, value_name = "N")]
+    max_width: Option<usize>,
+
+    /// Show age of the oldest upstream commit missing locally, ex: "⇣4 (3d)"
+    ///
+    /// Applies to '%a' when the branch is behind its upstream
+    #[clap(long)]
+    divergence_age: bool,
+
+    /// Break '%U' down by kind of conflict, each with its own glyph, ex:
+    /// "≠2⊖1" for 2 files modified by both sides and 1 deleted by us
+    ///
+    /// Glyphs: both_modified, both_added, deleted_by_us, deleted_by_them, and
+    /// unmerged for other conflicts
+    #[clap(long)]
+    unmerged_detail: bool,
+
+    /// Branch '%A' counts commits ahead and behind of, ex: `origin/develop`
+    ///
+    /// Defaults to the remote HEAD of `origin`, else local `main` or `master`
+    #[clap(long, value_name = "BRANCH")]
+    base_branch: Option<String>,
+
+    /// `user.email` '%i' should show; any other is painted with the
+    /// `identity_mismatch` style
+    ///
+    /// Best set per directory, ex: `git config gitpr.expected-email me@work.com`
+    /// in an `includeIf "gitdir:~/work/"` file, or in `.gitpr.toml`
+    #[clap(long, value_name = "EMAIL")]
+    expected_email: Option<String>,
+
+    /// Paint '%b' with the `branch_protected` style on branches matching
+    /// GLOB, as a warning before committing, ex: `--protected-branch main`
+    ///
+    /// `*` matches within one "/" part, ex: `release/*`. May be repeated;
+    /// adds to the `protected_branches` config list
+    #[clap(long, value_name = "GLOB", number_of_values = 1)]
+    protected_branch: Vec<String>,
+
+    /// Switch entire output to an alarm palette when there are unmerged files
+    /// or a merge, rebase, cherry-pick, revert or bisect is in progress
+    #[clap(long)]
+    alert: bool,
+
     /// Simple mode (similar to factory git prompt)
     ///
     /// Does not accept format string (-f, --format)
     #[clap(short, long = "simple")]
     simple_mode: bool,
 
-    /// Simple mode 2 (development)
-    #[clap(short = "S", long = "simple2")]
-    simple_mode2: bool,
+    /// Like --simple, but read from git's files without running git
+    ///
+    /// Shows branch, or commit when detached, a merge or rebase in progress,
+    /// and `*` if a tracked file's size or modified time changed. Staged
+    /// changes and untracked files aren't shown
+    #[clap(short = "S", long = "fs-only", alias = "simple2")]
+    fs_only: bool,
 
     /// Format print-f style string
     #[clap(
@@ -154,594 +261,957 @@ struct Arg {
         long,
         value_name = "F-STRING",
         default_value = "%g %b@%c %a %m %d %s %u %t %U",
-        long_about = FORMAT_STRING_USAGE
+        long_about = FORMAT_STRING_USAGE,
+        setting = ArgSettings::AllowEmptyValues
     )]
     format: String,
 
+    /// Output mode
+    ///
+    /// `waybar` prints a JSON object with `text`, `tooltip` and `class` keys
+    /// for use in a waybar custom module (`"return-type": "json"`).
+    /// Its `text` is Pango markup like `pango` output, and its `tooltip` is
+    /// escaped for it.
+    /// `polybar` colors each element with polybar format tags, `pango` with
+    /// Pango `<span foreground>` markup on one line, ex: for i3blocks with
+    /// `markup=pango`, and `i3blocks` prints the full text, short text and
+    /// color lines of the i3blocks protocol. Their colors come from the
+    /// element styles, including `--theme` and `--style`.
+    /// `plain-verbose` describes the format string tokens in words with no
+    /// glyphs or color, ex: "branch main, 2 ahead, 3 modified".
+    /// `json` prints every status field as a JSON object for scripts,
+    /// ignoring the format string; fields that may be out of date, because
+    /// `--timeout` cut them short or a cached render was reused, are wrapped
+    /// as `{"value": ..., "stale": true, "age_ms": ...}`.
+    /// `tmux` writes `#[fg=colourN]` tags for `status-right`, `vim` writes
+    /// `%#GitprBranch#`-style highlight groups for `statusline`, `powerline`
+    /// draws elements as colored blocks, and `json-segments` prints an array of
+    /// `{"element", "text"}` objects.
+    /// `kv` prints the `json` fields as uncolored `key=value` lines, ex:
+    /// `staged_modified=2`, for starship `custom` modules and other prompt
+    /// engines that do their own styling; `stale` lists timed out fields.
+    /// `fish-vars` prints them as fish statements, ex: `set -g gitpr_branch
+    /// 'main'`, for `gitpr --output fish-vars | source` in `fish_prompt`.
+    /// `porcelain` prints them as `key value` lines, ex: `staged.modified 2`,
+    /// in a schema kept stable across versions: `format_version` comes first,
+    /// keys are only added at the end, lines without a value are left out and
+    /// `stale` comes last
+    #[clap(
+        long,
+        value_name = "MODE",
+        default_value = "prompt",
+        possible_values = &[
+            "prompt",
+            "waybar",
+            "polybar",
+            "pango",
+            "i3blocks",
+            "plain-verbose",
+            "json",
+            "tmux",
+            "vim",
+            "powerline",
+            "json-segments",
+            "kv",
+            "fish-vars",
+            "porcelain",
+        ]
+    )]
+    output: OutputMode,
+
+    /// Wrap ANSI escapes for the shell prompt so line width is counted right
+    ///
+    /// `zsh` wraps them in `%{...%}` for `PROMPT`, `bash` in `\[...\]` for
+    /// `PS1`, escaping `\`, `$` and backticks. Bash only reads `\[...\]` in
+    /// PS1 itself, so from `PROMPT_COMMAND`, decode the output into a variable
+    /// a single-quoted PS1 refers to, ex: `g=$(gitpr --escape bash);
+    /// g=${g@P}` and `PS1='${g} $ '`, as `gitpr init bash` does.
+    /// `powershell` leaves them as they are, since PowerShell and PSReadLine
+    /// skip SGR sequences when measuring the prompt; gitpr turns on their
+    /// handling in Windows consoles
+    #[clap(
+        long,
+        value_name = "SHELL",
+        default_value = "none",
+        possible_values = &["zsh", "bash", "powershell", "none"]
+    )]
+    escape: Escape,
+
+    /// Frame output for async prompt plugins, tagged with TOKEN
+    ///
+    /// Prints TOKEN, a tab, the exit status, a tab, then the output and a NUL
+    /// byte. The frame is printed even when the output is empty, ex: outside a
+    /// repo or on errors, so the plugin always hears back. Pass a new token
+    /// for each prompt and drop frames that don't carry the latest one. TOKEN
+    /// can't contain tabs, newlines or NUL
+    #[clap(long, value_name = "TOKEN")]
+    async_token: Option<String>,
+
+    /// How hard to look for untracked files, when '%u', `--list` or a JSON
+    /// output mode needs them
+    ///
+    /// `no` never looks, `normal` counts an untracked dir once without looking
+    /// inside, and `all` counts every file inside untracked dirs, like git's
+    /// `--untracked-files`. `auto` is `normal`, but skips the expensive walk of
+    /// the work tree in repos whose index is larger than 10 MiB
+    #[clap(
+        long,
+        value_name = "MODE",
+        default_value = "normal",
+        possible_values = &["no", "normal", "all", "auto"]
+    )]
+    untracked: Untracked,
+
+    /// Don't count untracked paths matching gitignore-style GLOB, ex:
+    /// `--untracked-exclude node_modules/`
+    ///
+    /// May be repeated; adds to the `untracked_exclude` config list
+    #[clap(long, value_name = "GLOB", number_of_values = 1)]
+    untracked_exclude: Vec<String>,
+
+    /// Hide a count until it reaches N, ex: `--hide-below untracked=3`
+    ///
+    /// May be repeated; the `[hide_below]` config table sets defaults. Also
+    /// applies to `%x`. Elements: ahead, behind, stash, staged, unmerged,
+    /// unstaged, untracked
+    #[clap(long, value_name = "ELEMENT=N", number_of_values = 1)]
+    hide_below: Vec<String>,
+
+    /// Days after which the newest stash is shown with the `stash_old` style
+    #[clap(long, value_name = "DAYS", default_value = "3")]
+    stash_warn_days: u64,
+
+    /// Fetch in the background when the last fetch is older than MINUTES
+    /// (default 15), so '%a' stays current
+    ///
+    /// The prompt doesn't wait for the fetch; it shows in the next prompt
+    #[clap(long, value_name = "MINUTES", require_equals = true)]
+    auto_fetch: Option<Option<u64>>,
+
+    /// Hours since last fetch after which '%f' shows its age
+    #[clap(long, value_name = "HOURS", default_value = "1")]
+    fetch_stale_hours: u64,
+
+    /// Color theme: standard, solarized, gruvbox, nord, mono, or a theme file
+    ///
+    /// A theme file is TOML with a `[style]` table like the config file's,
+    /// and may build on another theme with `inherits = "nord"`. NAME is a
+    /// path to a `.toml` file, or looked up as `themes/NAME.toml` next to the
+    /// config file. `--style` and the `[style]` config table override it
+    #[clap(long, value_name = "NAME")]
+    theme: Option<String>,
+
+    /// Override color of a prompt element, ex: `--style branch=blue,bold`
+    ///
+    /// SPEC is comma-separated: a color name, 256-color number or `#rrggbb`
+    /// hex (24-bit, mapped to the nearest color of terminals with fewer; see
+    /// `--color-depth`), `bold` for the bright variant of a
+    /// named color, and `bg:COLOR` for background. May be repeated; the
+    /// `[style]` config table sets defaults. Elements: plain, ahead_behind,
+    /// bare, branch, branch_protected, branch_glyph, branch_count, change_id,
+    /// commit, detached, diff, dirty, fetch_age, identity, identity_mismatch,
+    /// nested, operation, partial_clone, pull_request, remote_url, repo_name,
+    /// repo_path, modified_unstaged, modified_staged, sparse, staged_diff,
+    /// stash, stash_old, tag_distance, untracked, unmerged, upstream, worktree
+    #[clap(long, value_name = "ELEMENT=SPEC", number_of_values = 1)]
+    style: Vec<String>,
+
+    /// Glyph overrides from the `[glyph]` config table, as `name=glyph`
+    #[clap(skip)]
+    glyph: Vec<String>,
+
+    /// Upstream shortening rules from `upstream_*` config keys
+    #[clap(skip)]
+    upstream: UpstreamRules,
+
+    /// Whether output is colored, as `configure` resolved it from `--color`,
+    /// the environment and whether output goes to a terminal
+    #[clap(skip)]
+    colored: bool,
+
+    /// List changed files by area under the prompt line
+    ///
+    /// AREA is one of `staged`, `unstaged`, `untracked` or `all` (default).
+    /// With an empty format string the list is the only output. Ignored for
+    /// status bar output modes
+    #[clap(
+        long,
+        value_name = "AREA",
+        require_equals = true,
+        possible_values = &["staged", "unstaged", "untracked", "all"]
+    )]
+    list: Option<Option<ListArea>>,
+
+    /// Backend used to read repo status
+    ///
+    /// `libgit2` reads the repo in-process instead of spawning `git status`,
+    /// and needs gitpr built with the `libgit2` feature. `gix` uses gitoxide
+    /// for every token, so no system git is needed at runtime, and needs the
+    /// `gix` feature
+    #[clap(
+        long,
+        value_name = "BACKEND",
+        default_value = "git",
+        possible_values = &["git", "libgit2", "gix"]
+    )]
+    backend: Backend,
+
+    /// Give up on git commands after MS milliseconds in total
+    ///
+    /// Output gathered so far is printed, followed by the `timeout` glyph.
+    /// Only `git` commands can be interrupted; in-process status from the
+    /// `libgit2` and `gix` backends runs to completion
+    #[clap(long, value_name = "MS")]
+    timeout: Option<u64>,
+
+    /// `LC_ALL` locale for git commands, so their output parses the same
+    /// everywhere
+    ///
+    /// Empty keeps the user's locale. Paths are always read unquoted with
+    /// `core.quotePath=false`
+    #[clap(long, value_name = "LOCALE", default_value = "C")]
+    git_locale: String,
+
+    /// Never look for a repo in absolute path DIR or above it, like
+    /// `GIT_CEILING_DIRECTORIES`
+    ///
+    /// Keeps prompts in network mounts or containers from hanging on slow
+    /// automounts above the boundary. May be repeated; adds to the `ceiling`
+    /// config list and `GIT_CEILING_DIRECTORIES`
+    #[clap(long, value_name = "DIR", number_of_values = 1)]
+    ceiling: Vec<PathBuf>,
+
+    /// Git dir of the repo, like `GIT_DIR`, ex: `--git-dir ~/.dotfiles`
+    ///
+    /// No repo is searched for. The working tree is the current dir unless
+    /// `--work-tree` or `GIT_WORK_TREE` is set
+    #[clap(long, value_name = "DIR")]
+    git_dir: Option<PathBuf>,
+
+    /// Working tree of the repo given by `--git-dir`, like `GIT_WORK_TREE`
+    #[clap(long, value_name = "DIR")]
+    work_tree: Option<PathBuf>,
+
+    /// Reuse the last output for this command line while `.git/index`,
+    /// `HEAD`, `MERGE_HEAD`, refs and the config file are unchanged
+    ///
+    /// Outputs are cached under `$XDG_CACHE_HOME/gitpr`. Edits to worktree
+    /// files alone don't touch git metadata, so the prompt shows them only
+    /// after the next `git add`, commit or checkout
+    #[clap(long, overrides_with = "no_cache")]
+    cache: bool,
+
+    /// Don't use the output cache, even if enabled in config
+    #[clap(long, overrides_with = "cache")]
+    no_cache: bool,
+
+    /// Keep running, printing status again on its own line each time it
+    /// changes, ex: for tmux, i3blocks `interval=persist` or a status bar
+    ///
+    /// On Linux, inotify reports changes to worktree dirs git doesn't ignore
+    /// and to git metadata (index, HEAD, refs and the stash), and status is
+    /// rendered once they've settled. Elsewhere git metadata is checked every
+    /// 200 ms, and worktree edits are only seen by the render done every
+    /// `--watch-interval`
+    #[clap(long)]
+    watch: bool,
+
+    /// Seconds between renders in `--watch` mode while nothing is seen to
+    /// change
+    #[clap(long, value_name = "SECONDS", default_value = "5")]
+    watch_interval: u64,
+
+    /// Least milliseconds between renders of a repo in `--watch` and
+    /// `--daemon` modes
+    ///
+    /// Bursts of changes, ex: a build touching thousands of files, are
+    /// coalesced into at most one render per MS. The daemon answers with its
+    /// last render of the repo meanwhile
+    #[clap(long, value_name = "MS", default_value = "500")]
+    min_refresh: u64,
+
+    /// Run in the background, caching status per repo for instant prompts
+    ///
+    /// Listens on `$XDG_RUNTIME_DIR/gitpr.sock`, or else in a `gitpr-<uid>` dir
+    /// of the temp dir, only accessible to the user; on Windows, on named pipe
+    /// `\\.\pipe\gitpr-<user>`. While it runs, gitpr asks the daemon first
+    /// and only renders itself if the daemon doesn't answer. A cached render
+    /// is reused until `.git/index`, `HEAD`, a ref or a config file changes,
+    /// or for at most 2 seconds, since worktree edits alone aren't noticed;
+    /// `gitpr daemon reload` drops them all
+    ///
+    /// Under systemd, a user socket unit with `ListenStream=%t/gitpr.sock`
+    /// starts it at the first prompt, and `--idle-timeout` lets it exit once
+    /// unused
+    #[clap(long = "daemon")]
+    serve_daemon: bool,
+
+    /// Seconds without a request after which `--daemon` exits; not on Windows
+    #[clap(long, value_name = "SECONDS")]
+    idle_timeout: Option<u64>,
+
+    /// Append timing of each run to a local log; see `gitpr timings report`
+    #[clap(long)]
+    timings: bool,
+
+    /// Config file, if not `$XDG_CONFIG_HOME/gitpr/config.toml`
+    ///
+    /// Options given on the command line take precedence over `GITPR_<KEY>`
+    /// environment variables, and those over the config file. For one repo,
+    /// keys in a `.gitpr.toml` at its top dir, then `gitpr.*` git config
+    /// values override it, ex: `git config gitpr.format '%b %a'`. These may only
+    /// change the prompt's look, not `auto_fetch`, `cache`, `timeout`, etc.
+    /// Git config names use `-` for `_`, ex: `gitpr.indicators-only`, and
+    /// `gitpr.style.<element>` for tables
+    #[clap(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Config file profile to use, ex: `ssh` for the `[profile.ssh]` table
+    ///
+    /// A profile can set any config key, overriding the top level; its
+    /// `[style]`, `[glyph]` and `[upstream_alias]` entries add to the
+    /// top-level tables
+    #[clap(long, value_name = "NAME", env = "GITPR_PROFILE")]
+    profile: Option<String>,
+
     /// Directory to check for status, if not current dir
-    #[clap(short, long, value_name = "PATH", env = "PWD", setting = ArgSettings::HideEnvValues)]
-    dir: PathBuf,
+    ///
+    /// May be repeated to check several repos in one run; see `--stdin`
+    #[clap(
+        short,
+        long,
+        value_name = "PATH",
+        default_value = ".",
+        number_of_values = 1
+    )]
+    dir: Vec<PathBuf>,
+
+    /// Also check the dirs listed on stdin, one per line
+    ///
+    /// With this or more than one `--dir`, each repo is checked in turn, with
+    /// the config it would get on its own. Each output line starts with the
+    /// dir as given and a tab, except with `--output json`, which prints one
+    /// object per line with the dir as its `dir` field. Dirs outside a repo
+    /// print nothing. Exits 0 if every repo is clean, 1 if some have changes
+    /// and 2 if some failed or timed out
+    #[clap(long)]
+    stdin: bool,
+
+    /// After checking several dirs, print a line with how many repos were
+    /// clean, dirty and failed; a JSON object with `--output json`
+    #[clap(long)]
+    summary: bool,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
 }
 
-/// Hold status of git repo attributes
-#[derive(Debug, Default)]
-struct Repo {
-    branch:     Option<String>,
-    commit:     Option<String>,
-    tag:        Option<String>,
-    remote:     Option<String>,
-    upstream:   Option<String>,
-    stashed:    u32,
-    ahead:      u32,
-    behind:     u32,
-    untracked:  u32,
-    unmerged:   u32,
-    insertions: u32,
-    deletions:  u32,
-    unstaged:   GitArea,
-    staged:     GitArea,
+/// Subcommands
+#[derive(Clap, Debug)]
+enum Command {
+    /// Run the status pipeline repeatedly and report timings
+    ///
+    /// Uses the same options as a normal run. Writes a flamegraph when built
+    /// with the `pprof` feature
+    Profile {
+        /// Number of runs
+        #[clap(short = "n", long, default_value = "100")]
+        iterations: u32,
+
+        /// Flamegraph output file
+        #[clap(long, value_name = "FILE", default_value = "flamegraph.svg")]
+        flamegraph: PathBuf,
+    },
+    /// Print what each element of the prompt means for the current repo
+    ///
+    /// Each token of the format string is shown as rendered, followed by what
+    /// it says in words and the token's description, ex: "⇡2: 2 ahead (%a,
+    /// commits ahead/behind remote)". Tokens that render empty are left out
+    Explain,
+    /// Print a snippet that sets up the prompt, for `eval` in the shell's
+    /// startup file
+    ///
+    /// zsh: `eval "$(gitpr init zsh)"` in ~/.zshrc; the status is filled in
+    /// asynchronously. bash: `eval "$(gitpr init bash)"` in ~/.bashrc. fish:
+    /// `gitpr init fish | source` in ~/.config/fish/config.fish. PowerShell:
+    /// `Invoke-Expression (& gitpr init powershell | Out-String)` in $PROFILE
+    Init {
+        #[clap(possible_values = &["zsh", "bash", "fish", "powershell"])]
+        shell: Shell,
+    },
+    /// Find repos below a dir and print a table of their status
+    ///
+    /// Each row shows the repo's path, branch, dirty state like `%x`, commits
+    /// ahead of and behind upstream, and stash count. Status options like
+    /// `--untracked` and `--timeout` apply to each repo. Bare repos are listed
+    /// too, and repos aren't searched for nested repos. Exits 0 if every repo is clean, 1 if some have
+    /// changes and 2 if some failed or timed out
+    Scan {
+        /// Dir to search, relative to `--dir`
+        #[clap(value_name = "ROOT", default_value = ".")]
+        root: PathBuf,
+
+        /// Levels of dirs to search below ROOT
+        #[clap(long, value_name = "N", default_value = "3")]
+        max_depth: usize,
+
+        /// Skip dirs matching gitignore-style GLOB, ex: `--exclude
+        /// node_modules/`; may be repeated
+        #[clap(long, value_name = "GLOB", number_of_values = 1)]
+        exclude: Vec<String>,
+
+        /// Print one JSON object per repo instead of a table
+        #[clap(long)]
+        json: bool,
+
+        /// Also show the apparent size of each repo's metadata dir, ex:
+        /// `.git`, and of the rest of its worktree. Sizes are measured in
+        /// parallel and cached for an hour, or until the repo's git metadata
+        /// changes
+        #[clap(long)]
+        du: bool,
+
+        /// End with a line of how many repos were clean, dirty and failed
+        #[clap(long)]
+        summary: bool,
+    },
+    /// Inspect timings recorded with `--timings`
+    Timings {
+        #[clap(subcommand)]
+        action: TimingsAction,
+    },
+    /// Control the daemon started with `--daemon`
+    Daemon {
+        #[clap(subcommand)]
+        action: DaemonAction,
+    },
 }
 
-/// Hold status of specific git area (staged, unstaged)
-#[derive(Debug, Default)]
-struct GitArea {
-    modified: u32,
-    added:    u32,
-    deleted:  u32,
-    renamed:  u32,
-    copied:   u32,
+/// Actions for `timings` subcommand
+#[derive(Clap, Debug)]
+enum TimingsAction {
+    /// Summarize recorded timings per repo, slowest first
+    Report,
 }
 
-impl Repo {
-    const AHEAD_GLYPH: &'static str = "⇡";
-    const BEHIND_GLYPH: &'static str = "⇣";
-    const BRANCH_GLYPH: &'static str = "";
-    const MODIFIED_GLYPH: &'static str = "Δ";
-    const STASH_GLYPH: &'static str = "$";
-    const UNMERGED_GLYPH: &'static str = "‼";
-    const UNTRACKED_GLYPH: &'static str = "…";
-
-    fn git_root_dir(&mut self) -> Result<String> {
-        cmd!("git", "rev-parse", "--absolute-git-dir")
-            .read()
-            .context("cannot get root dir of git repo")
-    }
-
-    /// Get chunk insertions/deletions
-    fn git_diff_numstat(&mut self) -> Result {
-        let output = cmd!("git", "diff", "--numstat").read()?;
-        for line in output.lines() {
-            let mut split = line.split_whitespace();
-            self.insertions += split.next().unwrap_or_default().parse().unwrap_or(0);
-            self.deletions += split.next().unwrap_or_default().parse().unwrap_or(0);
+/// Actions for `daemon` subcommand
+#[derive(Clap, Debug)]
+enum DaemonAction {
+    /// Drop cached renders, so the next prompts show config and theme changes
+    ///
+    /// Changes to the config file, `.gitpr.toml` or git config are noticed
+    /// without this, but theme files aren't
+    Reload,
+}
+
+impl Arg {
+    /// Rendering options for the library
+    fn options(&self) -> Result<Options> {
+        let mut styles = StyleSet::standard();
+        for setting in &self.style {
+            styles.set(setting)?;
         }
-        Ok(())
-    }
-
-    /// Parse git status by line
-    fn parse_status<S: AsRef<str>>(&mut self, gs: S) {
-        for line in gs.as_ref().lines() {
-            let mut words = line.split_whitespace();
-            while let Some(word) = words.next() {
-                match word {
-                    "#" => {
-                        while let Some(br) = words.next() {
-                            match br {
-                                "branch.oid" => self.commit = words.next().map(String::from),
-                                "branch.head" => self.branch = self.parse_head(words.next()),
-                                "branch.upstream" => self.upstream = words.next().map(String::from),
-                                "branch.ab" => {
-                                    self.ahead = words.next().map_or(0, |s| s.parse().unwrap());
-                                    self.behind =
-                                        words.next().map_or(0, |s| s[1..].parse().unwrap());
-                                }
-                                _ => (),
-                            }
-                        }
-                    }
-                    // Tracked file
-                    "1" | "2" => {
-                        let mut code = words.next().unwrap().chars();
-                        self.staged.parse_modified(code.next().unwrap());
-                        self.unstaged.parse_modified(code.next().unwrap());
-                    }
-                    "u" => self.unmerged += 1,
-                    "?" => self.untracked += 1,
-                    _ => (),
-                }
-            }
+        let color_depth = self.color_depth.unwrap_or_else(ColorDepth::detect);
+        if !self.output.is_hex_colored() {
+            styles.fit_depth(color_depth);
+        }
+        // Environment overrides config
+        let mut glyphs = GlyphSet::default();
+        for setting in &self.glyph {
+            glyphs.set(setting)?;
         }
+        glyphs.set_from_env()?;
+        Ok(Options {
+            format: self.format.clone(),
+            indicators_only: self.indicators_only,
+            divergence_age: self.divergence_age,
+            no_trim: self.no_trim,
+            max_width: self.max_width,
+            alert: self.alert,
+            unmerged_detail: self.unmerged_detail,
+            stash_warn_days: self.stash_warn_days,
+            fetch_stale_hours: self.fetch_stale_hours,
+            diff_style: self.diff_style,
+            styles,
+            glyphs,
+            escape: self.escape,
+            upstream: self.upstream.clone(),
+            base_branch: self.base_branch.clone(),
+            expected_email: self.expected_email.clone(),
+            protected_branches: self.protected_branch.clone(),
+            color_depth,
+        })
     }
+}
 
-    /// Parse git status output, seeking tag if needed
-    fn parse_head(&self, head: Option<&str>) -> Option<String> {
-        match head {
-            Some(br) => match br {
-                "(detached)" => Some(git_tag().unwrap_or_else(|_| String::from("unknown"))),
-                _ => Some(br.to_string()),
-            },
-            None => None,
-        }
+/// What a run found out about the repo besides its output
+#[derive(Debug, Clone, Copy)]
+pub struct RunStatus {
+    /// False if git commands timed out, so the output is partial
+    pub complete: bool,
+    /// Changes, untracked files or conflicts were found, as far as the run
+    /// looked for them
+    pub dirty:    bool,
+}
+
+/// Gather repo status and write output for the selected mode
+fn run<W: Write>(args: &Arg, buf: &mut W, timer: &mut Timer) -> Result<RunStatus> {
+    if args.simple_mode {
+        let status = git!("status", "--porcelain", "--branch", "--untracked-files=no",).read()?;
+        timer.stage("status");
+        let mut out = Vec::with_capacity(255);
+        let dirty = simple_output(status, &mut out)?;
+        buf.write_all(escape_prompt(&String::from_utf8(out)?, args.escape).as_bytes())?;
+        timer.stage("render");
+        return Ok(RunStatus {
+            complete: true,
+            dirty,
+        });
+    }
+    if args.fs_only {
+        let mut out = Vec::with_capacity(255);
+        let dirty = fs_only_output(&mut out)?;
+        buf.write_all(escape_prompt(&String::from_utf8(out)?, args.escape).as_bytes())?;
+        timer.stage("render");
+        return Ok(RunStatus {
+            complete: true,
+            dirty,
+        });
     }
+    let nodes =
+        format::parse(&args.format).map_err(|e| format_err!("{}\n{}", e, FORMAT_STRING_USAGE))?;
+    let mut tokens = format::tokens(&nodes);
+    let mut show_untracked = tokens.iter().any(|&c| c == 'u' || c == 'x');
 
-    /// Write formatted branch to buffer
-    fn fmt_branch<W: Write>(&self, buf: &mut W, style: &Style) -> Result {
-        if let Some(s) = &self.branch {
-            write!(buf, "{}", style.paint(s))?;
+    let list = match args.output {
+        OutputMode::Prompt | OutputMode::PlainVerbose => {
+            args.list.map(|area| area.unwrap_or(ListArea::All))
         }
-        Ok(())
+        _ => None,
+    };
+    if let Some(ListArea::Untracked) | Some(ListArea::All) = list {
+        show_untracked = true;
     }
-
-    /// Write branch glyph to buffer
-    fn fmt_branch_glyph<W: Write>(&self, buf: &mut W, style: &Style) -> Result {
-        write!(buf, "{}", style.paint(Repo::BRANCH_GLYPH))?;
-        Ok(())
+    if let OutputMode::Json | OutputMode::Kv | OutputMode::FishVars | OutputMode::Porcelain =
+        args.output
+    {
+        show_untracked = true;
     }
 
-    /// Write formatted commit to buffer
-    fn fmt_commit<W: Write>(&self, buf: &mut W, style: &Style, len: usize) -> Result {
-        if let Some(commit) = &self.commit {
-            let display = if commit == "(initial)" {
-                "(initial)"
-            } else {
-                commit[..len].into()
-            };
-            write!(buf, "{}", style.paint(display))?;
-        }
-        Ok(())
-    }
-
-    /// Write formatted ahead/behind details to buffer
-    fn fmt_ahead_behind<W: Write>(
-        &self,
-        buf: &mut W,
-        style: &Style,
-        indicators_only: bool,
-    ) -> Result {
-        if self.ahead + self.behind == 0 {
-            return Ok(());
-        }
-        style.write_to(buf)?;
-        if self.ahead != 0 {
-            buf.write_all(Repo::AHEAD_GLYPH.as_bytes())?;
-            if !indicators_only {
-                write!(buf, "{}", self.ahead)?;
-            }
-        }
-        if self.behind != 0 {
-            buf.write_all(Repo::BEHIND_GLYPH.as_bytes())?;
-            if !indicators_only {
-                write!(buf, "{}", self.behind)?;
-            }
-        }
-        Style::reset().write_to(buf)?;
-        Ok(())
-    }
-
-    /// Write formatted +n/-n git diff numstat details to buffer
-    fn fmt_diff_numstat<W: Write>(
-        &mut self,
-        buf: &mut W,
-        style: &Style,
-        indicators_only: bool,
-    ) -> Result {
-        if !self.unstaged.has_changed() || indicators_only {
-            return Ok(());
-        }
-        if self.insertions == 0 && self.deletions == 0 {
-            self.git_diff_numstat()?;
-        }
-        style.write_to(buf)?;
-        if self.insertions > 0 {
-            write!(buf, "+{}", self.insertions)?;
-            if self.deletions > 0 {
-                write!(buf, "/")?;
-            }
-        }
-        if self.deletions > 0 {
-            write!(buf, "-{}", self.deletions)?;
-        }
-        Style::reset().write_to(buf)?;
-        Ok(())
-    }
-
-    /// Write formatted stash details to buffer
-    fn fmt_stash<W: Write>(&mut self, buf: &mut W, style: &Style, indicators_only: bool) -> Result {
-        let mut git = self.git_root_dir()?;
-        git.push_str("/logs/refs/stash");
-        let st = std::fs::read_to_string(git)
-            .unwrap_or_default()
-            .lines()
-            .count();
-        if st > 0 {
-            self.stashed = u32::try_from(st)?;
-            style.write_to(buf)?;
-            buf.write_all(Repo::STASH_GLYPH.as_bytes())?;
-            if !indicators_only {
-                write!(buf, "{}", self.stashed)?;
-            }
-            Style::reset().write_to(buf)?;
-        }
-        Ok(())
-    }
-
-    /// Write formatted untracked indicator and/or count to buffer
-    fn fmt_untracked<W: Write>(
-        &mut self,
-        buf: &mut W,
-        style: &Style,
-        indicators_only: bool,
-    ) -> Result {
-        if self.untracked > 0 {
-            style.write_to(buf)?;
-            buf.write_all(Repo::UNTRACKED_GLYPH.as_bytes())?;
-            if !indicators_only {
-                write!(buf, "{}", self.untracked)?;
-            }
-            Style::reset().write_to(buf)?;
-        }
-        Ok(())
-    }
-
-    /// Write formatted unmerged files indicator and/or count to buffer
-    fn fmt_unmerged<W: Write>(
-        &mut self,
-        buf: &mut W,
-        style: &Style,
-        indicators_only: bool,
-    ) -> Result {
-        if self.unmerged > 0 {
-            style.write_to(buf)?;
-            buf.write_all(Repo::UNMERGED_GLYPH.as_bytes())?;
-            if !indicators_only {
-                write!(buf, "{}", self.unmerged)?;
-            }
-            Style::reset().write_to(buf)?;
-        }
-        Ok(())
+    // TODO: possibly use rev-parse first
+    let deadline = args
+        .timeout
+        .map(|ms| Instant::now() + Duration::from_millis(ms));
+    let untracked = if show_untracked {
+        args.untracked.resolve(&env::current_dir()?)
+    } else {
+        Untracked::No
+    };
+    // Diff stats would be cleared anyway, or aren't shown
+    if args.no_dirty_state || args.diff_style == DiffStyle::Files {
+        tokens.retain(|&c| c != 'd' && c != 'I');
+    }
+    let mut ri = Repo::gather(&GatherOptions {
+        backend: args.backend,
+        untracked,
+        renames: args.renames,
+        nul: !args.line_status,
+        fast_dirty: args.fast_dirty,
+        tokens: &tokens,
+        deadline,
+    })?;
+    ri.exclude_untracked(&args.untracked_exclude);
+    // Before `--no-dirty-state` hides changes from the output
+    let dirty = ri.is_dirty() || ri.unmerged > 0;
+    if args.no_dirty_state {
+        ri.clear_dirty_state();
     }
+    if let Some(minutes) = args.auto_fetch {
+        let r = ri.git_auto_fetch(Duration::from_secs(minutes.unwrap_or(15) * 60));
+        ri.allow_timeout(r)?;
+    }
+    timer.stage("status");
 
-    /// Write formatted upstream to buffer
-    fn fmt_upstream<W: Write>(&self, buf: &mut W, style: &Style) -> Result {
-        if let Some(r) = &self.upstream {
-            write!(buf, "{}", style.paint(r))?;
+    debug!("{:#?}", &ri);
+    info!("{:#?}", &args);
+
+    let options = args.options()?;
+    // JSON, kv, fish-vars and porcelain output read every field themselves
+    if !matches!(
+        args.output,
+        OutputMode::Json | OutputMode::Kv | OutputMode::FishVars | OutputMode::Porcelain
+    ) {
+        ri.collect(&tokens, &options)?;
+        ri.hide_below(&args.hide_below)?;
+        timer.stage("collect");
+    }
+    let mut files = vec![];
+    if let Some(area) = list {
+        list_files(&ri, area, &options.styles, &mut files)?;
+    }
+    let complete = !ri.timed_out;
+    write_output(ri, args.output, &options, buf)?;
+    if !files.is_empty() {
+        if args.output == OutputMode::Prompt && !args.format.is_empty() {
+            writeln!(buf)?;
         }
-        Ok(())
+        buf.write_all(&files)?;
     }
+    timer.stage("render");
+    Ok(RunStatus { complete, dirty })
 }
 
-impl GitArea {
-    /// Parse git status to determine what has been modified
-    fn parse_modified(&mut self, ln: char) {
-        match ln {
-            'M' => self.modified += 1,
-            'T' => self.modified += 1,
-            'A' => self.added += 1,
-            'D' => self.deleted += 1,
-            'R' => self.renamed += 1,
-            'C' => self.copied += 1,
-            _ => (),
+/// Apply config files and set up environment for a parsed command line. `tty`
+/// is true if output goes to a terminal, for `--color auto`
+fn configure(args: &mut Arg, matches: &ArgMatches, tty: bool) -> Result {
+    let mut cfg = Config::load(args.config.as_deref())?;
+    if let Some(name) = &args.profile {
+        match &mut cfg {
+            Some(cfg) => cfg.select_profile(name)?,
+            None => return Err(format_err!("--profile given, but there is no config file")),
         }
     }
-
-    fn fmt_modified<W: Write>(&self, buf: &mut W, style: &Style, indicators_only: bool) -> Result {
-        if !self.has_changed() {
-            return Ok(());
-        }
-        style.write_to(buf)?;
-        buf.write(Repo::MODIFIED_GLYPH.as_bytes())?;
-        if !indicators_only {
-            write!(buf, "{}", self.change_ct())?;
-        }
-        Style::reset().write_to(buf)?;
-        Ok(())
+    // Relative to where gitpr was started, not `--dir`
+    let cwd = env::current_dir()?;
+    if let Some(dir) = &args.git_dir {
+        env::set_var("GIT_DIR", cwd.join(dir));
     }
-
-    fn has_changed(&self) -> bool {
-        self.added + self.deleted + self.modified + self.copied + self.renamed != 0
+    if let Some(dir) = &args.work_tree {
+        env::set_var("GIT_WORK_TREE", cwd.join(dir));
     }
-
-    fn change_ct(&self) -> u32 {
-        self.added + self.deleted + self.modified + self.copied + self.renamed
+    env::set_current_dir(&args.dir[0])?;
+    // Settings of the repo override the user's. Look for them within the
+    // user's ceiling dirs, since the repo's can't be known yet
+    let mut ceiling = args.ceiling.clone();
+    ceiling.extend(cfg.iter().flat_map(Config::ceiling));
+    set_ceiling_dirs(&ceiling);
+    let dir = env::current_dir()?;
+    // `--fs-only` promises not to run git
+    let git_config = if args.fs_only {
+        GitConfig::default()
+    } else {
+        GitConfig::read(&dir, &["gitpr", "bash", "status"])
+    };
+    // Git's own prompt settings come first, so any of gitpr's override them
+    let mut layered = Config::from_git_prompt_settings(&git_config);
+    if let Some(cfg) = &cfg {
+        layered.overlay(cfg);
     }
-}
-
-/// Query for git tag, use in simple or regular options
-fn git_tag() -> Result<String> {
-    cmd!("git", "describe", "--tags", "--exact-match")
-        .read()
-        .context("invalid git tags")
-}
-
-/// Simple output to mimic default git prompt
-fn simple_output<S, W>(git_status: S, buf: &mut W) -> Result
-where
-    S: AsRef<str>,
-    W: Write,
-{
-    let mut raw_branch = "";
-    let mut dirty = false;
-    for line in git_status.as_ref().lines() {
-        if line.starts_with("##") {
-            raw_branch = &line[3..];
-        } else {
-            dirty = true;
-            break;
-        }
+    if let Some(repo) = Config::load_repo(&dir, &git_config)? {
+        layered.overlay(&repo);
+    }
+    layered.overlay(&Config::from_env()?);
+    debug!("{:#?}", layered);
+    layered.apply(args, matches)?;
+    // Theme styles come first so `[style]` and `--style` override them
+    if let Some(theme) = &args.theme {
+        let mut styles = config::theme_styles(theme)?;
+        styles.append(&mut args.style);
+        args.style = styles;
     }
-    let split = raw_branch.split("...").collect::<Vec<&str>>();
-    let branch = match split.get(0) {
-        Some(b) if b.starts_with("HEAD") => git_tag().unwrap_or_else(|_| "unknown".to_string()),
-        Some(b) => b.to_string(),
-        None => "unknown".to_string(),
+    if let Some(Command::Explain) = args.command {
+        args.output = OutputMode::Explain;
+    }
+    let choice = if args.no_color {
+        ColorChoice::Never
+    } else {
+        args.color
     };
-    debug!(
-        "Raw: {}; Split: {:?}; Branch: {}",
-        raw_branch, split, branch
-    );
-    let styles = StyleSet::simple();
-    styles.branch.write_to(buf)?;
-    write!(buf, "({})", branch)?;
-    if dirty {
-        styles.dirty.write_to(buf)?;
-        write!(buf, "*")?;
-    }
-    Style::reset().write_to(buf)?;
+    let no_color = env::var("NO_COLOR").ok();
+    let force = env::var("CLICOLOR_FORCE").ok();
+    let captured = args.escape != Escape::None
+        || matches!(args.output, OutputMode::Tmux | OutputMode::Powerline);
+    let color = args.output.is_colored()
+        && choice.resolve(no_color.as_deref(), force.as_deref(), tty || captured);
+    // Older Windows consoles show escape sequences as text
+    #[cfg(windows)]
+    let color = color && ansi_term::enable_ansi_support().is_ok();
+    args.colored = color;
+    // Styles leave out their escape sequences while `NO_COLOR` is set, so it
+    // carries the decision from here on
+    if color {
+        env::remove_var("NO_COLOR");
+    } else {
+        env::set_var("NO_COLOR", "1");
+    }
+    set_git_locale(&args.git_locale);
+    set_ceiling_dirs(&args.ceiling);
+    set_quiet_errors(args.quiet_errors);
     Ok(())
 }
 
-/// Return true if we're inside the hidden .git/ directory in a repo.
-fn inside_dotgit_dir(wd: &Path) -> bool {
-    for path_component in wd {
-        if path_component == ".git" {
-            return true;
+/// Entry point
+fn main() {
+    // Usage errors exit with `EXIT_ERROR`, not clap's 2, which means outside a
+    // repo here
+    let matches = match Arg::into_app().try_get_matches() {
+        Ok(m) => m,
+        Err(e) if e.use_stderr() => {
+            eprintln!("{}", e);
+            std::process::exit(EXIT_ERROR);
         }
+        Err(e) => e.exit(),
+    };
+    let mut args = Arg::from_arg_matches(&matches);
+
+    if !args.quiet {
+        logger::init_logger(args.verbose);
     }
-    false
+    let stdout = std::io::stdout();
+    let mut lock = stdout.lock();
+    let code = match args.async_token.clone() {
+        Some(token) => {
+            if let Err(e) = check_async_token(&token) {
+                report_error(&args, &e);
+                std::process::exit(EXIT_ERROR);
+            }
+            let mut out = vec![];
+            let result = try_main(&mut args, &matches, &mut out);
+            let code = exit_code(&args, result);
+            if let Err(e) = write_async_frame(&token, code, &out, &mut lock) {
+                report_error(&args, &e);
+            }
+            code
+        }
+        None => {
+            let result = try_main(&mut args, &matches, &mut lock);
+            exit_code(&args, result)
+        }
+    };
+    let _ = lock.flush();
+    std::process::exit(code);
 }
 
-/// Return the absolute path to the .git/HEAD file, which contains the name of
-/// the current branch. If the current working directory isn't in a git repo, it
-/// will return None.
-fn find_head(dir: &Path) -> Option<PathBuf> {
-    // Iterate through all the parent directories and see if $DIR/.git/HEAD is
-    // a file that exists.
-    //   /home/me/projects/foo/src/bar/.git/HEAD ??? -> doesn't exist
-    //   /home/me/projects/foo/src/.git/HEAD ???     -> doesn't exist
-    //   /home/me/projects/foo/.git/HEAD ???         -> found it!
-    for d in dir.ancestors() {
-        let p = d.join(".git/HEAD");
-        if p.is_file() {
-            return Some(p);
+/// Exit status for the result of `try_main`, reporting any error
+fn exit_code(args: &Arg, result: Result<i32>) -> i32 {
+    match result {
+        Ok(code) => code,
+        Err(e) => {
+            report_error(args, &e);
+            EXIT_ERROR
         }
     }
-    None
 }
 
-/// Get absolute dir of .git; should be equivalent to `git rev-parse --absolute-git-dir`
-fn find_git_dir(dir: &Path) -> Option<PathBuf> {
-    find_head(dir).and_then(|f| f.parent().map(|f| f.to_path_buf()))
-}
-
-/// Return the name of the current branch. If we're in a directory that isn't
-/// inside a git repo, return `None`.
-fn current_branch(wd: &Path) -> Option<String> {
-    if inside_dotgit_dir(wd) {
-        // Print ".git" instead of the branch name.
-        return Some(".git".to_owned());
-    }
-    // Find the path to the .git/HEAD file.
-    let path_to_head = find_head(wd)?;
-    // Read .git/HEAD and extract the branch name.
-    std::fs::read_to_string(path_to_head)
-        .map(|s| s.trim().trim_start_matches("ref: refs/heads/").to_owned())
-        .ok()
-}
-
-/// Simple output using different means
-fn simple_output2(buf: &mut impl Write) -> Result {
-    let _ = buf;
-    let cwd = env::current_dir()?;
-    let dirty = cmd!("git", "status", "--short")
-        .stdout_capture()
-        .run()
-        .map(|out| out.stdout.len() != 0)
-        .unwrap_or(false);
-    if dirty {
-        debug!("Repo is dirty!");
-    }
-    if let Some(branch) = current_branch(&cwd) {
-        debug!("Current branch: {}", branch);
-    }
-    debug!("Absolute git dir: {:?}", find_git_dir(&cwd));
-    Ok(())
+/// Print error to stderr unless `--quiet-errors`
+fn report_error(args: &Arg, e: &anyhow::Error) {
+    if !args.quiet_errors {
+        eprintln!("Error: {:?}", e);
+    }
 }
 
-/// Print output based on parsing of --format string
-fn print_output<W: Write>(mut ri: Repo, args: &Arg, buf: &mut W) -> Result {
-    let mut fmt_str = args.format.chars();
-    let styles = StyleSet::standard();
-    while let Some(c) = fmt_str.next() {
-        if c == '%' {
-            if let Some(c) = fmt_str.next() {
-                match c {
-                    'a' => ri.fmt_ahead_behind(buf, &styles.ahead_behind, args.indicators_only)?,
-                    'b' => ri.fmt_branch(buf, &styles.branch)?,
-                    'c' => ri.fmt_commit(buf, &styles.commit, 7)?,
-                    'd' => ri.fmt_diff_numstat(buf, &styles.diff, args.indicators_only)?,
-                    'g' => ri.fmt_branch_glyph(buf, &styles.branch_glyph)?,
-                    'm' => ri.unstaged.fmt_modified(
-                        buf,
-                        &styles.modified_unstaged,
-                        args.indicators_only,
-                    )?,
-                    'n' => write!(buf, "{}git", styles.plain)?,
-                    'r' => ri.fmt_upstream(buf, &styles.upstream)?,
-                    's' => ri.staged.fmt_modified(
-                        buf,
-                        &styles.modified_staged,
-                        args.indicators_only,
-                    )?,
-                    't' => ri.fmt_stash(buf, &styles.stash, args.indicators_only)?,
-                    'u' => ri.fmt_untracked(buf, &styles.untracked, args.indicators_only)?,
-                    'U' => ri.fmt_unmerged(buf, &styles.unmerged, args.indicators_only)?,
-                    '%' => write!(buf, "{}%", styles.plain)?,
-                    _ => unreachable!(
-                        "invalid format token allowed to reach print_output: \"%{}\"",
-                        c
-                    ),
-                }
-            }
-        } else {
-            if c != ' ' {
-                // Only write plain style if there's something there
-                styles.plain.write_to(buf)?;
+/// Run command line, writing output to `buf` and returning exit status
+fn try_main<W: Write>(args: &mut Arg, matches: &ArgMatches, buf: &mut W) -> Result<i32> {
+    let batch = args.command.is_none() && (args.stdin || args.dir.len() > 1);
+    #[cfg(any(unix, windows))]
+    {
+        if args.serve_daemon {
+            let idle = args.idle_timeout.map(Duration::from_secs);
+            let min_refresh = Duration::from_millis(args.min_refresh);
+            daemon::serve(&transport::socket_path(), idle, min_refresh)?;
+            return Ok(0);
+        }
+        if args.command.is_none() && !batch && !args.watch {
+            let path = transport::socket_path();
+            if let Some((out, complete)) = daemon::query(&path, &args.dir[0]) {
+                buf.write_all(out.as_bytes())?;
+                return Ok(if complete { 0 } else { EXIT_TIMED_OUT });
             }
-            write!(buf, "{}", c)?;
         }
     }
-    Ok(())
-}
-
-/// Entry point
-fn main() -> Result {
-    let args = Arg::parse();
-    let mut opts: Opt = Default::default();
-
-    if !args.quiet {
-        logger::init_logger(args.verbose);
+    #[cfg(not(any(unix, windows)))]
+    {
+        if args.serve_daemon {
+            return Err(format_err!(
+                "--daemon needs Unix domain sockets or named pipes"
+            ));
+        }
     }
-    if args.no_color {
-        env::set_var("NO_COLOR", "1");
+    // Works without a repo or a valid config
+    if let Some(Command::Init { shell }) = args.command {
+        init::print(shell, buf)?;
+        return Ok(0);
     }
-    env::set_current_dir(&args.dir)?;
-
-    if args.simple_mode {
-        let status = cmd!(
-            "git",
-            "status",
-            "--porcelain",
-            "--branch",
-            "--untracked-files=no",
-        )
-        .read()?;
-        let mut buf = Vec::with_capacity(255);
-        simple_output(status, &mut buf)?;
-        let stdout = std::io::stdout();
-        let mut lock = stdout.lock();
-        lock.write_all(&buf)?;
-        return Ok(());
-    }
-    if args.simple_mode2 {
-        let mut buf = Vec::with_capacity(255);
-        simple_output2(&mut buf)?;
-        return Ok(());
-    }
-    // TODO: use env vars for format str and glyphs
-    let mut fmt_str = args.format.chars();
-    while let Some(c) = fmt_str.next() {
-        if c == '%' {
-            if let Some(c) = fmt_str.next() {
-                match c {
-                    'a' => opts.show_ahead_behind = true,
-                    'b' => opts.show_branch = true,
-                    'c' => opts.show_commit = true,
-                    'd' => opts.show_diff = true,
-                    'g' => opts.show_branch_glyph = true,
-                    'm' => opts.show_unstaged_modified = true,
-                    'n' => opts.show_vcs = true,
-                    'r' => opts.show_upstream = true,
-                    's' => opts.show_staged_modified = true,
-                    't' => opts.show_stashed = true,
-                    'u' => opts.show_untracked = true,
-                    'U' => opts.show_unmerged = true,
-                    '%' => continue,
-                    _ => {
-                        return Err(format_err!(
-                            "invalid format string token \"%{}\"\n{}",
-                            c,
-                            FORMAT_STRING_USAGE
-                        ));
-                    }
+    if let Some(Command::Daemon {
+        action: DaemonAction::Reload,
+    }) = args.command
+    {
+        #[cfg(any(unix, windows))]
+        return daemon::reload(&transport::socket_path()).map(|_| 0);
+        #[cfg(not(any(unix, windows)))]
+        return Err(format_err!(
+            "--daemon needs Unix domain sockets or named pipes"
+        ));
+    }
+    if batch {
+        let mut dirs = args.dir.clone();
+        if args.stdin {
+            // Only dirs given on the command line, not the default
+            if matches.occurrences_of("dir") == 0 {
+                dirs.clear();
+            }
+            for line in std::io::stdin().lock().lines() {
+                let line = line?;
+                if !line.trim().is_empty() {
+                    dirs.push(PathBuf::from(line));
                 }
             }
         }
+        return batch_status(&dirs, matches, buf);
     }
+    configure(args, matches, std::io::stdout().is_terminal())?;
 
-    // TODO: possibly use rev-parse first
-    let mut ri = Repo::default();
-    let git_status = cmd!(
-        "git",
-        "status",
-        "--porcelain=2",
-        "--branch",
-        if opts.show_untracked {
-            "--untracked-files=normal"
-        } else {
-            "--untracked-files=no"
-        },
-    );
-    debug!("{:?}", git_status);
-    ri.parse_status(git_status.read()?.as_str());
+    match &args.command {
+        Some(Command::Profile {
+            iterations,
+            flamegraph,
+        }) => profile::profile(args, *iterations, flamegraph, buf).map(|_| 0),
+        Some(Command::Timings {
+            action: TimingsAction::Report,
+        }) => timings::report(buf).map(|_| 0),
+        Some(Command::Init { .. }) | Some(Command::Daemon { .. }) => Ok(0),
+        Some(Command::Scan {
+            root,
+            max_depth,
+            exclude,
+            json,
+            du,
+            summary,
+        }) => {
+            let totals = scan::scan(args, root, *max_depth, exclude, *json, *du, buf)?;
+            if *summary {
+                totals.write(*json, buf)?;
+            }
+            Ok(summary_exit_code(&totals))
+        }
+        Some(Command::Explain) => {
+            if !in_repo(&env::current_dir()?) {
+                return Ok(EXIT_NOT_A_REPO);
+            }
+            let mut out = vec![];
+            let complete = run(args, &mut out, &mut Timer::new())?.complete;
+            buf.write_all(&out)?;
+            Ok(if complete { 0 } else { EXIT_TIMED_OUT })
+        }
+        None if args.watch => {
+            if !in_repo(&env::current_dir()?) {
+                return Ok(EXIT_NOT_A_REPO);
+            }
+            let interval = Duration::from_secs(args.watch_interval);
+            let min_refresh = Duration::from_millis(args.min_refresh);
+            watch::watch(args, interval, min_refresh, buf).map(|_| 0)
+        }
+        None => status(args, buf).map(|(code, _)| code),
+    }
+}
 
-    debug!("{:#?}", &ri);
-    info!("{:#?}", &args);
+/// Check status of each of `dirs` in turn, each with its arguments parsed
+/// from `matches` afresh and its own config, as if gitpr was run there.
+/// Returns the exit status for all of them
+fn batch_status<W: Write>(dirs: &[PathBuf], matches: &ArgMatches, buf: &mut W) -> Result<i32> {
+    let start = env::current_dir()?;
+    let tty = std::io::stdout().is_terminal();
+    let mut totals = BatchSummary::default();
+    let mut json = false;
+    for dir in dirs {
+        // `--dir` and `--git-dir` are relative to where gitpr was started
+        env::set_current_dir(&start)?;
+        let mut args = Arg::from_arg_matches(matches);
+        args.dir = vec![start.join(dir)];
+        let mut out = vec![];
+        let mut result = configure(&mut args, matches, tty).and_then(|_| status(&args, &mut out));
+        json = args.output == OutputMode::Json;
+        // Output that can't be reported fails this repo, not the whole batch
+        match write_batch_entry(
+            &dir.to_string_lossy(),
+            &String::from_utf8_lossy(&out),
+            args.output,
+            buf,
+        ) {
+            Err(e) if e.is::<serde_json::Error>() => {
+                result = result.and(Err(e.context("cannot parse JSON output")));
+            }
+            written => written?,
+        }
+        match result {
+            Ok((EXIT_NOT_A_REPO, _)) => (),
+            Ok((0, true)) => totals.dirty += 1,
+            Ok((0, false)) => totals.clean += 1,
+            Ok(_) => totals.errors += 1,
+            Err(e) => {
+                report_error(&args, &e);
+                totals.errors += 1;
+            }
+        }
+    }
+    if Arg::from_arg_matches(matches).summary {
+        totals.write(json, buf)?;
+    }
+    Ok(summary_exit_code(&totals))
+}
 
-    let mut buf = vec![];
-    print_output(ri, &args, &mut buf)?;
-    let out = if args.no_trim {
-        String::from_utf8(buf)?
+/// Exit status of a run over several repos: whether some failed, else whether
+/// some are dirty
+fn summary_exit_code(totals: &BatchSummary) -> i32 {
+    if totals.errors > 0 {
+        EXIT_SOME_FAILED
+    } else if totals.dirty > 0 {
+        EXIT_SOME_DIRTY
     } else {
-        String::from_utf8(buf)?
-            .to_string()
-            .split_whitespace()
-            .collect::<Vec<_>>()
-            .join(" ")
-    };
-    debug!("{:?}", out);
-    let test = out.split_whitespace().collect::<Vec<_>>();
-    debug!("{:?}", test);
-    print!("{}", out);
-    Ok(())
+        0
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use pretty_assertions::assert_eq;
-
-    #[test]
-    fn simple_clean() -> Result {
-        const CLEAN: &str = "## master...origin/master";
-        let expected = "\u{1b}[38;5;14m(master)\u{1b}[0m";
-
-        let mut buf = Vec::new();
-        simple_output(CLEAN, &mut buf)?;
-        let result = str::from_utf8(&buf)?;
-        assert_eq!(result, expected);
-        Ok(())
-    }
-
-    #[test]
-    fn simple_dirty() -> Result {
-        const DIRTY: &str = "## master...origin/master
-  M src/main.rs
- ?? src/tests.rs";
-        let expected = "\u{1b}[38;5;14m(master)\u{1b}[31m*\u{1b}[0m";
-
-        let mut buf = Vec::new();
-        simple_output(DIRTY, &mut buf)?;
-        let result = str::from_utf8(&buf)?;
-        assert_eq!(result, expected);
-        Ok(())
-    }
-
-    #[test]
-    fn absolute_git_dir() -> Result {
-        let fs_dir =
-            find_git_dir(&env::current_dir()?).ok_or_else(|| format_err!("cannot find git dir"))?;
-        let git_dir = cmd!("git", "rev-parse", "--absolute-git-dir").read()?;
-        assert_eq!(git_dir, fs_dir.to_string_lossy());
-        Ok(())
+/// Check status of the repo in the current dir, from the cache if enabled and
+/// fresh, writing output to `buf`. Returns exit status and whether the repo is
+/// dirty; see `RunStatus`
+fn status<W: Write>(args: &Arg, buf: &mut W) -> Result<(i32, bool)> {
+    if !in_repo(&env::current_dir()?) {
+        return Ok((EXIT_NOT_A_REPO, false));
+    }
+    let mut code = 0;
+    let dirty;
+    let mut timer = Timer::new();
+    let cache = if args.cache && !args.no_cache {
+        Cache::open(args)
+    } else {
+        None
+    };
+    match cache.as_ref().and_then(Cache::read) {
+        Some((out, age, was_dirty)) => {
+            dirty = was_dirty;
+            let out = match args.output {
+                OutputMode::Json => mark_cached_json(&out, age)?,
+                _ => out,
+            };
+            buf.write_all(out.as_bytes())?;
+            timer.stage("cache");
+        }
+        None => {
+            let mut out = vec![];
+            let ran = match run(args, &mut out, &mut timer) {
+                Ok(ran) => ran,
+                Err(e) => {
+                    report_error(args, &e);
+                    return Ok((EXIT_VCS_ERROR, false));
+                }
+            };
+            dirty = ran.dirty;
+            if !ran.complete {
+                code = EXIT_TIMED_OUT;
+            }
+            buf.write_all(&out)?;
+            if let (Some(cache), true) = (&cache, ran.complete) {
+                if let Err(e) = cache.write(&String::from_utf8_lossy(&out), dirty) {
+                    debug!("cannot write cache: {:?}", e);
+                }
+            }
+        }
+    }
+    if args.timings {
+        if let Err(e) = timer.record() {
+            debug!("cannot record timings: {:?}", e);
+        }
     }
+    Ok((code, dirty))
 }
@@ -0,0 +1,2157 @@
+//! Render repo status as prompt string or status bar output
+use crate::{
+    encoder::{self, OutputEncoder, StyledBuf},
+    format::{self, Node, Width},
+    github::Checks,
+    glyph::GlyphSet,
+    repo::{
+        current_branch, find_bare_dir, find_git_dir, find_work_tree, fmt_age, git_tag, stat_dirty,
+        TimedOut, UpstreamRules,
+    },
+    style::{self, ColorDepth, StyleSet},
+    GitArea, Repo, Result, FORMAT_STRING_USAGE,
+};
+use anyhow::format_err;
+use log::debug;
+use std::{env, io::Write, str::FromStr, time::Duration};
+use unicode_width::UnicodeWidthStr;
+use writecolor::Style;
+
+/// Options controlling how a repo is rendered
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Format string; see `FORMAT_STRING_USAGE` for tokens
+    pub format:             String,
+    /// Show indicators instead of numeric values
+    pub indicators_only:    bool,
+    /// Show age of the oldest upstream commit not yet merged
+    pub divergence_age:     bool,
+    /// Skip collapsing whitespace in rendered output
+    pub no_trim:            bool,
+    /// Columns the rendered format string must fit in, dropping elements in
+    /// `DROP_ORDER` and then truncating the branch
+    pub max_width:          Option<usize>,
+    /// Paint whole prompt with alert style when repo needs attention
+    pub alert:              bool,
+    /// Break `%U` count down by kind of conflict
+    pub unmerged_detail:    bool,
+    /// Days after which newest stash is painted with `stash_old` style
+    pub stash_warn_days:    u64,
+    /// Hours after which `%f` shows age of last fetch
+    pub fetch_stale_hours:  u64,
+    /// What `%d` and `%I` show
+    pub diff_style:         DiffStyle,
+    /// Element styles, `StyleSet::standard()` with user overrides applied,
+    /// fitted to `color_depth`
+    pub styles:             StyleSet,
+    /// Colors the terminal can show
+    pub color_depth:        ColorDepth,
+    /// Element glyphs, defaults overridden by config and `GITPR_GLYPH_*`
+    pub glyphs:             GlyphSet,
+    /// Shell prompt escaping for ANSI sequences
+    pub escape:             Escape,
+    /// Shortening rules for `%r`
+    pub upstream:           UpstreamRules,
+    /// Branch `%A` counts commits against; the default branch if `None`
+    pub base_branch:        Option<String>,
+    /// `user.email` that `%i` is expected to show; others are painted with
+    /// the `identity_mismatch` style
+    pub expected_email:     Option<String>,
+    /// Branch globs painted with the `branch_protected` style
+    pub protected_branches: Vec<String>,
+}
+
+/// Output modes selectable with `--output`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputMode {
+    /// Colored prompt string (default)
+    Prompt,
+    /// JSON object for a waybar custom module
+    Waybar,
+    /// Text with polybar `%{F#rrggbb}` color tags
+    Polybar,
+    /// Pango markup with `<span foreground="#rrggbb">` per element
+    Pango,
+    /// i3blocks protocol: full text, short text and color lines
+    I3blocks,
+    /// Words instead of glyphs and no color, for screen readers
+    PlainVerbose,
+    /// JSON object with all gathered status fields, for scripts
+    Json,
+    /// tmux status line with `#[fg=colourN]` style tags
+    Tmux,
+    /// Vim `statusline` with `%#GitprElement#` highlight groups
+    Vim,
+    /// Prompt drawn as powerline blocks, colored by element
+    Powerline,
+    /// JSON array of rendered text segments with their element names
+    JsonSegments,
+    /// Uncolored `key=value` lines with all gathered status fields, for
+    /// prompt engines that apply their own styling
+    Kv,
+    /// fish `set -g gitpr_<field>` statements with all gathered status fields
+    FishVars,
+    /// `key value` lines in a fixed, versioned schema, for scripts
+    Porcelain,
+    /// Each token as rendered and described in words, one per line; only
+    /// selected by the `explain` subcommand
+    Explain,
+}
+
+impl OutputMode {
+    /// True if output is painted from element styles; other modes run with
+    /// color disabled
+    pub fn is_colored(self) -> bool {
+        matches!(
+            self,
+            OutputMode::Prompt | OutputMode::Tmux | OutputMode::Powerline | OutputMode::Explain
+        )
+    }
+
+    /// True if element styles are given to a status bar as hex colors, so
+    /// aren't limited to what the terminal can show
+    pub fn is_hex_colored(self) -> bool {
+        matches!(
+            self,
+            OutputMode::Waybar | OutputMode::Polybar | OutputMode::Pango | OutputMode::I3blocks
+        )
+    }
+}
+
+impl FromStr for OutputMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "prompt" => Ok(OutputMode::Prompt),
+            "waybar" => Ok(OutputMode::Waybar),
+            "polybar" => Ok(OutputMode::Polybar),
+            "pango" => Ok(OutputMode::Pango),
+            "i3blocks" => Ok(OutputMode::I3blocks),
+            "plain-verbose" => Ok(OutputMode::PlainVerbose),
+            "json" => Ok(OutputMode::Json),
+            "tmux" => Ok(OutputMode::Tmux),
+            "vim" => Ok(OutputMode::Vim),
+            "powerline" => Ok(OutputMode::Powerline),
+            "json-segments" => Ok(OutputMode::JsonSegments),
+            "kv" => Ok(OutputMode::Kv),
+            "fish-vars" => Ok(OutputMode::FishVars),
+            "porcelain" => Ok(OutputMode::Porcelain),
+            _ => Err(format_err!("invalid output mode \"{}\"", s)),
+        }
+    }
+}
+
+/// Wrapping of ANSI sequences so the shell can tell prompt width
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Escape {
+    /// Raw escape sequences (default)
+    None,
+    /// `%{...%}` for zsh `PROMPT`
+    Zsh,
+    /// `\[...\]` for bash `PS1`
+    Bash,
+    /// Raw sequences for the PowerShell `prompt` function, which measures
+    /// width skipping them
+    Powershell,
+}
+
+impl Escape {
+    /// Encoder writing ANSI sequences wrapped for this shell
+    pub fn encoder(self) -> Box<dyn OutputEncoder> {
+        match self {
+            Escape::None | Escape::Powershell => Box::new(encoder::Ansi),
+            Escape::Zsh => Box::new(encoder::Zsh),
+            Escape::Bash => Box::new(encoder::Bash),
+        }
+    }
+}
+
+/// When to color output, from `--color`
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ColorChoice {
+    /// Color if output goes to a terminal or a shell prompt, unless the
+    /// environment says otherwise (default)
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    /// True if output should be colored. For `Auto`, a non-empty `NO_COLOR`
+    /// turns color off, then `CLICOLOR_FORCE` other than "0" turns it on,
+    /// and otherwise it's on if `tty`
+    pub fn resolve(self, no_color: Option<&str>, force: Option<&str>, tty: bool) -> bool {
+        match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto if no_color.is_some_and(|v| !v.is_empty()) => false,
+            ColorChoice::Auto if force.is_some_and(|v| v != "0") => true,
+            ColorChoice::Auto => tty,
+        }
+    }
+}
+
+impl FromStr for ColorChoice {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "auto" => Ok(ColorChoice::Auto),
+            "always" => Ok(ColorChoice::Always),
+            "never" => Ok(ColorChoice::Never),
+            _ => Err(format_err!("invalid color choice \"{}\"", s)),
+        }
+    }
+}
+
+/// What `%d` and `%I` show of a diff
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum DiffStyle {
+    /// Lines inserted and deleted, ex: "+20/-10" (default)
+    #[default]
+    Lines,
+    /// Changed file count, ex: "3Δ"; needs no `git diff`
+    Files,
+    /// File count and line counts, ex: "3Δ +20/-10"
+    Both,
+}
+
+impl FromStr for DiffStyle {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "lines" => Ok(DiffStyle::Lines),
+            "files" => Ok(DiffStyle::Files),
+            "both" => Ok(DiffStyle::Both),
+            _ => Err(format_err!("invalid diff style \"{}\"", s)),
+        }
+    }
+}
+
+impl FromStr for Escape {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(Escape::None),
+            "zsh" => Ok(Escape::Zsh),
+            "bash" => Ok(Escape::Bash),
+            "powershell" => Ok(Escape::Powershell),
+            _ => Err(format_err!("invalid escape mode \"{}\"", s)),
+        }
+    }
+}
+
+/// Wrap each run of ANSI escape sequences in `out` in the shell's markers for
+/// zero-width prompt text. For zsh, literal `%` is doubled so branch names
+/// aren't read as prompt sequences, and for bash, `\`, `$` and backticks are
+/// escaped so they aren't expanded or run
+pub fn escape_prompt(out: &str, escape: Escape) -> String {
+    let (open, close) = match escape {
+        Escape::None | Escape::Powershell => return out.to_string(),
+        Escape::Zsh => ("%{", "%}"),
+        Escape::Bash => ("\\[", "\\]"),
+    };
+    let text = |s: &str| match escape {
+        Escape::Zsh => s.replace('%', "%%"),
+        Escape::Bash => encoder::bash_text(s),
+        _ => s.to_string(),
+    };
+    let mut escaped = String::with_capacity(out.len());
+    let mut rest = out;
+    while let Some(start) = rest.find('\x1b') {
+        escaped.push_str(&text(&rest[..start]));
+        rest = &rest[start..];
+        // Consume consecutive CSI sequences: ESC [ params final-byte
+        let mut end = 0;
+        while rest[end..].starts_with("\x1b[") {
+            match rest[end + 2..].find(|c: char| ('@'..='~').contains(&c)) {
+                Some(i) => end += i + 3,
+                None => break,
+            }
+        }
+        if end == 0 {
+            // Not a CSI sequence; pass the lone ESC through
+            end = 1;
+            escaped.push('\x1b');
+        } else {
+            escaped.push_str(open);
+            escaped.push_str(&rest[..end]);
+            escaped.push_str(close);
+        }
+        rest = &rest[end..];
+    }
+    escaped.push_str(&text(rest));
+    escaped
+}
+
+/// Write `out` framed for async prompt plugins: `token`, a tab, the exit
+/// status, a tab, then `out` and a NUL byte
+pub fn write_async_frame<W: Write>(token: &str, status: i32, out: &[u8], buf: &mut W) -> Result {
+    write!(buf, "{}\t{}\t", token, status)?;
+    buf.write_all(out)?;
+    buf.write_all(b"\0")?;
+    Ok(())
+}
+
+/// Check that `token` can't be confused with the framing around it
+pub fn check_async_token(token: &str) -> Result {
+    if token.contains(&['\t', '\n', '\0'][..]) {
+        return Err(format_err!(
+            "invalid async token {:?}: can't contain tabs, newlines or NUL",
+            token
+        ));
+    }
+    Ok(())
+}
+
+/// Prompt element that produced a segment of output
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Element {
+    Plain,
+    AheadBehind,
+    BaseDivergence,
+    PushDivergence,
+    Branch,
+    BranchGlyph,
+    BranchCount,
+    MergedBranches,
+    ChangeId,
+    Commit,
+    Detached,
+    Diff,
+    FetchAge,
+    Identity,
+    ModifiedStaged,
+    ModifiedUnstaged,
+    Nested,
+    Operation,
+    PartialClone,
+    PullRequest,
+    RemoteBranches,
+    RemoteUrl,
+    RepoName,
+    RepoPath,
+    Sparse,
+    StagedDiff,
+    Stash,
+    Summary,
+    Tag,
+    TagDistance,
+    Untracked,
+    Unmerged,
+    Upstream,
+    Vcs,
+    Worktree,
+}
+
+impl Element {
+    /// Element rendered by format string token
+    pub fn from_token(c: char) -> Self {
+        match c {
+            'a' => Element::AheadBehind,
+            'A' => Element::BaseDivergence,
+            'B' => Element::PushDivergence,
+            'b' => Element::Branch,
+            'c' => Element::Commit,
+            'd' => Element::Diff,
+            'D' => Element::Detached,
+            'e' => Element::Nested,
+            'f' => Element::FetchAge,
+            'g' => Element::BranchGlyph,
+            'h' => Element::RemoteUrl,
+            'i' => Element::Identity,
+            'I' => Element::StagedDiff,
+            'j' => Element::ChangeId,
+            'k' => Element::BranchCount,
+            'K' => Element::MergedBranches,
+            'l' => Element::PartialClone,
+            'M' => Element::RemoteBranches,
+            'p' => Element::PullRequest,
+            'P' => Element::RepoPath,
+            'm' => Element::ModifiedUnstaged,
+            'n' => Element::Vcs,
+            'o' => Element::Operation,
+            'r' => Element::Upstream,
+            'R' => Element::RepoName,
+            's' => Element::ModifiedStaged,
+            'S' => Element::Sparse,
+            't' => Element::Stash,
+            'u' => Element::Untracked,
+            'U' => Element::Unmerged,
+            'T' => Element::Tag,
+            'v' => Element::TagDistance,
+            'w' => Element::Worktree,
+            'x' => Element::Summary,
+            _ => Element::Plain,
+        }
+    }
+
+    /// Snake case name, matching the `--style` element where there is one
+    pub fn name(self) -> &'static str {
+        match self {
+            Element::Plain => "plain",
+            Element::AheadBehind => "ahead_behind",
+            Element::BaseDivergence => "base_divergence",
+            Element::PushDivergence => "push_divergence",
+            Element::Branch => "branch",
+            Element::BranchGlyph => "branch_glyph",
+            Element::BranchCount => "branch_count",
+            Element::MergedBranches => "merged_branches",
+            Element::ChangeId => "change_id",
+            Element::Commit => "commit",
+            Element::Detached => "detached",
+            Element::Diff => "diff",
+            Element::FetchAge => "fetch_age",
+            Element::Identity => "identity",
+            Element::ModifiedStaged => "modified_staged",
+            Element::ModifiedUnstaged => "modified_unstaged",
+            Element::Nested => "nested",
+            Element::Operation => "operation",
+            Element::PartialClone => "partial_clone",
+            Element::PullRequest => "pull_request",
+            Element::RemoteBranches => "remote_branches",
+            Element::RemoteUrl => "remote_url",
+            Element::RepoName => "repo_name",
+            Element::RepoPath => "repo_path",
+            Element::Sparse => "sparse",
+            Element::StagedDiff => "staged_diff",
+            Element::Stash => "stash",
+            Element::Summary => "dirty",
+            Element::Tag => "tag",
+            Element::TagDistance => "tag_distance",
+            Element::Untracked => "untracked",
+            Element::Unmerged => "unmerged",
+            Element::Upstream => "upstream",
+            Element::Vcs => "vcs",
+            Element::Worktree => "worktree",
+        }
+    }
+
+    /// Style of the element in `styles`; the one tokens are painted with
+    /// unless a state like a protected branch or old stash picks another
+    pub fn style(self, styles: &StyleSet) -> &Style {
+        match self {
+            Element::Plain | Element::Vcs => &styles.plain,
+            Element::AheadBehind | Element::BaseDivergence | Element::PushDivergence => {
+                &styles.ahead_behind
+            }
+            Element::Branch => &styles.branch,
+            Element::BranchGlyph => &styles.branch_glyph,
+            Element::BranchCount | Element::MergedBranches => &styles.branch_count,
+            Element::ChangeId => &styles.change_id,
+            Element::Commit => &styles.commit,
+            Element::Detached => &styles.detached,
+            Element::Diff => &styles.diff,
+            Element::FetchAge => &styles.fetch_age,
+            Element::Identity => &styles.identity,
+            Element::ModifiedStaged => &styles.modified_staged,
+            Element::ModifiedUnstaged => &styles.modified_unstaged,
+            Element::Nested => &styles.nested,
+            Element::Operation => &styles.operation,
+            Element::PartialClone => &styles.partial_clone,
+            Element::PullRequest => &styles.pull_request,
+            Element::RemoteBranches | Element::Upstream => &styles.upstream,
+            Element::RemoteUrl => &styles.remote_url,
+            Element::RepoName => &styles.repo_name,
+            Element::RepoPath => &styles.repo_path,
+            Element::Sparse => &styles.sparse,
+            Element::StagedDiff => &styles.staged_diff,
+            Element::Stash => &styles.stash,
+            Element::Summary => &styles.dirty,
+            Element::Tag | Element::TagDistance => &styles.tag_distance,
+            Element::Untracked => &styles.untracked,
+            Element::Unmerged => &styles.unmerged,
+            Element::Worktree => &styles.worktree,
+        }
+    }
+}
+
+/// Areas whose files are printed with `--list`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ListArea {
+    Staged,
+    Unstaged,
+    Untracked,
+    All,
+}
+
+impl FromStr for ListArea {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "staged" => Ok(ListArea::Staged),
+            "unstaged" => Ok(ListArea::Unstaged),
+            "untracked" => Ok(ListArea::Untracked),
+            "all" => Ok(ListArea::All),
+            _ => Err(format_err!("invalid file list area \"{}\"", s)),
+        }
+    }
+}
+
+/// Rendered output of a format token or run of literal text
+#[derive(Debug)]
+pub struct Segment {
+    pub element: Element,
+    pub text:    String,
+}
+
+impl Segment {
+    /// Move contents of `buf` into a new segment, skipping empty output
+    fn push(segments: &mut Vec<Segment>, element: Element, buf: &mut Vec<u8>) -> Result {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let text = String::from_utf8(std::mem::take(buf))?;
+        segments.push(Segment { element, text });
+        Ok(())
+    }
+}
+
+/// Simple output to mimic default git prompt. Returns true if the status
+/// lists any changes
+pub fn simple_output<S, W>(git_status: S, buf: &mut W) -> Result<bool>
+where
+    S: AsRef<str>,
+    W: Write,
+{
+    let mut raw_branch = "";
+    let mut dirty = false;
+    for line in git_status.as_ref().lines() {
+        if line.starts_with("##") {
+            raw_branch = &line[3..];
+        } else {
+            dirty = true;
+            break;
+        }
+    }
+    let split = raw_branch.split("...").collect::<Vec<&str>>();
+    let branch = match split.get(0) {
+        Some(b) if b.starts_with("HEAD") => git_tag().unwrap_or_else(|_| "unknown".to_string()),
+        Some(b) => b.to_string(),
+        None => "unknown".to_string(),
+    };
+    debug!(
+        "Raw: {}; Split: {:?}; Branch: {}",
+        raw_branch, split, branch
+    );
+    let styles = StyleSet::simple();
+    styles.branch.write_to(buf)?;
+    write!(buf, "({})", branch)?;
+    if dirty {
+        styles.dirty.write_to(buf)?;
+        write!(buf, "*")?;
+    }
+    Style::reset().write_to(buf)?;
+    Ok(dirty)
+}
+
+/// Simple output read from git's files alone, without running git: branch, or
+/// short commit when detached, any operation in progress, and `*` if a tracked
+/// file looks changed; see `stat_dirty`. Nothing outside a git repo. Returns
+/// true if `*` was shown
+pub fn fs_only_output(buf: &mut impl Write) -> Result<bool> {
+    let cwd = env::current_dir()?;
+    let bare_dir = find_bare_dir(&cwd);
+    let git_dir = match find_git_dir(&cwd).or_else(|| bare_dir.clone()) {
+        Some(dir) => dir,
+        None => return Ok(false),
+    };
+    let mut ri = Repo::from_git_files(&git_dir)?;
+    ri.git_operation()?;
+    let branch = match &ri.commit {
+        Some(commit) if ri.detached => commit.get(..7).unwrap_or(commit).to_string(),
+        _ => current_branch(&cwd)
+            .or_else(|| ri.branch.clone())
+            .unwrap_or_default(),
+    };
+    // No working tree to compare the index with in the git dir
+    let dirty = match (&bare_dir, find_work_tree(&cwd)) {
+        (None, Some(top)) => stat_dirty(&git_dir, &top).unwrap_or(false),
+        _ => false,
+    };
+    debug!(
+        "Git dir: {:?}; Branch: {}; Operation: {:?}; Dirty: {}",
+        git_dir, branch, ri.operation, dirty
+    );
+    let styles = StyleSet::simple();
+    styles.branch.write_to(buf)?;
+    match &ri.operation {
+        Some(op) => write!(buf, "({}|{})", branch, op)?,
+        None => write!(buf, "({})", branch)?,
+    }
+    if dirty {
+        styles.dirty.write_to(buf)?;
+        write!(buf, "*")?;
+    }
+    Style::reset().write_to(buf)?;
+    Ok(dirty)
+}
+
+/// Tokens dropped first when output is wider than `--max-width`, least
+/// important first. Tokens not listed are kept, and the branch is truncated
+/// if they still don't fit
+const DROP_ORDER: &[char] = &[
+    'd', 'I', 'u', 'f', 'K', 'k', 'A', 'B', 'M', 'p', 'h', 'i', 'P', 'T', 'v', 'l', 'S', 'e', 'j',
+    'n', 'w', 't', 'r', 'g', 'R', 'a', 'm', 's', 'x', 'c', 'U',
+];
+
+/// Changes to rendered tokens that make output fit `--max-width`
+#[derive(Debug, Default)]
+struct Fit {
+    /// Tokens rendered empty
+    dropped:    Vec<char>,
+    /// Columns `%b` is truncated to
+    branch_max: Option<usize>,
+}
+
+/// Walks parsed format string, collecting rendered segments
+struct Renderer<'a> {
+    ri:       &'a Repo,
+    options:  &'a Options,
+    styles:   &'a StyleSet,
+    encoder:  &'a mut dyn OutputEncoder,
+    segments: Vec<Segment>,
+    literal:  Vec<u8>,
+    fit:      Fit,
+    /// Text of output so far without styles, and of each token
+    plain:    String,
+    widths:   Vec<(char, usize)>,
+}
+
+impl<'a> Renderer<'a> {
+    /// Render nodes, choosing the arm of each conditional section by whether
+    /// its token produces output, and dropping groups whose tokens don't
+    fn render(&mut self, nodes: &[Node]) -> Result {
+        for node in nodes {
+            match node {
+                Node::Literal(text) => {
+                    let mut buf = StyledBuf::new(&mut *self.encoder, Element::Plain);
+                    for c in text.chars() {
+                        if c != ' ' {
+                            // Only write plain style if there's something there
+                            buf.style(&self.styles.plain)?;
+                        }
+                        write!(buf, "{}", c)?;
+                    }
+                    let (mut out, plain) = buf.into_parts()?;
+                    self.literal.append(&mut out);
+                    self.plain.push_str(&plain);
+                }
+                Node::Token(c, width) => {
+                    let (mut buf, plain) = self.fitted_token(*c, *width, false)?;
+                    self.plain.push_str(&plain);
+                    self.widths.push((*c, plain.width()));
+                    Segment::push(&mut self.segments, Element::Plain, &mut self.literal)?;
+                    Segment::push(&mut self.segments, Element::from_token(*c), &mut buf)?;
+                }
+                Node::Cond {
+                    token,
+                    then,
+                    otherwise,
+                } => {
+                    if self
+                        .fitted_token(*token, Width::default(), true)?
+                        .0
+                        .is_empty()
+                    {
+                        self.render(otherwise)?;
+                    } else {
+                        self.render(then)?;
+                    }
+                }
+                Node::Group(nodes) => {
+                    let mut shown = false;
+                    for c in format::tokens(nodes) {
+                        if !self.fitted_token(c, Width::default(), true)?.0.is_empty() {
+                            shown = true;
+                            break;
+                        }
+                    }
+                    if shown {
+                        self.render(nodes)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Render single token and its text without styles, leaving it empty if
+    /// it was dropped to fit `--max-width`. A `probe` only checks for output,
+    /// so it leaves the encoder's state alone
+    fn fitted_token(
+        &mut self,
+        c: char,
+        mut width: Width,
+        probe: bool,
+    ) -> Result<(Vec<u8>, String)> {
+        if self.fit.dropped.contains(&c) {
+            return Ok(Default::default());
+        }
+        if let (Some(max), 'b') = (self.fit.branch_max, c) {
+            width.max = Some(width.max.map_or(max, |m| m.min(max)));
+        }
+        if probe {
+            Self::token(
+                self.ri,
+                self.options,
+                self.styles,
+                &mut encoder::Ansi,
+                c,
+                width,
+            )
+        } else {
+            Self::token(self.ri, self.options, self.styles, self.encoder, c, width)
+        }
+    }
+
+    /// Render single token, fitted to `width`. For `%c`, width is the number
+    /// of hash digits instead
+    fn token(
+        ri: &Repo,
+        options: &Options,
+        styles: &StyleSet,
+        encoder: &mut dyn OutputEncoder,
+        c: char,
+        width: Width,
+    ) -> Result<(Vec<u8>, String)> {
+        let glyphs = &options.glyphs;
+        let mut buf = StyledBuf::new(encoder, Element::from_token(c));
+        if c != 'c' {
+            buf = buf.with_width(width);
+        }
+        match c {
+            'a' => ri.fmt_ahead_behind(
+                &mut buf,
+                &styles.ahead_behind,
+                glyphs,
+                options.indicators_only,
+                options.divergence_age,
+            )?,
+            'A' => ri.fmt_base_divergence(
+                &mut buf,
+                &styles.ahead_behind,
+                glyphs,
+                options.indicators_only,
+            )?,
+            'B' => ri.fmt_push_divergence(
+                &mut buf,
+                &styles.ahead_behind,
+                glyphs,
+                options.indicators_only,
+            )?,
+            'b' => {
+                let style = if ri.is_protected(&options.protected_branches) {
+                    &styles.branch_protected
+                } else {
+                    &styles.branch
+                };
+                ri.fmt_branch(&mut buf, style, &styles.bare, glyphs)?
+            }
+            'c' => ri.fmt_commit(
+                &mut buf,
+                &styles.commit,
+                width.max.or(width.min).unwrap_or(7),
+            )?,
+            'd' => ri.fmt_diff_numstat(&mut buf, &styles.diff, options)?,
+            'I' => ri.fmt_staged_numstat(&mut buf, &styles.staged_diff, options)?,
+            'D' => ri.fmt_detached(&mut buf, &styles.detached)?,
+            'e' => ri.fmt_nested(&mut buf, &styles.nested, glyphs)?,
+            'f' => ri.fmt_fetch_age(
+                &mut buf,
+                &styles.fetch_age,
+                glyphs,
+                options.fetch_stale_hours * 3600,
+            )?,
+            'g' => ri.fmt_branch_glyph(&mut buf, &styles.branch_glyph, glyphs)?,
+            'h' => ri.fmt_remote_url(&mut buf, &styles.remote_url)?,
+            'M' => {
+                ri.fmt_remote_branches(&mut buf, &styles.upstream, glyphs, options.indicators_only)?
+            }
+            'i' => ri.fmt_user_email(
+                &mut buf,
+                &styles.identity,
+                &styles.identity_mismatch,
+                options.expected_email.as_deref(),
+            )?,
+            'j' => ri.fmt_change_id(&mut buf, &styles.change_id)?,
+            'k' => ri.fmt_branch_count(
+                &mut buf,
+                &styles.branch_count,
+                glyphs,
+                options.indicators_only,
+            )?,
+            'K' => ri.fmt_merged_branches(
+                &mut buf,
+                &styles.branch_count,
+                glyphs,
+                options.indicators_only,
+            )?,
+            'l' => ri.fmt_partial_clone(&mut buf, &styles.partial_clone, glyphs)?,
+            'S' => ri.fmt_sparse(&mut buf, &styles.sparse, glyphs)?,
+            'x' => ri.fmt_summary(&mut buf, &styles.dirty)?,
+            'm' => ri.unstaged.fmt_modified(
+                &mut buf,
+                &styles.modified_unstaged,
+                glyphs,
+                options.indicators_only || ri.fast_dirty,
+            )?,
+            'n' => {
+                buf.style(&styles.plain)?;
+                write!(buf, "{}", ri.vcs.name())?
+            }
+            'o' => ri.fmt_operation(&mut buf, &styles.operation)?,
+            'p' => ri.fmt_pull_request(&mut buf, &styles.pull_request, glyphs)?,
+            'P' => ri.fmt_repo_path(&mut buf, &styles.repo_path)?,
+            'r' => ri.fmt_upstream(&mut buf, &styles.upstream, &options.upstream)?,
+            'R' => ri.fmt_repo_name(&mut buf, &styles.repo_name)?,
+            's' => ri.staged.fmt_modified(
+                &mut buf,
+                &styles.modified_staged,
+                glyphs,
+                options.indicators_only || ri.fast_dirty,
+            )?,
+            't' => ri.fmt_stash(
+                &mut buf,
+                &styles.stash,
+                &styles.stash_old,
+                glyphs,
+                options.indicators_only,
+                options.stash_warn_days * 86400,
+            )?,
+            'u' => {
+                ri.fmt_untracked(&mut buf, &styles.untracked, glyphs, options.indicators_only)?
+            }
+            'U' => ri.fmt_unmerged(
+                &mut buf,
+                &styles.unmerged,
+                glyphs,
+                options.indicators_only,
+                options.unmerged_detail,
+            )?,
+            'T' => ri.fmt_describe(&mut buf, &styles.tag_distance)?,
+            'v' => ri.fmt_tag_distance(&mut buf, &styles.tag_distance)?,
+            'w' => ri.fmt_worktree(&mut buf, &styles.worktree)?,
+            _ => unreachable!(
+                "invalid format token allowed to reach print_output: \"%{}\"",
+                c
+            ),
+        }
+        buf.into_parts()
+    }
+}
+
+/// Choose tokens to drop, in `DROP_ORDER`, until the format string renders in
+/// `max` columns, then truncate the branch by whatever is left over. Each try
+/// renders the format string again, without styles
+fn fit_width(
+    ri: &Repo,
+    nodes: &[Node],
+    options: &Options,
+    styles: &StyleSet,
+    max: usize,
+) -> Result<Fit> {
+    let mut fit = Fit::default();
+    loop {
+        let mut encoder = encoder::Ansi;
+        let mut renderer = Renderer {
+            ri,
+            options,
+            styles,
+            encoder: &mut encoder,
+            segments: vec![],
+            literal: vec![],
+            fit,
+            plain: String::new(),
+            widths: vec![],
+        };
+        renderer.render(nodes)?;
+        if ri.timed_out {
+            renderer.plain.push(' ');
+            renderer.plain.push_str(&options.glyphs.timeout);
+        }
+        let Renderer {
+            fit: f,
+            plain,
+            widths,
+            ..
+        } = renderer;
+        fit = f;
+        let plain = trim_output(plain.into_bytes(), options.no_trim)?;
+        let over = match plain.width().checked_sub(max) {
+            Some(over) if over > 0 => over,
+            _ => break,
+        };
+        let shown = |c: &char| widths.iter().any(|&(t, w)| t == *c && w > 0);
+        match DROP_ORDER.iter().find(|c| shown(c)) {
+            Some(&c) => fit.dropped.push(c),
+            None => {
+                let branch = widths
+                    .iter()
+                    .filter(|&&(t, _)| t == 'b')
+                    .map(|&(_, w)| w)
+                    .max();
+                // At least one column before the ellipsis
+                fit.branch_max = branch.map(|w| w.saturating_sub(over).max(2));
+                break;
+            }
+        }
+    }
+    Ok(fit)
+}
+
+/// Render format string into segments, one for each token and run of literal
+/// text, encoded for the output target by `encoder`. Only formats what
+/// `Repo::collect` gathered
+pub fn render_segments(
+    ri: &Repo,
+    options: &Options,
+    styles: &StyleSet,
+    encoder: &mut dyn OutputEncoder,
+) -> Result<Vec<Segment>> {
+    let nodes = format::parse(&options.format)?;
+    let fit = match options.max_width {
+        Some(max) => fit_width(ri, &nodes, options, styles, max)?,
+        None => Fit::default(),
+    };
+    let mut renderer = Renderer {
+        ri,
+        options,
+        styles,
+        encoder,
+        segments: vec![],
+        literal: vec![],
+        fit,
+        plain: String::new(),
+        widths: vec![],
+    };
+    renderer.render(&nodes)?;
+    let mut buf = StyledBuf::new(&mut *renderer.encoder, Element::Plain);
+    if ri.timed_out {
+        write!(buf, " ")?;
+        buf.paint(&styles.plain, &options.glyphs.timeout)?;
+    }
+    renderer.literal.append(&mut buf.into_inner()?);
+    renderer.encoder.finish(&mut renderer.literal)?;
+    let mut segments = renderer.segments;
+    Segment::push(&mut segments, Element::Plain, &mut renderer.literal)?;
+    Ok(segments)
+}
+
+/// Print output based on parsing of --format string
+pub fn print_output<W: Write>(
+    ri: &Repo,
+    options: &Options,
+    styles: &StyleSet,
+    encoder: &mut dyn OutputEncoder,
+    buf: &mut W,
+) -> Result {
+    for segment in render_segments(ri, options, styles, encoder)? {
+        buf.write_all(segment.text.as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Collapse runs of whitespace in rendered output unless `--no-trim` is set
+pub fn trim_output(buf: Vec<u8>, no_trim: bool) -> Result<String> {
+    let out = String::from_utf8(buf)?;
+    if no_trim {
+        return Ok(out);
+    }
+    // Padding from width modifiers is figure spaces, which this keeps
+    Ok(out.split_ascii_whitespace().collect::<Vec<_>>().join(" "))
+}
+
+/// Print JSON object for a waybar custom module, with `remote` URL if `%h` is used
+fn waybar_output<W: Write>(ri: &Repo, options: &Options, buf: &mut W) -> Result {
+    let class = ri.state_classes();
+    let tooltip = ri.summary();
+    let remote = ri.remote.clone();
+    // Waybar reads both as Pango markup
+    let text = pango_markup(ri, options)?;
+    let mut json = serde_json::json!({
+        "text": text,
+        "tooltip": pango_escape(&tooltip),
+        "class": class,
+    });
+    if let Some(url) = remote {
+        json["remote"] = url.into();
+    }
+    writeln!(buf, "{}", json)?;
+    Ok(())
+}
+
+/// Paint entire output with alert style, reapplying it after each reset so the
+/// background is unbroken
+pub fn paint_alert(out: &str, style: &Style, encoder: &mut dyn OutputEncoder) -> Result<String> {
+    let mut start = StyledBuf::new(encoder, Element::Plain);
+    start.style(style)?;
+    let start = String::from_utf8(start.into_inner()?)?;
+    let mut reset = StyledBuf::new(encoder, Element::Plain);
+    reset.reset()?;
+    let reset = String::from_utf8(reset.into_inner()?)?;
+    if reset.is_empty() {
+        return Ok(out.to_string());
+    }
+    let restyle = format!("{}{}", reset, start);
+    Ok(format!(
+        "{}{}{}",
+        start,
+        out.replace(&reset, &restyle),
+        reset
+    ))
+}
+
+/// Print polybar formatted output, coloring each segment with `%{F#rrggbb}` tags
+/// from its element's style, and `%{B#rrggbb}` if the style has a background
+fn polybar_output<W: Write>(ri: &Repo, options: &Options, buf: &mut W) -> Result {
+    let mut out = vec![];
+    for segment in render_segments(ri, options, &options.styles, &mut encoder::Ansi)? {
+        let style = segment.element.style(&options.styles);
+        if segment.text.trim().is_empty() {
+            out.write_all(segment.text.as_bytes())?;
+            continue;
+        }
+        let (fg, bg) = (style::hex_color(style), style::hex_background(style));
+        if let Some(hex) = &bg {
+            write!(out, "%{{B{}}}", hex)?;
+        }
+        match &fg {
+            Some(hex) => write!(out, "%{{F{}}}{}%{{F-}}", hex, segment.text)?,
+            None => out.write_all(segment.text.as_bytes())?,
+        }
+        if bg.is_some() {
+            write!(out, "%{{B-}}")?;
+        }
+    }
+    writeln!(buf, "{}", trim_output(out, options.no_trim)?)?;
+    Ok(())
+}
+
+/// Escape text for Pango markup
+fn pango_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Print Pango markup, coloring each segment with a `<span>`, for status bars
+/// that render it, ex: i3blocks with `markup=pango` or a waybar `format`
+fn pango_output<W: Write>(ri: &Repo, options: &Options, buf: &mut W) -> Result {
+    writeln!(buf, "{}", pango_markup(ri, options)?)?;
+    Ok(())
+}
+
+/// Format string rendered as Pango markup, each segment in a `<span>` with the
+/// colors of its element's style
+fn pango_markup(ri: &Repo, options: &Options) -> Result<String> {
+    let mut out = vec![];
+    for segment in render_segments(ri, options, &options.styles, &mut encoder::Ansi)? {
+        let text = pango_escape(&segment.text);
+        let style = segment.element.style(&options.styles);
+        let attrs = [
+            ("foreground", style::hex_color(style)),
+            ("background", style::hex_background(style)),
+        ]
+        .iter()
+        .filter_map(|(name, hex)| Some(format!(" {}=\"{}\"", name, hex.as_ref()?)))
+        .collect::<String>();
+        if attrs.is_empty() || segment.text.trim().is_empty() {
+            out.write_all(text.as_bytes())?;
+        } else {
+            write!(out, "<span{}>{}</span>", attrs, text)?;
+        }
+    }
+    trim_output(out, options.no_trim)
+}
+
+/// Print i3blocks output: full text, short text and color on separate lines
+fn i3blocks_output<W: Write>(ri: &Repo, options: &Options, buf: &mut W) -> Result {
+    let dirty = ri.is_dirty();
+    let short = format!(
+        "{}{}",
+        ri.branch.as_deref().unwrap_or_default(),
+        if dirty { "*" } else { "" }
+    );
+    let state = if ri.unmerged > 0 {
+        Element::Unmerged
+    } else if dirty {
+        Element::ModifiedUnstaged
+    } else {
+        Element::Branch
+    };
+    let mut full = vec![];
+    print_output(ri, options, &options.styles, &mut encoder::Ansi, &mut full)?;
+    writeln!(buf, "{}", trim_output(full, options.no_trim)?)?;
+    writeln!(buf, "{}", short)?;
+    writeln!(
+        buf,
+        "{}",
+        style::hex_color(state.style(&options.styles)).unwrap_or_default()
+    )?;
+    Ok(())
+}
+
+/// Change counts of a git area as JSON object
+fn area_json(area: &GitArea) -> serde_json::Value {
+    serde_json::json!({
+        "modified": area.modified,
+        "added": area.added,
+        "deleted": area.deleted,
+        "renamed": area.renamed,
+        "copied": area.copied,
+        "total": area.change_ct(),
+    })
+}
+
+/// JSON fields that depend on worktree files, which a cached render can't
+/// tell have changed
+const WORKTREE_FIELDS: &[&str] = &["unstaged", "untracked", "insertions", "deletions", "dirty"];
+
+/// JSON field value that may be out of date: skipped because the time budget
+/// ran out, or rendered `age` ago and reused from a cache
+fn stale_field(value: serde_json::Value, age: Option<Duration>) -> serde_json::Value {
+    let mut field = serde_json::json!({ "value": value, "stale": true });
+    if let Some(age) = age {
+        field["age_ms"] = (age.as_millis() as u64).into();
+    }
+    field
+}
+
+/// Mark worktree fields of a cached `--output json` render as stale, since
+/// files may have changed in the `age` since it was rendered
+pub fn mark_cached_json(out: &str, age: Duration) -> Result<String> {
+    let mut json: serde_json::Value = serde_json::from_str(out)?;
+    for key in WORKTREE_FIELDS {
+        if let Some(value) = json.get_mut(*key) {
+            *value = stale_field(value.take(), Some(age));
+        }
+    }
+    Ok(format!("{}\n", json))
+}
+
+/// All status fields as a JSON object, with the names of fields whose git
+/// command ran out of time. Divergence from `base` is only included when it is
+/// given, since it costs another git command
+fn status_json(mut ri: Repo, base: Option<&str>) -> Result<(serde_json::Value, Vec<&'static str>)> {
+    // Nothing is known if status itself timed out
+    let mut skipped: Vec<&'static str> = if ri.timed_out {
+        vec![
+            "branch",
+            "detached",
+            "commit",
+            "upstream",
+            "ahead",
+            "behind",
+            "staged",
+            "unstaged",
+            "untracked",
+            "unmerged",
+            "conflicts",
+            "insertions",
+            "deletions",
+            "staged_insertions",
+            "staged_deletions",
+            "dirty",
+        ]
+    } else {
+        vec![]
+    };
+    // Like `Repo::allow_timeout`, also recording which fields were skipped
+    let mut allow_timeout = |ri: &mut Repo, fields: &[&'static str], result: Result| match result {
+        Err(e) if e.is::<TimedOut>() => {
+            ri.timed_out = true;
+            skipped.extend_from_slice(fields);
+            Ok(())
+        }
+        r => r,
+    };
+    // Only what git's files tell is known when git can't be run
+    if !ri.files_only {
+        let r = ri.git_stash_count();
+        allow_timeout(&mut ri, &["stashed", "stash_age"], r)?;
+        let r = ri.git_operation();
+        allow_timeout(&mut ri, &["operation"], r)?;
+        let r = ri.git_worktree();
+        allow_timeout(&mut ri, &["worktree"], r)?;
+        let r = ri.git_fetch_age();
+        allow_timeout(&mut ri, &["fetch_age"], r)?;
+        if base.is_some() {
+            let r = ri.git_base_divergence(base);
+            allow_timeout(&mut ri, &["base"], r)?;
+        }
+        let r = ri.git_push_divergence();
+        allow_timeout(&mut ri, &["push"], r)?;
+        let r = ri.git_remote_url();
+        allow_timeout(&mut ri, &["remote_url"], r)?;
+        if ri.unstaged.has_changed() {
+            let r = ri.git_diff_numstat();
+            allow_timeout(&mut ri, &["insertions", "deletions"], r)?;
+        }
+        if ri.staged.has_changed() {
+            let r = ri.git_staged_numstat();
+            allow_timeout(&mut ri, &["staged_insertions", "staged_deletions"], r)?;
+        }
+    }
+    ri.find_work_tree()?;
+    let path = ri.work_tree_path()?;
+    let partial_clone = ri.is_promisor();
+    let sparse = ri.is_sparse();
+    let mut json = serde_json::json!({
+        "vcs": ri.vcs.name(),
+        "work_tree": ri.work_tree,
+        "path": path,
+        "branch": ri.branch,
+        "detached": ri.detached,
+        "bare": ri.bare,
+        "commit": ri.commit,
+        "change_id": ri.change_id,
+        "worktree": ri.worktree,
+        "upstream": ri.upstream,
+        "remote_url": ri.remote,
+        "ahead": ri.ahead,
+        "behind": ri.behind,
+        "fetch_age": ri.fetch_age,
+        "staged": area_json(&ri.staged),
+        "unstaged": area_json(&ri.unstaged),
+        "untracked": ri.untracked,
+        "unmerged": ri.unmerged,
+        "conflicts": {
+            "both_modified": ri.conflicts.both_modified,
+            "both_added": ri.conflicts.both_added,
+            "deleted_by_us": ri.conflicts.deleted_by_us,
+            "deleted_by_them": ri.conflicts.deleted_by_them,
+        },
+        "operation": ri.operation,
+        "stashed": ri.stashed,
+        "stash_age": ri.stash_age,
+        "insertions": ri.insertions,
+        "deletions": ri.deletions,
+        "staged_insertions": ri.staged_insertions,
+        "staged_deletions": ri.staged_deletions,
+        "dirty": ri.is_dirty(),
+        "partial_clone": partial_clone,
+        "sparse": sparse,
+        "timed_out": ri.timed_out,
+    });
+    if let Some(base) = &ri.base {
+        json["base"] = serde_json::json!({
+            "branch": base,
+            "ahead": ri.base_ahead,
+            "behind": ri.base_behind,
+        });
+    }
+    if let Some(push) = &ri.push {
+        json["push"] = serde_json::json!({
+            "branch": push,
+            "ahead": ri.push_ahead,
+            "behind": ri.push_behind,
+        });
+    }
+    Ok((json, skipped))
+}
+
+/// Print all status fields as a JSON object, ignoring the format string.
+/// Fields whose git command ran out of time are wrapped as
+/// `{"value": ..., "stale": true}`
+fn json_output<W: Write>(ri: Repo, base: Option<&str>, buf: &mut W) -> Result {
+    let (mut json, skipped) = status_json(ri, base)?;
+    for key in skipped {
+        json[key] = stale_field(json[key].take(), None);
+    }
+    writeln!(buf, "{}", json)?;
+    Ok(())
+}
+
+/// Value of a `key=value` line, single-quoted if the shell would split or
+/// expand it. Null is empty
+fn kv_value(value: &serde_json::Value) -> String {
+    let text = match value {
+        serde_json::Value::Null => return String::new(),
+        serde_json::Value::String(s) => s.clone(),
+        v => v.to_string(),
+    };
+    let plain = |c: char| c.is_ascii_alphanumeric() || "-_./:@+,%".contains(c);
+    if text.chars().all(plain) {
+        text
+    } else {
+        format!("'{}'", text.replace('\'', "'\\''"))
+    }
+}
+
+/// Write `value` as `key=value` lines, naming fields of nested objects
+/// `<key>_<field>`, ex: "staged_modified=2"
+fn write_kv<W: Write>(key: &str, value: &serde_json::Value, buf: &mut W) -> Result {
+    match value {
+        serde_json::Value::Object(fields) => {
+            for (field, value) in fields {
+                write_kv(&format!("{}_{}", key, field), value, buf)?;
+            }
+        }
+        v => writeln!(buf, "{}={}", key, kv_value(v))?,
+    }
+    Ok(())
+}
+
+/// Print all status fields as uncolored `key=value` lines, ignoring the format
+/// string, ex: for a starship `custom` module. Fields whose git command ran out
+/// of time are listed in `stale`
+fn kv_output<W: Write>(ri: Repo, base: Option<&str>, buf: &mut W) -> Result {
+    let (json, skipped) = status_json(ri, base)?;
+    if let serde_json::Value::Object(fields) = &json {
+        for (key, value) in fields {
+            write_kv(key, value, buf)?;
+        }
+    }
+    writeln!(buf, "stale={}", skipped.join(","))?;
+    Ok(())
+}
+
+/// Quote for fish: single quotes, escaping `\` and `'` with a backslash
+pub fn fish_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+/// Write `value` as a fish `set -g gitpr_<key>` statement, naming fields of
+/// nested objects like `write_kv`. Arrays of scalars become lists, and null an
+/// empty list, so values left from the last prompt are cleared
+fn write_fish_var<W: Write>(key: &str, value: &serde_json::Value, buf: &mut W) -> Result {
+    let item = |v: &serde_json::Value| match v {
+        serde_json::Value::String(s) => fish_quote(s),
+        serde_json::Value::Number(_) | serde_json::Value::Bool(_) => v.to_string(),
+        v => fish_quote(&v.to_string()),
+    };
+    let items: Vec<String> = match value {
+        serde_json::Value::Object(fields) => {
+            for (field, value) in fields {
+                write_fish_var(&format!("{}_{}", key, field), value, buf)?;
+            }
+            return Ok(());
+        }
+        serde_json::Value::Null => vec![],
+        serde_json::Value::Array(values) if !values.iter().any(|v| v.is_object()) => {
+            values.iter().map(item).collect()
+        }
+        v => vec![item(v)],
+    };
+    write!(buf, "set -g gitpr_{}", key)?;
+    for item in items {
+        write!(buf, " {}", item)?;
+    }
+    writeln!(buf)?;
+    Ok(())
+}
+
+/// Print all status fields as fish `set -g` statements, ignoring the format
+/// string, for `gitpr --output fish-vars | source` in `fish_prompt`. Fields
+/// whose git command ran out of time are listed in `gitpr_stale`
+fn fish_vars_output<W: Write>(ri: Repo, base: Option<&str>, buf: &mut W) -> Result {
+    let (json, skipped) = status_json(ri, base)?;
+    if let serde_json::Value::Object(fields) = &json {
+        for (key, value) in fields {
+            write_fish_var(key, value, buf)?;
+        }
+    }
+    write_fish_var("stale", &serde_json::json!(skipped), buf)
+}
+
+/// Write output of one repo of several checked in a run: each line after `dir`
+/// and a tab, or for `--output json`, the object with `dir` added on one line.
+/// Empty output, from a dir outside a repo, writes nothing. JSON that doesn't
+/// parse fails with a `serde_json::Error` before anything is written
+pub fn write_batch_entry<W: Write>(dir: &str, out: &str, mode: OutputMode, buf: &mut W) -> Result {
+    if out.is_empty() {
+        return Ok(());
+    }
+    if mode == OutputMode::Json {
+        let mut json: serde_json::Value = serde_json::from_str(out)?;
+        json["dir"] = dir.into();
+        writeln!(buf, "{}", json)?;
+    } else {
+        for line in out.lines() {
+            writeln!(buf, "{}\t{}", dir, line)?;
+        }
+    }
+    Ok(())
+}
+
+/// Totals of the repos checked in one run of several, for `--summary`
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct BatchSummary {
+    pub clean:  usize,
+    /// Repos with changes, untracked files or conflicts
+    pub dirty:  usize,
+    /// Repos whose status failed or timed out
+    pub errors: usize,
+}
+
+impl BatchSummary {
+    /// Write the totals as a trailer line, ex: "3 repos: 1 clean, 1 dirty, 1
+    /// failed", or as an object under `summary` if `json`
+    pub fn write<W: Write>(&self, json: bool, buf: &mut W) -> Result {
+        let repos = self.clean + self.dirty + self.errors;
+        if json {
+            let summary = serde_json::json!({
+                "summary": {
+                    "repos": repos,
+                    "clean": self.clean,
+                    "dirty": self.dirty,
+                    "errors": self.errors,
+                }
+            });
+            writeln!(buf, "{}", summary)?;
+        } else {
+            writeln!(
+                buf,
+                "{} repo{}: {} clean, {} dirty, {} failed",
+                repos,
+                if repos == 1 { "" } else { "s" },
+                self.clean,
+                self.dirty,
+                self.errors
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Version of the `--output porcelain` schema, printed as its first line.
+/// Bumped only if a key is removed or its meaning changes; new keys may be
+/// added to the end of `PORCELAIN_KEYS` without a bump
+pub const PORCELAIN_VERSION: u32 = 1;
+
+/// Keys of `--output porcelain` lines in the order they're printed, each a
+/// path into the `json` fields with `.` between levels
+const PORCELAIN_KEYS: &[&str] = &[
+    "vcs",
+    "work_tree",
+    "path",
+    "branch",
+    "detached",
+    "bare",
+    "commit",
+    "change_id",
+    "worktree",
+    "upstream",
+    "ahead",
+    "behind",
+    "fetch_age",
+    "staged.modified",
+    "staged.added",
+    "staged.deleted",
+    "staged.renamed",
+    "staged.copied",
+    "staged.total",
+    "unstaged.modified",
+    "unstaged.added",
+    "unstaged.deleted",
+    "unstaged.renamed",
+    "unstaged.copied",
+    "unstaged.total",
+    "untracked",
+    "unmerged",
+    "conflicts.both_modified",
+    "conflicts.both_added",
+    "conflicts.deleted_by_us",
+    "conflicts.deleted_by_them",
+    "operation",
+    "stashed",
+    "stash_age",
+    "insertions",
+    "deletions",
+    "staged_insertions",
+    "staged_deletions",
+    "dirty",
+    "partial_clone",
+    "sparse",
+    "base.branch",
+    "base.ahead",
+    "base.behind",
+    "push.branch",
+    "push.ahead",
+    "push.behind",
+    "timed_out",
+    "remote_url",
+];
+
+/// Write `format_version`, then a `key value` line for each of
+/// `PORCELAIN_KEYS` that has a value, and `stale` listing fields that ran out
+/// of time. Backslashes and newlines in values are escaped as `\\` and `\n`
+fn write_porcelain<W: Write>(json: &serde_json::Value, skipped: &[&str], buf: &mut W) -> Result {
+    writeln!(buf, "format_version {}", PORCELAIN_VERSION)?;
+    for key in PORCELAIN_KEYS {
+        let value = match json.pointer(&format!("/{}", key.replace('.', "/"))) {
+            None | Some(serde_json::Value::Null) => continue,
+            Some(serde_json::Value::String(s)) => s.replace('\\', "\\\\").replace('\n', "\\n"),
+            Some(v) => v.to_string(),
+        };
+        writeln!(buf, "{} {}", key, value)?;
+    }
+    writeln!(buf, "stale {}", skipped.join(","))?;
+    Ok(())
+}
+
+/// Print all status fields as `key value` lines in the stable schema of
+/// `PORCELAIN_VERSION`, ignoring the format string, ex: "branch main"
+fn porcelain_output<W: Write>(ri: Repo, base: Option<&str>, buf: &mut W) -> Result {
+    let (json, skipped) = status_json(ri, base)?;
+    write_porcelain(&json, &skipped, buf)
+}
+
+/// Format count with singular or plural noun, ex: "1 stash", "2 stashes"
+fn count_words(n: u32, one: &str, many: &str) -> String {
+    format!("{} {}", n, if n == 1 { one } else { many })
+}
+
+/// Print format string tokens described in words, for screen readers
+fn plain_verbose_output<W: Write>(ri: &Repo, options: &Options, buf: &mut W) -> Result {
+    let mut words = vec![];
+    for c in unique_tokens(&options.format)? {
+        token_words(ri, options, c, &mut words)?;
+    }
+    if ri.timed_out {
+        words.push("timed out".to_string());
+    }
+    writeln!(buf, "{}", words.join(", "))?;
+    Ok(())
+}
+
+/// Tokens of format string in order of first use, without `%%`
+fn unique_tokens(format: &str) -> Result<Vec<char>> {
+    let mut seen = vec![];
+    for c in format::tokens(&format::parse(format)?) {
+        if c != '%' && !seen.contains(&c) {
+            seen.push(c);
+        }
+    }
+    Ok(seen)
+}
+
+/// Add words describing what token `c` shows for the repo; none if it renders
+/// empty
+fn token_words(ri: &Repo, options: &Options, c: char, words: &mut Vec<String>) -> Result {
+    match c {
+        'a' => {
+            if ri.ahead > 0 {
+                words.push(format!("{} ahead", ri.ahead));
+            }
+            if ri.behind > 0 {
+                words.push(format!("{} behind", ri.behind));
+            }
+        }
+        'A' => {
+            if let Some(base) = &ri.base {
+                if ri.base_ahead > 0 {
+                    words.push(format!("{} ahead of {}", ri.base_ahead, base));
+                }
+                if ri.base_behind > 0 {
+                    words.push(format!("{} behind {}", ri.base_behind, base));
+                }
+            }
+        }
+        'M' => {
+            for rb in ri.remote_branches.iter().flatten() {
+                words.push(match (rb.ahead, rb.behind) {
+                    (0, 0) => format!("{} in sync", rb.remote),
+                    (ahead, 0) => format!("{} ahead of {}", ahead, rb.remote),
+                    (0, behind) => format!("{} behind {}", behind, rb.remote),
+                    (ahead, behind) => {
+                        format!("{} ahead of and {} behind {}", ahead, behind, rb.remote)
+                    }
+                });
+            }
+        }
+        'B' => {
+            if let Some(push) = &ri.push {
+                if ri.push_ahead > 0 {
+                    words.push(format!("{} ahead of push {}", ri.push_ahead, push));
+                }
+                if ri.push_behind > 0 {
+                    words.push(format!("{} behind push {}", ri.push_behind, push));
+                }
+            }
+        }
+        'b' => {
+            if let Some(branch) = &ri.branch {
+                words.push(format!("branch {}", branch));
+            }
+            if ri.is_protected(&options.protected_branches) {
+                words.push("protected".to_string());
+            }
+            if ri.bare {
+                words.push("bare repo".to_string());
+            }
+        }
+        'c' => {
+            if let Some(commit) = &ri.commit {
+                words.push(format!("commit {}", commit.get(..7).unwrap_or(commit)));
+            }
+        }
+        'd' if ri.unstaged.has_changed() => {
+            words.push(format!(
+                "{} added, {} removed",
+                count_words(ri.insertions, "line", "lines"),
+                ri.deletions
+            ));
+        }
+        'I' if ri.staged.has_changed() => {
+            words.push(format!(
+                "{} staged, {} removed",
+                count_words(ri.staged_insertions, "line", "lines"),
+                ri.staged_deletions
+            ));
+        }
+        'D' => {
+            if let (true, Some(commit)) = (ri.detached, &ri.commit) {
+                words.push(format!("detached at {}", commit.get(..7).unwrap_or(commit)));
+            }
+        }
+        'e' if ri.nested => words.push("nested repo".to_string()),
+        'h' => {
+            if let Some(url) = &ri.remote {
+                words.push(format!("remote {}", url));
+            }
+        }
+        'i' => {
+            if let Some(email) = &ri.user_email {
+                words.push(format!("email {}", email));
+            }
+        }
+        'j' => {
+            if let Some(id) = &ri.change_id {
+                words.push(format!("change {}", id));
+            }
+        }
+        'k' | 'K' => match (c, ri.local_branches, ri.merged_branches) {
+            ('k', Some(n), _) => words.push(count_words(n, "local branch", "local branches")),
+            ('K', _, Some(n)) if n > 0 => words.push(format!("{} merged", n)),
+            _ => (),
+        },
+        'l' if ri.promisor == Some(true) => words.push("partial clone".to_string()),
+        'S' if ri.sparse == Some(true) => words.push("sparse checkout".to_string()),
+        'm' if ri.unstaged.has_changed() && ri.fast_dirty => words.push("modified".to_string()),
+        'm' if ri.unstaged.has_changed() => {
+            words.push(format!("{} modified", ri.unstaged.change_ct()))
+        }
+        'n' => words.push(ri.vcs.name().to_string()),
+        'P' => {
+            if let Some(path) = ri.work_tree_path()?.filter(|p| !p.as_os_str().is_empty()) {
+                words.push(format!("path {}", path.display()));
+            }
+        }
+        'R' => {
+            if let Some(name) = ri.work_tree.as_ref().and_then(|root| root.file_name()) {
+                words.push(format!("repo {}", name.to_string_lossy()));
+            }
+        }
+        'o' => {
+            if let Some(op) = &ri.operation {
+                words.push(op.to_lowercase());
+            }
+        }
+        'r' => {
+            if let Some(upstream) = &ri.upstream {
+                words.push(format!("upstream {}", upstream));
+            }
+        }
+        's' if ri.staged.has_changed() && ri.fast_dirty => words.push("staged".to_string()),
+        's' if ri.staged.has_changed() => words.push(format!("{} staged", ri.staged.change_ct())),
+        't' => match (ri.stashed.unwrap_or(0), ri.stash_age) {
+            (0, _) => (),
+            (n, Some(age)) => words.push(format!(
+                "{} (newest {} old)",
+                count_words(n, "stash", "stashes"),
+                fmt_age(age)
+            )),
+            (n, None) => words.push(count_words(n, "stash", "stashes")),
+        },
+        'u' if ri.untracked > 0 => words.push(format!("{} untracked", ri.untracked)),
+        'x' => {
+            let states = [
+                (ri.unstaged.has_changed() || ri.unmerged > 0, "unstaged"),
+                (ri.staged.has_changed(), "staged"),
+                (ri.stashed.unwrap_or(0) > 0, "stashed"),
+                (ri.untracked > 0, "untracked"),
+            ];
+            let dirty = states
+                .iter()
+                .filter(|(on, _)| *on)
+                .map(|(_, state)| *state)
+                .collect::<Vec<_>>();
+            if !dirty.is_empty() {
+                words.push(format!("dirty: {}", dirty.join(", ")));
+            }
+        }
+        'U' if ri.unmerged > 0 => {
+            let c = &ri.conflicts;
+            let kinds = [
+                (c.both_modified, "both modified"),
+                (c.both_added, "both added"),
+                (c.deleted_by_us, "deleted by us"),
+                (c.deleted_by_them, "deleted by them"),
+            ]
+            .iter()
+            .filter(|(n, _)| *n > 0)
+            .map(|(n, kind)| format!("{} {}", n, kind))
+            .collect::<Vec<_>>();
+            if options.unmerged_detail && !kinds.is_empty() {
+                words.push(format!("{} unmerged ({})", ri.unmerged, kinds.join(", ")));
+            } else {
+                words.push(format!("{} unmerged", ri.unmerged));
+            }
+        }
+        'T' => match (&ri.tag, ri.tag_dist) {
+            (Some(tag), Some(0)) => words.push(format!("tag {}", tag)),
+            (Some(tag), Some(dist)) => words.push(format!(
+                "tag {} plus {}",
+                tag,
+                count_words(dist, "commit", "commits")
+            )),
+            _ => (),
+        },
+        'v' => {
+            if let (Some(dist), Some(tag)) = (ri.tag_dist, &ri.tag) {
+                words.push(format!(
+                    "{} since {}",
+                    count_words(dist, "commit", "commits"),
+                    tag
+                ));
+            }
+        }
+        'f' => {
+            if let Some(age) = ri.fetch_age {
+                if age >= options.fetch_stale_hours * 3600 {
+                    words.push(format!("fetched {} ago", fmt_age(age)));
+                }
+            }
+        }
+        'p' => {
+            if let Some(pr) = &ri.pull_request {
+                words.push(match pr.checks {
+                    Checks::None => format!("pull request {}", pr.number),
+                    Checks::Pending => format!("pull request {}, checks running", pr.number),
+                    Checks::Pass => format!("pull request {}, checks passed", pr.number),
+                    Checks::Fail => format!("pull request {}, checks failed", pr.number),
+                });
+            }
+        }
+        'w' => {
+            if let Some(name) = &ri.worktree {
+                words.push(format!("worktree {}", name));
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+/// Print each token of the format string as rendered, followed by what it
+/// shows in words and the token's description from `FORMAT_STRING_USAGE`,
+/// ex: "⇡2: 2 ahead (%a, commits ahead/behind remote)"
+fn explain_output<W: Write>(ri: &Repo, options: &Options, buf: &mut W) -> Result {
+    for c in unique_tokens(&options.format)? {
+        let mut words = vec![];
+        token_words(ri, options, c, &mut words)?;
+        if words.is_empty() {
+            continue;
+        }
+        let token = Options {
+            format: format!("%{}", c),
+            max_width: None,
+            ..options.clone()
+        };
+        let mut rendered = vec![];
+        print_output(
+            ri,
+            &token,
+            &options.styles,
+            &mut encoder::Ansi,
+            &mut rendered,
+        )?;
+        write!(
+            buf,
+            "{}: {} (%{}",
+            String::from_utf8(rendered)?,
+            words.join(", "),
+            c
+        )?;
+        match token_description(c) {
+            Some(desc) => writeln!(buf, ", {})", desc)?,
+            None => writeln!(buf, ")")?,
+        }
+    }
+    if ri.timed_out {
+        writeln!(buf, "{}: git commands timed out", options.glyphs.timeout)?;
+    }
+    Ok(())
+}
+
+/// Description of token `c` from its line in `FORMAT_STRING_USAGE`, without
+/// the example, ex: "commits ahead/behind remote" for 'a'
+fn token_description(c: char) -> Option<&'static str> {
+    let prefix = format!("%{}  ", c);
+    FORMAT_STRING_USAGE.lines().find_map(|line| {
+        let desc = line.strip_prefix(prefix.as_str())?;
+        Some(
+            desc.split(", ex:")
+                .next()
+                .unwrap_or(desc)
+                .trim_end_matches(','),
+        )
+    })
+}
+
+/// Write colored prompt string
+fn prompt_output<W: Write>(ri: &Repo, options: &Options, buf: &mut W) -> Result {
+    let alert = options.alert && ri.needs_alert();
+    let styles = if alert {
+        let mut styles = StyleSet::alert();
+        styles.fit_depth(options.color_depth);
+        styles
+    } else {
+        options.styles.clone()
+    };
+    let mut encoder = options.escape.encoder();
+    let mut out = vec![];
+    print_output(ri, options, &styles, encoder.as_mut(), &mut out)?;
+    let mut out = trim_output(out, options.no_trim)?;
+    if alert {
+        out = paint_alert(&out, &styles.plain, encoder.as_mut())?;
+    }
+    debug!("{:?}", out);
+    let test = out.split_whitespace().collect::<Vec<_>>();
+    debug!("{:?}", test);
+    write!(buf, "{}", out)?;
+    Ok(())
+}
+
+/// Print format string through an encoder for a status line or editor target
+fn encoded_output<W: Write>(
+    ri: &Repo,
+    options: &Options,
+    encoder: &mut dyn OutputEncoder,
+    buf: &mut W,
+) -> Result {
+    let mut out = vec![];
+    print_output(ri, options, &options.styles, encoder, &mut out)?;
+    writeln!(buf, "{}", trim_output(out, options.no_trim)?)?;
+    Ok(())
+}
+
+/// Print paths of files in `area`, grouped under a heading for each area with
+/// changes
+pub fn list_files<W: Write>(ri: &Repo, area: ListArea, styles: &StyleSet, buf: &mut W) -> Result {
+    let groups = [
+        (
+            ListArea::Staged,
+            "staged",
+            &styles.modified_staged,
+            &ri.staged.files,
+        ),
+        (
+            ListArea::Unstaged,
+            "unstaged",
+            &styles.modified_unstaged,
+            &ri.unstaged.files,
+        ),
+        (
+            ListArea::Untracked,
+            "untracked",
+            &styles.untracked,
+            &ri.untracked_files,
+        ),
+    ];
+    for (group, name, style, files) in groups.iter() {
+        if files.is_empty() || !(area == ListArea::All || area == *group) {
+            continue;
+        }
+        writeln!(buf, "{}:", style.paint(name))?;
+        for file in files.iter() {
+            writeln!(buf, "  {}", file)?;
+        }
+    }
+    Ok(())
+}
+
+/// Write repo status in the selected output mode
+pub fn write_output<W: Write>(
+    ri: Repo,
+    mode: OutputMode,
+    options: &Options,
+    buf: &mut W,
+) -> Result {
+    match mode {
+        OutputMode::Waybar => waybar_output(&ri, options, buf),
+        OutputMode::Polybar => polybar_output(&ri, options, buf),
+        OutputMode::Pango => pango_output(&ri, options, buf),
+        OutputMode::I3blocks => i3blocks_output(&ri, options, buf),
+        OutputMode::PlainVerbose => plain_verbose_output(&ri, options, buf),
+        OutputMode::Explain => explain_output(&ri, options, buf),
+        OutputMode::Json => json_output(ri, options.base_branch.as_deref(), buf),
+        OutputMode::Kv => kv_output(ri, options.base_branch.as_deref(), buf),
+        OutputMode::FishVars => fish_vars_output(ri, options.base_branch.as_deref(), buf),
+        OutputMode::Porcelain => porcelain_output(ri, options.base_branch.as_deref(), buf),
+        OutputMode::Tmux => encoded_output(&ri, options, &mut encoder::Tmux, buf),
+        OutputMode::Vim => encoded_output(&ri, options, &mut encoder::Vim, buf),
+        OutputMode::Powerline => {
+            encoded_output(&ri, options, &mut encoder::Powerline::default(), buf)
+        }
+        OutputMode::JsonSegments => {
+            let mut out = vec![];
+            print_output(
+                &ri,
+                options,
+                &options.styles,
+                &mut encoder::JsonSegments::default(),
+                &mut out,
+            )?;
+            buf.write_all(&out)?;
+            writeln!(buf)?;
+            Ok(())
+        }
+        OutputMode::Prompt => prompt_output(&ri, options, buf),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::Vcs;
+    use pretty_assertions::assert_eq;
+    use std::{path::PathBuf, str};
+
+    #[test]
+    fn shell_escapes() {
+        let out = "\u{1b}[34m\u{1b}[1mmain\u{1b}[0m \u{1b}[31mΔ1\u{1b}[0m";
+        assert_eq!(
+            escape_prompt(out, Escape::Zsh),
+            "%{\u{1b}[34m\u{1b}[1m%}main%{\u{1b}[0m%} %{\u{1b}[31m%}Δ1%{\u{1b}[0m%}"
+        );
+        assert_eq!(
+            escape_prompt("\u{1b}[31mx\u{1b}[0m", Escape::Bash),
+            "\\[\u{1b}[31m\\]x\\[\u{1b}[0m\\]"
+        );
+        assert_eq!(escape_prompt("50%", Escape::Zsh), "50%%");
+        assert_eq!(
+            escape_prompt("\u{1b}[31m$(id)\u{1b}[0m", Escape::Bash),
+            "\\[\u{1b}[31m\\]\\\\$(id)\\[\u{1b}[0m\\]"
+        );
+        assert_eq!(escape_prompt(out, Escape::None), out);
+    }
+
+    #[test]
+    fn color_choice() {
+        assert!(ColorChoice::Auto.resolve(None, None, true));
+        assert!(!ColorChoice::Auto.resolve(None, None, false));
+        assert!(!ColorChoice::Auto.resolve(Some("1"), Some("1"), true));
+        assert!(ColorChoice::Auto.resolve(Some(""), Some("1"), false));
+        assert!(!ColorChoice::Auto.resolve(None, Some("0"), false));
+        assert!(ColorChoice::Always.resolve(Some("1"), None, false));
+        assert!(!ColorChoice::Never.resolve(None, Some("1"), true));
+    }
+
+    #[test]
+    fn async_frames() -> Result {
+        let mut buf = Vec::new();
+        write_async_frame("42", 0, b"main \xce\x941", &mut buf)?;
+        write_async_frame("43", 2, b"", &mut buf)?;
+        assert_eq!(str::from_utf8(&buf)?, "42\t0\tmain Δ1\x0043\t2\t\0");
+        assert!(check_async_token("a\tb").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn kv_lines() -> Result {
+        let json = serde_json::json!({ "path": "it's here", "staged": { "total": 2 } });
+        let mut buf = Vec::new();
+        write_kv("path", &json["path"], &mut buf)?;
+        write_kv("staged", &json["staged"], &mut buf)?;
+        write_kv("upstream", &serde_json::Value::Null, &mut buf)?;
+        assert_eq!(
+            str::from_utf8(&buf)?,
+            "path='it'\\''s here'\nstaged_total=2\nupstream=\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn pango_escapes() {
+        assert_eq!(pango_escape("a&b <c>"), "a&amp;b &lt;c&gt;");
+    }
+
+    #[test]
+    fn fish_vars() -> Result {
+        let json = serde_json::json!({ "path": "it's here", "staged": { "total": 2 } });
+        let mut buf = Vec::new();
+        write_fish_var("path", &json["path"], &mut buf)?;
+        write_fish_var("staged", &json["staged"], &mut buf)?;
+        write_fish_var("upstream", &serde_json::Value::Null, &mut buf)?;
+        write_fish_var("stale", &serde_json::json!(["a", "b"]), &mut buf)?;
+        assert_eq!(
+            str::from_utf8(&buf)?,
+            "set -g gitpr_path 'it\\'s here'\nset -g gitpr_staged_total 2\nset -g \
+             gitpr_upstream\nset -g gitpr_stale 'a' 'b'\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn batch_entries() -> Result {
+        let mut buf = Vec::new();
+        write_batch_entry("a", "main ⇡1", OutputMode::Prompt, &mut buf)?;
+        write_batch_entry("b", "", OutputMode::Prompt, &mut buf)?;
+        write_batch_entry("c", "ahead=1\nbehind=0\n", OutputMode::Kv, &mut buf)?;
+        write_batch_entry("d", "{\"ahead\":1}\n", OutputMode::Json, &mut buf)?;
+        assert_eq!(
+            str::from_utf8(&buf)?,
+            "a\tmain ⇡1\nc\tahead=1\nc\tbehind=0\n{\"ahead\":1,\"dir\":\"d\"}\n"
+        );
+
+        let mut buf = Vec::new();
+        let e = write_batch_entry("e", "{\"ahead\":", OutputMode::Json, &mut buf).unwrap_err();
+        assert!(e.is::<serde_json::Error>());
+        assert!(buf.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn batch_summary() -> Result {
+        let summary = BatchSummary {
+            clean:  2,
+            dirty:  1,
+            errors: 0,
+        };
+        let mut buf = Vec::new();
+        summary.write(false, &mut buf)?;
+        summary.write(true, &mut buf)?;
+        assert_eq!(
+            str::from_utf8(&buf)?,
+            "3 repos: 2 clean, 1 dirty, 0 \
+             failed\n{\"summary\":{\"clean\":2,\"dirty\":1,\"errors\":0,\"repos\":3}}\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn porcelain_schema() -> Result {
+        // Changing these breaks scripts; see `PORCELAIN_VERSION`
+        assert_eq!(PORCELAIN_VERSION, 1);
+        assert_eq!(
+            PORCELAIN_KEYS.join(" "),
+            "vcs work_tree path branch detached bare commit change_id worktree upstream ahead \
+             behind fetch_age staged.modified staged.added staged.deleted staged.renamed \
+             staged.copied staged.total unstaged.modified unstaged.added unstaged.deleted \
+             unstaged.renamed unstaged.copied unstaged.total untracked unmerged \
+             conflicts.both_modified conflicts.both_added conflicts.deleted_by_us \
+             conflicts.deleted_by_them operation stashed stash_age insertions deletions \
+             staged_insertions staged_deletions dirty partial_clone sparse base.branch base.ahead \
+             base.behind push.branch push.ahead push.behind timed_out remote_url"
+        );
+        let json = serde_json::json!({
+            "vcs": "git",
+            "path": "a\\b\nc",
+            "branch": "main",
+            "upstream": null,
+            "ahead": 2,
+            "staged": { "modified": 1, "total": 1 },
+            "dirty": true,
+        });
+        let mut buf = Vec::new();
+        write_porcelain(&json, &["stashed", "stash_age"], &mut buf)?;
+        assert_eq!(
+            str::from_utf8(&buf)?,
+            "format_version 1\nvcs git\npath a\\\\b\\nc\nbranch main\nahead 2\nstaged.modified \
+             1\nstaged.total 1\ndirty true\nstale stashed,stash_age\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn remote_url_field() -> Result {
+        let ri = Repo {
+            vcs: Vcs::Hg,
+            files_only: true,
+            work_tree: Some(PathBuf::from("/src/repo")),
+            remote: Some("github.com/org/repo".to_string()),
+            ..Repo::default()
+        };
+        let (json, _) = status_json(ri, None)?;
+        assert_eq!(json["remote_url"], "github.com/org/repo");
+        let mut buf = Vec::new();
+        write_porcelain(&json, &[], &mut buf)?;
+        assert!(str::from_utf8(&buf)?.contains("\nremote_url github.com/org/repo\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn render_collected() -> Result {
+        let options = Options {
+            format:             "%b %a %t %o %r".to_string(),
+            indicators_only:    false,
+            divergence_age:     true,
+            no_trim:            false,
+            max_width:          None,
+            alert:              false,
+            unmerged_detail:    false,
+            stash_warn_days:    7,
+            fetch_stale_hours:  24,
+            diff_style:         DiffStyle::Lines,
+            styles:             StyleSet::default(),
+            color_depth:        ColorDepth::Ansi256,
+            glyphs:             GlyphSet::default(),
+            escape:             Escape::None,
+            upstream:           UpstreamRules::default(),
+            base_branch:        None,
+            expected_email:     None,
+            protected_branches: vec![],
+        };
+        // Nothing collected for `%o` and `%r`, and no git to ask
+        let ri = Repo {
+            branch: Some("main".into()),
+            ahead: 1,
+            behind: 2,
+            behind_age: Some(3 * 86400),
+            stashed: Some(1),
+            ..Default::default()
+        };
+        let mut out = vec![];
+        print_output(&ri, &options, &options.styles, &mut encoder::Ansi, &mut out)?;
+        // Unset styles still reset
+        assert_eq!(
+            trim_output(out, false)?.replace("\u{1b}[0m", ""),
+            "main ⇡1⇣2 (3d) $1"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn simple_clean() -> Result {
+        const CLEAN: &str = "## master...origin/master";
+        let expected = "\u{1b}[38;5;14m(master)\u{1b}[0m";
+
+        let mut buf = Vec::new();
+        assert!(!simple_output(CLEAN, &mut buf)?);
+        let result = str::from_utf8(&buf)?;
+        assert_eq!(result, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn simple_dirty() -> Result {
+        const DIRTY: &str = "## master...origin/master
+  M src/main.rs
+ ?? src/tests.rs";
+        let expected = "\u{1b}[38;5;14m(master)\u{1b}[31m*\u{1b}[0m";
+
+        let mut buf = Vec::new();
+        assert!(simple_output(DIRTY, &mut buf)?);
+        let result = str::from_utf8(&buf)?;
+        assert_eq!(result, expected);
+        Ok(())
+    }
+}
@@ -0,0 +1,75 @@
+//! Time the status pipeline over repeated runs
+use crate::{run, timings::Timer, Arg};
+use gitpr::Result;
+use std::{
+    io::{self, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+/// Run status pipeline `iterations` times, then print a timing histogram and
+/// write a flamegraph if built with the `pprof` feature
+pub fn profile<W: Write>(args: &Arg, iterations: u32, flamegraph: &Path, buf: &mut W) -> Result {
+    #[cfg(feature = "pprof")]
+    let guard = pprof::ProfilerGuard::new(1000)?;
+    let mut times = Vec::with_capacity(iterations as usize);
+    for _ in 0..iterations {
+        let start = Instant::now();
+        run(args, &mut io::sink(), &mut Timer::new())?;
+        times.push(start.elapsed());
+    }
+    #[cfg(feature = "pprof")]
+    {
+        let report = guard.report().build()?;
+        report.flamegraph(std::fs::File::create(flamegraph)?)?;
+        writeln!(buf, "wrote flamegraph to {}", flamegraph.display())?;
+    }
+    #[cfg(not(feature = "pprof"))]
+    writeln!(
+        buf,
+        "built without `pprof` feature; not writing {}",
+        flamegraph.display()
+    )?;
+    histogram(&mut times, buf)
+}
+
+/// Print summary statistics and a text histogram of run times
+fn histogram<W: Write>(times: &mut [Duration], buf: &mut W) -> Result {
+    const BUCKETS: usize = 10;
+    const WIDTH: usize = 40;
+    if times.is_empty() {
+        return Ok(());
+    }
+    times.sort();
+    let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+    let pct = |p: usize| ms(times[(times.len() - 1) * p / 100]);
+    let (min, max) = (pct(0), pct(100));
+    writeln!(
+        buf,
+        "{} runs: min {:.2}ms, median {:.2}ms, p90 {:.2}ms, max {:.2}ms",
+        times.len(),
+        min,
+        pct(50),
+        pct(90),
+        max
+    )?;
+    let step = ((max - min) / BUCKETS as f64).max(f64::EPSILON);
+    let mut counts = [0; BUCKETS];
+    for t in times.iter() {
+        counts[(((ms(*t) - min) / step) as usize).min(BUCKETS - 1)] += 1;
+    }
+    let peak = counts.iter().copied().max().unwrap_or(1);
+    for (i, n) in counts.iter().enumerate() {
+        let lo = min + step * i as f64;
+        writeln!(
+            buf,
+            "{:>9.2} - {:>9.2} ms | {:<width$} {}",
+            lo,
+            lo + step,
+            "#".repeat(n * WIDTH / peak),
+            n,
+            width = WIDTH
+        )?;
+    }
+    Ok(())
+}
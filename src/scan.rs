@@ -0,0 +1,550 @@
+//! Find repos below a dir and summarize their status, for `gitpr scan`
+use crate::Arg;
+use gitpr::{
+    output::BatchSummary,
+    repo::{
+        common_dir, find_git_dir, is_bare_dir, metadata_fingerprint, path_excluded,
+        repo_cache_file, write_cache, GatherOptions, Vcs,
+    },
+    vcs, Repo, Result,
+};
+use log::debug;
+use std::{
+    env, fs,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use unicode_width::UnicodeWidthStr;
+
+/// Metadata dirs of version control systems, never searched
+const VCS_DIRS: &[&str] = &[".git", ".hg", ".jj"];
+
+/// Longest cached sizes are used for. Only git metadata is checked for
+/// changes, so worktree edits, ex: build output, show up once this passes
+const DU_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// Status of one repo found by `scan`
+#[derive(Debug, Default)]
+struct Row {
+    /// Path relative to the dir searched
+    path:    String,
+    vcs:     &'static str,
+    /// Branch, or short commit when detached
+    branch:  String,
+    /// Dirty state summary like `%x`, ex: "*+%"
+    dirty:   String,
+    /// Whether there are changes, untracked files or conflicts, unlike a stash
+    changed: bool,
+    ahead:   u32,
+    behind:  u32,
+    stashed: u32,
+    /// Sizes on disk, with `--du`
+    du:      Option<DiskUsage>,
+    /// Why status couldn't be read, or that `--timeout` cut it short
+    error:   Option<String>,
+}
+
+/// Apparent sizes of a repo, in bytes
+#[derive(Debug, Clone, Copy)]
+struct DiskUsage {
+    /// VCS metadata dirs, ex: `.git`
+    meta: u64,
+    /// Everything else below the repo's top dir, other than nested metadata
+    work: u64,
+}
+
+/// Search `root` for repos at most `max_depth` dirs down and print a table of
+/// their status, or one JSON object per repo. With `du`, the size of each
+/// repo's metadata and worktree is added. Returns totals of the repos found
+pub fn scan<W: Write>(
+    args: &Arg,
+    root: &Path,
+    max_depth: usize,
+    exclude: &[String],
+    json: bool,
+    du: bool,
+    buf: &mut W,
+) -> Result<BatchSummary> {
+    let root = env::current_dir()?.join(root);
+    let dirs = find_repos(&root, max_depth, exclude);
+    let sizes = if du {
+        disk_usage(&dirs)
+    } else {
+        vec![None; dirs.len()]
+    };
+    let mut rows = vec![];
+    for (dir, du) in dirs.into_iter().zip(sizes) {
+        let path = match dir.strip_prefix(&root) {
+            Ok(p) if p.as_os_str().is_empty() => ".".to_string(),
+            Ok(p) => p.to_string_lossy().into_owned(),
+            Err(_) => dir.to_string_lossy().into_owned(),
+        };
+        let row = repo_row(args, &dir).unwrap_or_else(|e| Row {
+            vcs: vcs::detect(&dir).map_or("", |d| d.vcs().name()),
+            error: Some(e.to_string()),
+            ..Default::default()
+        });
+        rows.push(Row { path, du, ..row });
+    }
+    let mut totals = BatchSummary::default();
+    for row in &rows {
+        match row {
+            Row { error: Some(_), .. } => totals.errors += 1,
+            Row { changed: true, .. } => totals.dirty += 1,
+            _ => totals.clean += 1,
+        }
+    }
+    if json {
+        for row in &rows {
+            writeln!(buf, "{}", row_json(row))?;
+        }
+    } else {
+        write_table(&rows, du, buf)?;
+    }
+    Ok(totals)
+}
+
+/// Repos at or below `root`, bare ones included, at most `max_depth` dirs down,
+/// in path order. Dirs matching an `exclude` glob aren't entered, and neither
+/// are repos, so nested repos aren't listed
+fn find_repos(root: &Path, max_depth: usize, exclude: &[String]) -> Vec<PathBuf> {
+    let mut repos = vec![];
+    walk(root, root, max_depth, exclude, &mut repos);
+    repos
+}
+
+/// Add `dir` to `repos` if it's the top of a repo or a bare repo, or else
+/// search its subdirs. Symlinks aren't followed, so links back up the tree
+/// don't loop
+fn walk(root: &Path, dir: &Path, depth: usize, exclude: &[String], repos: &mut Vec<PathBuf>) {
+    if vcs::detect(dir).is_some() || is_bare_dir(dir) {
+        repos.push(dir.to_path_buf());
+        return;
+    }
+    if depth == 0 {
+        return;
+    }
+    let mut subdirs = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_ok_and(|t| t.is_dir()))
+            .map(|e| e.path())
+            .collect::<Vec<_>>(),
+        Err(_) => return,
+    };
+    subdirs.sort();
+    for sub in subdirs {
+        let name = sub.file_name().unwrap_or_default().to_string_lossy();
+        // Globs match paths from `root` with `/`, ending in `/` for dirs
+        let rel = sub.strip_prefix(root).unwrap_or(&sub);
+        let rel = format!("{}/", rel.to_string_lossy().replace('\\', "/"));
+        if VCS_DIRS.contains(&name.as_ref()) || exclude.iter().any(|p| path_excluded(p, &rel)) {
+            continue;
+        }
+        walk(root, &sub, depth - 1, exclude, repos);
+    }
+}
+
+/// Gather status of the repo at `dir` with the status options of `args`
+fn repo_row(args: &Arg, dir: &Path) -> Result<Row> {
+    env::set_current_dir(dir)?;
+    let deadline = args
+        .timeout
+        .map(|ms| Instant::now() + Duration::from_millis(ms));
+    let mut ri = Repo::gather(&GatherOptions {
+        backend: args.backend,
+        untracked: args.untracked.resolve(dir),
+        renames: args.renames,
+        nul: !args.line_status,
+        fast_dirty: args.fast_dirty,
+        tokens: &[],
+        deadline,
+    })?;
+    if ri.vcs == Vcs::Git {
+        let r = ri.git_stash_count();
+        ri.allow_timeout(r)?;
+    }
+    let branch = match (&ri.branch, &ri.commit) {
+        (Some(branch), _) => branch.clone(),
+        (None, Some(commit)) => format!("@{}", commit.get(..7).unwrap_or(commit)),
+        (None, None) => String::new(),
+    };
+    Ok(Row {
+        vcs: ri.vcs.name(),
+        branch,
+        dirty: ri.dirty_summary(),
+        changed: ri.is_dirty() || ri.unmerged > 0,
+        ahead: ri.ahead,
+        behind: ri.behind,
+        stashed: ri.stashed.unwrap_or(0),
+        error: if ri.timed_out {
+            Some("timed out".to_string())
+        } else {
+            None
+        },
+        ..Default::default()
+    })
+}
+
+/// Sizes of each of `dirs`, measured in parallel since a big worktree can take
+/// seconds to walk
+fn disk_usage(dirs: &[PathBuf]) -> Vec<Option<DiskUsage>> {
+    let threads = thread::available_parallelism().map_or(4, |n| n.get());
+    let next = AtomicUsize::new(0);
+    let sizes = Mutex::new(vec![None; dirs.len()]);
+    thread::scope(|s| {
+        for _ in 0..threads.min(dirs.len()) {
+            s.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let dir = match dirs.get(i) {
+                    Some(dir) => dir,
+                    None => break,
+                };
+                let du = repo_usage(dir);
+                if let Ok(mut sizes) = sizes.lock() {
+                    sizes[i] = du;
+                }
+            });
+        }
+    });
+    sizes.into_inner().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Sizes of the repo at `dir`, cached until its git metadata changes or the
+/// sizes are `DU_MAX_AGE` old. All of a bare repo is metadata
+fn repo_usage(dir: &Path) -> Option<DiskUsage> {
+    let bare = is_bare_dir(dir);
+    let meta_dirs = if bare {
+        vec![dir.to_path_buf()]
+    } else {
+        VCS_DIRS
+            .iter()
+            .map(|d| dir.join(d))
+            .filter(|d| d.exists())
+            // A `.git` file points to the git dir of a linked worktree
+            .filter_map(|d| if d.is_file() { find_git_dir(dir) } else { Some(d) })
+            .collect::<Vec<_>>()
+    };
+    if meta_dirs.is_empty() {
+        return None;
+    }
+    let git_dir = if bare {
+        Some(dir.to_path_buf())
+    } else {
+        find_git_dir(dir)
+    };
+    let stamp = git_dir.map_or_else(String::new, |d| usage_stamp(&d));
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+    let file = repo_cache_file(dir, "du");
+    if let Some(du) = file
+        .as_deref()
+        .and_then(|f| fs::read_to_string(f).ok())
+        .and_then(|text| cached_usage(&text, &stamp, now))
+    {
+        return Some(du);
+    }
+    let du = DiskUsage {
+        meta: meta_dirs.iter().map(|d| dir_size(d, false)).sum(),
+        work: if bare { 0 } else { dir_size(dir, true) },
+    };
+    if let Some(file) = file {
+        let text = format!("{} {} {} {}", now, du.meta, du.work, stamp);
+        if let Err(e) = write_cache(&file, &text) {
+            debug!("writing {}: {}", file.display(), e);
+        }
+    }
+    Some(du)
+}
+
+/// Modification times of git metadata that change when the repo's size does,
+/// as text to store with cached sizes
+fn usage_stamp(git_dir: &Path) -> String {
+    let mtime = |p: &Path| fs::metadata(p).and_then(|m| m.modified()).ok();
+    let objects = common_dir(git_dir).join("objects");
+    let mut times = metadata_fingerprint(git_dir);
+    times.push(mtime(&objects));
+    times.push(mtime(&objects.join("pack")));
+    times
+        .iter()
+        .map(
+            |t| match t.and_then(|t| t.duration_since(UNIX_EPOCH).ok()) {
+                Some(d) => d.as_nanos().to_string(),
+                None => "-".to_string(),
+            },
+        )
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Sizes in cache file `text` of the form "<written> <meta> <work> <stamp>",
+/// if `stamp` matches and they were written less than `DU_MAX_AGE` before `now`
+fn cached_usage(text: &str, stamp: &str, now: u64) -> Option<DiskUsage> {
+    let mut parts = text.splitn(4, ' ');
+    let written = parts.next()?.parse::<u64>().ok()?;
+    let du = DiskUsage {
+        meta: parts.next()?.parse().ok()?,
+        work: parts.next()?.parse().ok()?,
+    };
+    if parts.next()? != stamp || now.saturating_sub(written) >= DU_MAX_AGE.as_secs() {
+        return None;
+    }
+    Some(du)
+}
+
+/// Total size of the files below `root`, skipping VCS metadata dirs if
+/// `skip_meta`. Symlinks count as themselves and aren't followed
+fn dir_size(root: &Path, skip_meta: bool) -> u64 {
+    let mut size = 0;
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir).into_iter().flatten().flatten() {
+            match entry.file_type() {
+                Ok(t) if t.is_dir() => {
+                    let name = entry.file_name();
+                    if !(skip_meta && VCS_DIRS.iter().any(|d| name == *d)) {
+                        dirs.push(entry.path());
+                    }
+                }
+                Ok(_) => size += entry.metadata().map_or(0, |m| m.len()),
+                Err(_) => {}
+            }
+        }
+    }
+    size
+}
+
+/// `bytes` in binary units, with a decimal below 10, ex: "816K", "4.2M"
+fn human_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "K", "M", "G", "T"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    // Compared rounded, so 1023.96K shows as "1.0M", not "1024K"
+    while size.round() >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{}B", bytes)
+    } else if (size * 10.0).round() < 100.0 {
+        format!("{:.1}{}", size, UNITS[unit])
+    } else {
+        format!("{:.0}{}", size, UNITS[unit])
+    }
+}
+
+/// Row as a JSON object
+fn row_json(row: &Row) -> serde_json::Value {
+    serde_json::json!({
+        "path": row.path,
+        "vcs": row.vcs,
+        "branch": row.branch,
+        "dirty": row.dirty,
+        "ahead": row.ahead,
+        "behind": row.behind,
+        "stashed": row.stashed,
+        "meta_size": row.du.map(|du| du.meta),
+        "worktree_size": row.du.map(|du| du.work),
+        "error": row.error,
+    })
+}
+
+/// Write rows as a table with a header, columns padded to line up, with size
+/// columns if `du`
+fn write_table<W: Write>(rows: &[Row], du: bool, buf: &mut W) -> Result {
+    let mut header = vec!["REPO", "BRANCH", "DIRTY", "AHEAD", "BEHIND", "STASH"];
+    if du {
+        header.extend(["META", "WORKTREE"]);
+    }
+    let count = |n: u32| if n == 0 { String::new() } else { n.to_string() };
+    let mut lines = vec![header.iter().map(|h| h.to_string()).collect::<Vec<_>>()];
+    for row in rows {
+        lines.push(match &row.error {
+            Some(e) => vec![row.path.clone(), format!("({})", e)],
+            None => {
+                let mut line = vec![
+                    row.path.clone(),
+                    row.branch.clone(),
+                    row.dirty.clone(),
+                    count(row.ahead),
+                    count(row.behind),
+                    count(row.stashed),
+                ];
+                if du {
+                    line.extend(match row.du {
+                        Some(du) => [human_size(du.meta), human_size(du.work)],
+                        None => Default::default(),
+                    });
+                }
+                line
+            }
+        });
+    }
+    // Errors run past the columns instead of widening them
+    let mut widths = vec![0; header.len()];
+    for line in lines.iter().filter(|l| l.len() == header.len()) {
+        for (w, cell) in widths.iter_mut().zip(line) {
+            *w = (*w).max(cell.width());
+        }
+    }
+    for line in &lines {
+        let cells = line
+            .iter()
+            .zip(&widths)
+            .map(|(cell, w)| format!("{}{}", cell, " ".repeat(w.saturating_sub(cell.width()))))
+            .collect::<Vec<_>>();
+        writeln!(buf, "{}", cells.join("  ").trim_end())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::process;
+
+    /// Empty dir named for `test`, removed first if a previous run left it
+    fn temp_dir(test: &str) -> Result<PathBuf> {
+        let dir = env::temp_dir().join(format!("gitpr-{}-{}", test, process::id()));
+        if dir.exists() {
+            fs::remove_dir_all(&dir)?;
+        }
+        fs::create_dir_all(&dir)?;
+        Ok(dir)
+    }
+
+    /// Write `size` bytes to `path`, creating its parent dirs
+    fn write_file(path: &Path, size: usize) -> Result {
+        fs::create_dir_all(path.parent().unwrap_or(path))?;
+        fs::write(path, vec![b'x'; size])?;
+        Ok(())
+    }
+
+    #[test]
+    fn human_sizes() {
+        for (bytes, size) in &[
+            (0, "0B"),
+            (1023, "1023B"),
+            (1024, "1.0K"),
+            (1536, "1.5K"),
+            (10 * 1024 - 52, "9.9K"),
+            (10 * 1024 - 1, "10K"),
+            (10 * 1024, "10K"),
+            (1023 * 1024, "1023K"),
+            (1024 * 1024 - 1, "1.0M"),
+            (1024 * 1024, "1.0M"),
+            (5 << 40, "5.0T"),
+            (2048 << 40, "2048T"),
+        ] {
+            assert_eq!(human_size(*bytes), *size, "{} bytes", bytes);
+        }
+    }
+
+    #[test]
+    fn usage_cache() {
+        let now = 1_000_000;
+        let text = format!("{} 300 4000 1 2 -", now - 60);
+        let du = cached_usage(&text, "1 2 -", now).map(|du| (du.meta, du.work));
+        assert_eq!(du, Some((300, 4000)));
+        // Git metadata changed since
+        assert!(cached_usage(&text, "1 3 -", now).is_none());
+        // Too old, so worktree changes show up
+        assert!(cached_usage(&text, "1 2 -", now + DU_MAX_AGE.as_secs()).is_none());
+        assert!(cached_usage("garbage", "", now).is_none());
+    }
+
+    #[test]
+    fn repo_sizes() -> Result {
+        let dir = temp_dir("disk-usage")?;
+        let repo = dir.join("repo");
+        write_file(&repo.join(".git/objects/ab/cdef"), 100)?;
+        write_file(&repo.join(".git/HEAD"), 10)?;
+        write_file(&repo.join("src/main.rs"), 20)?;
+        // Metadata of nested repos is neither the outer repo's nor its worktree
+        write_file(&repo.join("vendor/dep/.hg/store"), 1000)?;
+        write_file(&repo.join("vendor/dep/lib.rs"), 3)?;
+        let plain = dir.join("plain");
+        write_file(&plain.join("notes.txt"), 5)?;
+        let cache = repo_cache_file(&repo, "du");
+        if let Some(file) = &cache {
+            let _ = fs::remove_file(file);
+        }
+
+        let sizes = disk_usage(&[repo.clone(), plain, dir.join("missing")])
+            .iter()
+            .map(|du| du.map(|du| (du.meta, du.work)))
+            .collect::<Vec<_>>();
+        // Worktree edits alone keep the cached sizes
+        write_file(&repo.join("src/lib.rs"), 50)?;
+        let cached = repo_usage(&repo).map(|du| (du.meta, du.work));
+        write_file(&repo.join(".git/objects/12/3456"), 7)?;
+        let changed = repo_usage(&repo).map(|du| (du.meta, du.work));
+        if let Some(file) = &cache {
+            let _ = fs::remove_file(file);
+        }
+        fs::remove_dir_all(&dir)?;
+        assert_eq!(sizes, [Some((110, 23)), None, None]);
+        assert_eq!(cached, Some((110, 23)));
+        assert_eq!(changed, Some((117, 73)));
+        Ok(())
+    }
+
+    #[test]
+    fn repo_search() -> Result {
+        let root = temp_dir("find-repos")?;
+        let git_dir = |dir: &Path| -> Result {
+            write_file(&dir.join("HEAD"), 0)?;
+            fs::create_dir_all(dir.join("objects"))?;
+            fs::create_dir_all(dir.join("refs"))?;
+            Ok(())
+        };
+        git_dir(&root.join("app/.git"))?;
+        // Neither nested repos nor git dirs of submodules are listed
+        git_dir(&root.join("app/.git/modules/lib"))?;
+        git_dir(&root.join("app/vendor/lib/.git"))?;
+        git_dir(&root.join("mirrors/site.git"))?;
+        fs::create_dir_all(root.join("src/hg/proj/.hg"))?;
+        fs::create_dir_all(root.join("src/jj/.jj"))?;
+        // A `.git` file, as in a linked worktree
+        write_file(&root.join("wt/.git"), 0)?;
+        git_dir(&root.join("a/b/c/deep/.git"))?;
+        git_dir(&root.join("node_modules/pkg/.git"))?;
+        fs::create_dir_all(root.join("plain/dir"))?;
+
+        let found = |depth, exclude: &[&str]| {
+            let exclude = exclude.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+            find_repos(&root, depth, &exclude)
+                .iter()
+                .map(|d| {
+                    d.strip_prefix(&root)
+                        .unwrap_or(d)
+                        .to_string_lossy()
+                        .to_string()
+                })
+                .collect::<Vec<_>>()
+        };
+        let shallow = found(1, &[]);
+        let default = found(3, &["node_modules/"]);
+        let deep = found(4, &["node_modules/", "src/hg/"]);
+        let at_repo = find_repos(&root.join("app"), 3, &[]);
+        fs::remove_dir_all(&root)?;
+
+        assert_eq!(shallow, ["app", "wt"]);
+        assert_eq!(
+            default,
+            ["app", "mirrors/site.git", "src/hg/proj", "src/jj", "wt"]
+        );
+        assert_eq!(
+            deep,
+            ["a/b/c/deep", "app", "mirrors/site.git", "src/jj", "wt"]
+        );
+        assert_eq!(at_repo, [root.join("app")]);
+        Ok(())
+    }
+}
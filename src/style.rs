@@ -0,0 +1,703 @@
+//! Color styles for prompt elements
+use crate::Result;
+use anyhow::format_err;
+use std::str::FromStr;
+use writecolor::{Color, Color::*, Style};
+
+/// Element names accepted by `--style`, in `StyleSet` field order
+pub const STYLE_ELEMENTS: &[&str] = &[
+    "plain",
+    "ahead_behind",
+    "bare",
+    "branch",
+    "branch_protected",
+    "branch_glyph",
+    "branch_count",
+    "change_id",
+    "commit",
+    "detached",
+    "diff",
+    "dirty",
+    "fetch_age",
+    "identity",
+    "identity_mismatch",
+    "nested",
+    "operation",
+    "partial_clone",
+    "pull_request",
+    "remote_url",
+    "repo_name",
+    "repo_path",
+    "modified_unstaged",
+    "modified_staged",
+    "sparse",
+    "staged_diff",
+    "stash",
+    "stash_old",
+    "tag_distance",
+    "untracked",
+    "unmerged",
+    "upstream",
+    "worktree",
+];
+
+/// Built-in themes: element styles set over `StyleSet::standard()`, like
+/// `--style` settings
+pub const THEMES: &[(&str, &[&str])] = &[
+    ("standard", &[]),
+    ("solarized", SOLARIZED),
+    ("gruvbox", GRUVBOX),
+    ("nord", NORD),
+    ("mono", MONO),
+];
+
+/// Solarized accent colors
+const SOLARIZED: &[&str] = &[
+    "bare=#b58900",
+    "branch=#268bd2",
+    "branch_protected=#fdf6e3,bg:#dc322f",
+    "commit=#002b36,bg:#859900",
+    "detached=#b58900",
+    "diff=#93a1a1",
+    "dirty=#dc322f",
+    "fetch_age=#586e75",
+    "identity=#586e75",
+    "identity_mismatch=#fdf6e3,bg:#dc322f",
+    "modified_unstaged=#dc322f",
+    "modified_staged=#cb4b16",
+    "nested=#b58900",
+    "operation=#d33682",
+    "partial_clone=#586e75",
+    "sparse=#586e75",
+    "staged_diff=#859900",
+    "repo_name=#93a1a1",
+    "repo_path=#586e75",
+    "pull_request=#6c71c4",
+    "branch_count=#586e75",
+    "change_id=#6c71c4",
+    "stash=#b58900",
+    "stash_old=#cb4b16",
+    "untracked=#586e75",
+    "unmerged=#dc322f",
+    "upstream=#2aa198",
+    "worktree=#2aa198",
+];
+
+/// Gruvbox dark palette colors
+const GRUVBOX: &[&str] = &[
+    "bare=#fabd2f",
+    "branch=#83a598",
+    "branch_protected=#fbf1c7,bg:#cc241d",
+    "commit=#282828,bg:#b8bb26",
+    "detached=#fabd2f",
+    "diff=#d5c4a1",
+    "dirty=#fb4934",
+    "fetch_age=#928374",
+    "identity=#928374",
+    "identity_mismatch=#fbf1c7,bg:#cc241d",
+    "modified_unstaged=#fb4934",
+    "modified_staged=#fe8019",
+    "nested=#fabd2f",
+    "operation=#fb4934",
+    "partial_clone=#928374",
+    "sparse=#928374",
+    "staged_diff=#b8bb26",
+    "repo_name=#ebdbb2",
+    "repo_path=#928374",
+    "pull_request=#d3869b",
+    "branch_count=#928374",
+    "change_id=#d3869b",
+    "stash=#fabd2f",
+    "stash_old=#fe8019",
+    "untracked=#928374",
+    "unmerged=#fb4934",
+    "upstream=#8ec07c",
+    "worktree=#8ec07c",
+];
+
+/// Nord frost and aurora colors
+const NORD: &[&str] = &[
+    "bare=#ebcb8b",
+    "branch=#88c0d0",
+    "branch_protected=#eceff4,bg:#bf616a",
+    "commit=#2e3440,bg:#a3be8c",
+    "detached=#ebcb8b",
+    "diff=#d8dee9",
+    "dirty=#bf616a",
+    "fetch_age=#616e88",
+    "identity=#616e88",
+    "identity_mismatch=#eceff4,bg:#bf616a",
+    "modified_unstaged=#bf616a",
+    "modified_staged=#d08770",
+    "nested=#ebcb8b",
+    "operation=#b48ead",
+    "partial_clone=#616e88",
+    "sparse=#616e88",
+    "staged_diff=#a3be8c",
+    "repo_name=#eceff4",
+    "repo_path=#616e88",
+    "pull_request=#b48ead",
+    "branch_count=#616e88",
+    "change_id=#b48ead",
+    "stash=#ebcb8b",
+    "stash_old=#d08770",
+    "untracked=#616e88",
+    "unmerged=#bf616a",
+    "upstream=#81a1c1",
+    "worktree=#8fbcbb",
+];
+
+/// Grays only, with emphasis from brightness and inverted blocks
+const MONO: &[&str] = &[
+    "bare=250",
+    "branch=255",
+    "branch_protected=232,bg:255",
+    "commit=232,bg:250",
+    "detached=255",
+    "diff=250",
+    "dirty=255",
+    "fetch_age=242",
+    "identity=242",
+    "identity_mismatch=232,bg:255",
+    "modified_unstaged=255",
+    "modified_staged=250",
+    "nested=250",
+    "operation=232,bg:255",
+    "partial_clone=242",
+    "sparse=242",
+    "staged_diff=250",
+    "repo_name=255",
+    "repo_path=242",
+    "pull_request=250",
+    "branch_count=242",
+    "change_id=250",
+    "stash=250",
+    "stash_old=255",
+    "untracked=242",
+    "unmerged=232,bg:255",
+    "upstream=246",
+    "worktree=250",
+];
+
+/// Color names in ANSI palette order
+const COLOR_NAMES: &[&str] = &[
+    "black", "red", "green", "yellow", "blue", "magenta", "cyan", "white",
+];
+
+/// Colors the terminal can show, from `--color-depth`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ColorDepth {
+    /// 8 colors and their bright variants
+    Ansi16,
+    /// 256-color palette
+    Ansi256,
+    /// 24-bit RGB
+    TrueColor,
+}
+
+impl ColorDepth {
+    /// Depth the terminal announces: 24-bit if `COLORTERM` is "truecolor" or
+    /// "24bit", `TERM` ends in "-direct" or `WT_SESSION` is set by Windows
+    /// Terminal; 16 colors for consoles like "linux" and "vt100" or a `TERM`
+    /// ending in "-color" or "-16color"; otherwise 256, the palette of the
+    /// default styles
+    pub fn detect() -> Self {
+        let var = |k| std::env::var(k).ok();
+        Self::from_env(
+            var("COLORTERM").as_deref(),
+            var("TERM").as_deref(),
+            var("WT_SESSION").is_some(),
+        )
+    }
+
+    /// Depth announced by `COLORTERM`, `TERM` and Windows Terminal
+    fn from_env(colorterm: Option<&str>, term: Option<&str>, windows_terminal: bool) -> Self {
+        let term = term.unwrap_or_default();
+        if matches!(colorterm, Some("truecolor") | Some("24bit"))
+            || term.ends_with("-direct")
+            || windows_terminal
+        {
+            ColorDepth::TrueColor
+        } else if term.contains("256color") {
+            ColorDepth::Ansi256
+        } else if matches!(term, "linux" | "vt100" | "vt220" | "ansi" | "cons25")
+            || term.ends_with("-color")
+            || term.ends_with("-16color")
+        {
+            ColorDepth::Ansi16
+        } else {
+            ColorDepth::Ansi256
+        }
+    }
+}
+
+impl FromStr for ColorDepth {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "16" => Ok(ColorDepth::Ansi16),
+            "256" => Ok(ColorDepth::Ansi256),
+            "truecolor" => Ok(ColorDepth::TrueColor),
+            _ => Err(format_err!("invalid color depth \"{}\"", s)),
+        }
+    }
+}
+
+/// Color styling for elements of prompt
+#[derive(Debug, Default, Clone)]
+pub struct StyleSet {
+    pub plain:             Style,
+    pub ahead_behind:      Style,
+    pub bare:              Style,
+    pub branch:            Style,
+    pub branch_protected:  Style,
+    pub branch_glyph:      Style,
+    pub branch_count:      Style,
+    pub change_id:         Style,
+    pub commit:            Style,
+    pub detached:          Style,
+    pub diff:              Style,
+    pub dirty:             Style,
+    pub fetch_age:         Style,
+    pub identity:          Style,
+    pub identity_mismatch: Style,
+    pub nested:            Style,
+    pub operation:         Style,
+    pub partial_clone:     Style,
+    pub pull_request:      Style,
+    pub remote_url:        Style,
+    pub repo_name:         Style,
+    pub repo_path:         Style,
+    pub modified_unstaged: Style,
+    pub modified_staged:   Style,
+    pub sparse:            Style,
+    pub staged_diff:       Style,
+    pub stash:             Style,
+    pub stash_old:         Style,
+    pub tag_distance:      Style,
+    pub untracked:         Style,
+    pub unmerged:          Style,
+    pub upstream:          Style,
+    pub worktree:          Style,
+}
+
+#[allow(dead_code)]
+impl StyleSet {
+    /// Blue ANSI color (intense)
+    const BLUE: u8 = 12;
+    /// Bold silver ANSI color
+    const BOLD_SILVER: u8 = 188;
+    /// Cyan ANSI color (intense)
+    const CYAN: u8 = 14;
+    /// Gray ANSI color
+    const GRAY: u8 = 245;
+    /// Magenta ANSI color (intense)
+    const MAGENTA: u8 = 13;
+    /// White ANSI color (intense)
+    const WHITE: u8 = 15;
+
+    /// Full format
+    pub fn standard() -> Self {
+        Self {
+            bare: Yellow.into(),
+            branch: Blue.intense(),
+            branch_protected: Fixed(Self::WHITE).on(Red),
+            commit: Black.on(Green),
+            detached: Yellow.intense(),
+            diff: Fixed(Self::BOLD_SILVER).normal(),
+            dirty: Red.into(),
+            fetch_age: Fixed(Self::GRAY).into(),
+            identity: Fixed(Self::GRAY).into(),
+            identity_mismatch: Fixed(Self::WHITE).on(Red),
+            modified_unstaged: Red.into(),
+            modified_staged: Red.into(),
+            nested: Yellow.into(),
+            operation: Red.intense(),
+            partial_clone: Fixed(Self::GRAY).into(),
+            sparse: Fixed(Self::GRAY).into(),
+            staged_diff: Green.into(),
+            repo_name: Fixed(Self::BOLD_SILVER).into(),
+            repo_path: Fixed(Self::GRAY).into(),
+            pull_request: Fixed(Self::MAGENTA).into(),
+            branch_count: Fixed(Self::GRAY).into(),
+            change_id: Fixed(Self::MAGENTA).into(),
+            stash: Yellow.into(),
+            stash_old: Red.into(),
+            untracked: Fixed(Self::GRAY).into(),
+            unmerged: Red.into(),
+            worktree: Fixed(Self::CYAN).into(),
+            ..StyleSet::default()
+        }
+    }
+
+    /// Alarm palette for conflicts and in-progress operations
+    pub fn alert() -> Self {
+        let alarm = Fixed(Self::WHITE).on(Red);
+        Self {
+            plain:             alarm,
+            ahead_behind:      alarm,
+            bare:              alarm,
+            branch:            alarm,
+            branch_protected:  alarm,
+            branch_glyph:      alarm,
+            branch_count:      alarm,
+            change_id:         alarm,
+            commit:            alarm,
+            detached:          alarm,
+            diff:              alarm,
+            dirty:             alarm,
+            fetch_age:         alarm,
+            identity:          alarm,
+            identity_mismatch: alarm,
+            nested:            alarm,
+            operation:         alarm,
+            partial_clone:     alarm,
+            pull_request:      alarm,
+            remote_url:        alarm,
+            repo_name:         alarm,
+            repo_path:         alarm,
+            modified_unstaged: alarm,
+            modified_staged:   alarm,
+            sparse:            alarm,
+            staged_diff:       alarm,
+            stash:             alarm,
+            stash_old:         alarm,
+            tag_distance:      alarm,
+            untracked:         alarm,
+            unmerged:          alarm,
+            upstream:          alarm,
+            worktree:          alarm,
+        }
+    }
+
+    /// Override style of one element from `element=spec`, ex: "branch=blue,bold"
+    pub fn set(&mut self, setting: &str) -> Result {
+        let (element, spec) = setting
+            .split_once('=')
+            .ok_or_else(|| format_err!("style \"{}\" must look like ELEMENT=SPEC", setting))?;
+        let style = parse_style(spec)?;
+        let field = self.field_mut(element.trim()).ok_or_else(|| {
+            format_err!(
+                "unknown style element \"{}\"; expected one of: {}",
+                element.trim(),
+                STYLE_ELEMENTS.join(", ")
+            )
+        })?;
+        *field = style;
+        Ok(())
+    }
+
+    /// Style of element named in `STYLE_ELEMENTS`
+    fn field_mut(&mut self, element: &str) -> Option<&mut Style> {
+        Some(match element {
+            "plain" => &mut self.plain,
+            "ahead_behind" => &mut self.ahead_behind,
+            "bare" => &mut self.bare,
+            "branch" => &mut self.branch,
+            "branch_protected" => &mut self.branch_protected,
+            "branch_glyph" => &mut self.branch_glyph,
+            "branch_count" => &mut self.branch_count,
+            "change_id" => &mut self.change_id,
+            "commit" => &mut self.commit,
+            "detached" => &mut self.detached,
+            "diff" => &mut self.diff,
+            "dirty" => &mut self.dirty,
+            "fetch_age" => &mut self.fetch_age,
+            "identity" => &mut self.identity,
+            "identity_mismatch" => &mut self.identity_mismatch,
+            "nested" => &mut self.nested,
+            "operation" => &mut self.operation,
+            "partial_clone" => &mut self.partial_clone,
+            "pull_request" => &mut self.pull_request,
+            "remote_url" => &mut self.remote_url,
+            "repo_name" => &mut self.repo_name,
+            "repo_path" => &mut self.repo_path,
+            "modified_unstaged" => &mut self.modified_unstaged,
+            "modified_staged" => &mut self.modified_staged,
+            "sparse" => &mut self.sparse,
+            "staged_diff" => &mut self.staged_diff,
+            "stash" => &mut self.stash,
+            "stash_old" => &mut self.stash_old,
+            "tag_distance" => &mut self.tag_distance,
+            "untracked" => &mut self.untracked,
+            "unmerged" => &mut self.unmerged,
+            "upstream" => &mut self.upstream,
+            "worktree" => &mut self.worktree,
+            _ => return None,
+        })
+    }
+
+    /// Map colors the terminal can't show to the nearest ones it can
+    pub fn fit_depth(&mut self, depth: ColorDepth) {
+        for element in STYLE_ELEMENTS {
+            if let Some(style) = self.field_mut(element) {
+                *style = fit_style(*style, depth);
+            }
+        }
+    }
+
+    /// Simple git prompt emulation
+    pub fn simple() -> Self {
+        Self {
+            branch: Fixed(Self::CYAN).into(),
+            dirty: Red.into(),
+            ..StyleSet::default()
+        }
+    }
+}
+
+/// Parse comma-separated style spec: a foreground color, optional `bold`, and
+/// optional `bg:COLOR` background, ex: "blue,bold", "245", "#ff8700,bg:black".
+///
+/// Colors are names, 256-color numbers or `#rrggbb` hex, which is kept as 24-bit
+/// color; `StyleSet::fit_depth` maps it to the palette of other terminals.
+/// `bold` selects the bright variant of named colors.
+pub fn parse_style(spec: &str) -> Result<Style> {
+    let (mut fg, mut bg, mut bold) = (None, None, false);
+    for part in spec.split(',').map(str::trim) {
+        match part {
+            "bold" | "bright" => bold = true,
+            p if p.starts_with("bg:") => bg = Some(parse_color(&p[3..])?),
+            p => fg = Some(parse_color(p)?),
+        }
+    }
+    let fg = match fg {
+        Some(Fixed(n)) if bold && n < 8 => Fixed(n + 8),
+        Some(c) => c,
+        None if bg.is_none() && !bold => return Ok(Style::default()),
+        None => return Err(format_err!("style \"{}\" needs a foreground color", spec)),
+    };
+    Ok(match bg {
+        Some(bg) => fg.on(bg),
+        None => fg.into(),
+    })
+}
+
+/// Parse color name or 256-color number into a palette color, or `#rrggbb`
+/// hex into an RGB color
+fn parse_color(color: &str) -> Result<Color> {
+    let color = color.to_lowercase();
+    if let Some(i) = COLOR_NAMES.iter().position(|&c| c == color) {
+        return Ok(Fixed(i as u8));
+    }
+    if let Some(hex) = color.strip_prefix('#') {
+        let rgb = u32::from_str_radix(hex, 16)
+            .ok()
+            .filter(|_| hex.len() == 6)
+            .ok_or_else(|| format_err!("invalid hex color \"{}\"", color))?;
+        return Ok(RGB((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8));
+    }
+    color.parse().map(Fixed).map_err(|_| {
+        format_err!(
+            "invalid color \"{}\"; expected a name ({}), 0-255 or #rrggbb",
+            color,
+            COLOR_NAMES.join(", ")
+        )
+    })
+}
+
+/// Foreground of `style` as `#rrggbb`, for status bars that take hex colors.
+/// Named and palette colors get their xterm RGB values, named ones the bright
+/// variant if the style is intense
+pub fn hex_color(style: &Style) -> Option<String> {
+    Some(hex_rgb(style.foreground?, style.is_intense))
+}
+
+/// Background of `style` as `#rrggbb`, like `hex_color`
+pub fn hex_background(style: &Style) -> Option<String> {
+    Some(hex_rgb(style.background?, false))
+}
+
+/// `color` as `#rrggbb`, the bright variant of named colors if `bright`
+fn hex_rgb(color: Color, bright: bool) -> String {
+    let named = [Black, Red, Green, Yellow, Blue, Purple, Cyan, White];
+    let (r, g, b) = match color {
+        RGB(r, g, b) => (r, g, b),
+        Fixed(n) => palette_rgb(n),
+        c => {
+            let n = named.iter().position(|&n| n == c).unwrap_or_default() as u8;
+            palette_rgb(if bright { n + 8 } else { n })
+        }
+    };
+    format!("#{:02x}{:02x}{:02x}", r, g, b)
+}
+
+/// `style` with colors the terminal can't show mapped to the nearest ones it
+/// can. Bright foregrounds of 16-color terminals are drawn intense
+fn fit_style(mut style: Style, depth: ColorDepth) -> Style {
+    if let Some(fg) = style.foreground {
+        let (fg, bright) = fit_color(fg, depth);
+        style.foreground = Some(fg);
+        style.is_intense |= bright;
+    }
+    if let Some(bg) = style.background {
+        style.background = Some(fit_color(bg, depth).0);
+    }
+    style
+}
+
+/// Nearest color to `color` the terminal can show, and whether it's the bright
+/// variant of a 16-color terminal's color
+fn fit_color(color: Color, depth: ColorDepth) -> (Color, bool) {
+    let n = match (depth, color) {
+        (ColorDepth::TrueColor, c) => return (c, false),
+        (ColorDepth::Ansi256, RGB(r, g, b)) => return (Fixed(nearest_256(r, g, b)), false),
+        (ColorDepth::Ansi256, c) => return (c, false),
+        (ColorDepth::Ansi16, RGB(r, g, b)) => nearest_16((r, g, b)),
+        (ColorDepth::Ansi16, Fixed(n)) if n >= 16 => nearest_16(palette_rgb(n)),
+        (ColorDepth::Ansi16, Fixed(n)) => n,
+        (ColorDepth::Ansi16, c) => return (c, false),
+    };
+    let named = [Black, Red, Green, Yellow, Blue, Purple, Cyan, White];
+    (named[usize::from(n % 8)], n >= 8)
+}
+
+/// RGB of the xterm 16 colors
+const ANSI_RGB: [(u8, u8, u8); 16] = [
+    (0, 0, 0),
+    (205, 0, 0),
+    (0, 205, 0),
+    (205, 205, 0),
+    (0, 0, 238),
+    (205, 0, 205),
+    (0, 205, 205),
+    (229, 229, 229),
+    (127, 127, 127),
+    (255, 0, 0),
+    (0, 255, 0),
+    (255, 255, 0),
+    (92, 92, 255),
+    (255, 0, 255),
+    (0, 255, 255),
+    (255, 255, 255),
+];
+
+/// Levels of each channel in the 6x6x6 cube of the 256-color palette
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+/// RGB of 256-color palette entry `n`
+fn palette_rgb(n: u8) -> (u8, u8, u8) {
+    match n {
+        0..=15 => ANSI_RGB[usize::from(n)],
+        16..=231 => {
+            let i = usize::from(n - 16);
+            (
+                CUBE_LEVELS[i / 36],
+                CUBE_LEVELS[i / 6 % 6],
+                CUBE_LEVELS[i % 6],
+            )
+        }
+        _ => {
+            let gray = 8 + 10 * (n - 232);
+            (gray, gray, gray)
+        }
+    }
+}
+
+/// Squared distance between two RGB colors
+fn rgb_dist((r, g, b): (u8, u8, u8), (r2, g2, b2): (u8, u8, u8)) -> i32 {
+    let d = |a: u8, b: u8| (i32::from(a) - i32::from(b)).pow(2);
+    d(r, r2) + d(g, g2) + d(b, b2)
+}
+
+/// Nearest of the 16 colors
+fn nearest_16(rgb: (u8, u8, u8)) -> u8 {
+    (0..16u8)
+        .min_by_key(|&n| rgb_dist(rgb, ANSI_RGB[usize::from(n)]))
+        .unwrap_or_default()
+}
+
+/// Nearest color in the 6x6x6 cube or gray ramp of the 256-color palette
+pub(crate) fn nearest_256(r: u8, g: u8, b: u8) -> u8 {
+    let dist = |rgb2| rgb_dist((r, g, b), rgb2);
+    let level = |c: u8| {
+        (0..6)
+            .min_by_key(|&i| (i32::from(CUBE_LEVELS[i]) - i32::from(c)).abs())
+            .unwrap()
+    };
+    let (ri, gi, bi) = (level(r), level(g), level(b));
+    let cube = (16 + 36 * ri + 6 * gi + bi) as u8;
+    let cube_rgb = (CUBE_LEVELS[ri], CUBE_LEVELS[gi], CUBE_LEVELS[bi]);
+    let avg = (u32::from(r) + u32::from(g) + u32::from(b)) / 3;
+    let gray_i = ((avg.saturating_sub(8) + 5) / 10).min(23) as u8;
+    let gray = 8 + 10 * gray_i;
+    if dist((gray, gray, gray)) < dist(cube_rgb) {
+        232 + gray_i
+    } else {
+        cube
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn style_specs() -> Result {
+        assert_eq!(parse_color("Blue")?, Fixed(4));
+        assert_eq!(parse_color("245")?, Fixed(245));
+        assert_eq!(parse_color("#ff8700")?, RGB(255, 135, 0));
+        assert!(parse_color("#ff87").is_err());
+        assert!(parse_style("blue,bold,bg:256").is_err());
+
+        let mut styles = StyleSet::standard();
+        styles.set("untracked=245")?;
+        assert!(styles.set("brnch=blue").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn builtin_themes() -> Result {
+        for (_, settings) in THEMES {
+            let mut styles = StyleSet::standard();
+            for setting in *settings {
+                styles.set(setting)?;
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn hex_colors() {
+        let hex = |spec| hex_color(&parse_style(spec).unwrap());
+        assert_eq!(hex("#ff8700"), Some("#ff8700".to_string()));
+        assert_eq!(hex("245"), Some("#8a8a8a".to_string()));
+        assert_eq!(hex("blue,bold"), Some("#5c5cff".to_string()));
+        assert_eq!(hex_color(&Red.into()), Some("#cd0000".to_string()));
+        assert_eq!(hex_color(&Style::default()), None);
+        assert_eq!(
+            hex_background(&Black.on(Green)),
+            Some("#00cd00".to_string())
+        );
+    }
+
+    #[test]
+    fn color_depths() -> Result {
+        let style = parse_style("#ff8700,bg:#808080")?;
+        let fit = |depth| {
+            let s = fit_style(style, depth);
+            (s.foreground, s.background, s.is_intense)
+        };
+        assert_eq!(
+            fit(ColorDepth::TrueColor),
+            (Some(RGB(255, 135, 0)), Some(RGB(128, 128, 128)), false)
+        );
+        assert_eq!(
+            fit(ColorDepth::Ansi256),
+            (Some(Fixed(208)), Some(Fixed(244)), false)
+        );
+        assert_eq!(fit(ColorDepth::Ansi16), (Some(Yellow), Some(Black), false));
+        assert_eq!(fit_color(Fixed(12), ColorDepth::Ansi16), (Blue, true));
+
+        let depth = |ct, term| ColorDepth::from_env(ct, Some(term), false);
+        assert_eq!(
+            depth(Some("truecolor"), "xterm-256color"),
+            ColorDepth::TrueColor
+        );
+        assert_eq!(depth(None, "xterm-256color"), ColorDepth::Ansi256);
+        assert_eq!(depth(None, "linux"), ColorDepth::Ansi16);
+        Ok(())
+    }
+}
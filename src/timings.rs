@@ -0,0 +1,153 @@
+//! Opt-in local log of per-run timings, enabled with `--timings`
+use anyhow::format_err;
+use gitpr::{find_git_dir, Result};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    env,
+    fs::{self, OpenOptions},
+    hash::{Hash, Hasher},
+    io::Write,
+    path::PathBuf,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Measure time spent in each stage of a run
+#[derive(Debug)]
+pub struct Timer {
+    start:  Instant,
+    last:   Instant,
+    stages: Vec<(&'static str, f64)>,
+}
+
+impl Timer {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        Self {
+            start:  now,
+            last:   now,
+            stages: vec![],
+        }
+    }
+
+    /// Record milliseconds elapsed since previous stage ended
+    pub fn stage(&mut self, name: &'static str) {
+        let now = Instant::now();
+        self.stages
+            .push((name, (now - self.last).as_secs_f64() * 1000.0));
+        self.last = now;
+    }
+
+    /// Append record to timings log as tab-separated
+    /// `timestamp  repo-hash  total-ms  stage=ms,...`
+    pub fn record(&self) -> Result {
+        let total = self.start.elapsed().as_secs_f64() * 1000.0;
+        let stages = self
+            .stages
+            .iter()
+            .map(|(name, ms)| format!("{}={:.3}", name, ms))
+            .collect::<Vec<_>>()
+            .join(",");
+        let path = log_path().ok_or_else(|| format_err!("cannot find data directory"))?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut log = OpenOptions::new().create(true).append(true).open(path)?;
+        writeln!(
+            log,
+            "{}\t{}\t{:.3}\t{}",
+            SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            current_repo_hash()?,
+            total,
+            stages
+        )?;
+        Ok(())
+    }
+}
+
+/// Log location: `$XDG_DATA_HOME/gitpr/timings.log`, falling back to
+/// `~/.local/share/gitpr/timings.log`
+fn log_path() -> Option<PathBuf> {
+    env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|h| PathBuf::from(h).join(".local/share")))
+        .map(|d| d.join("gitpr").join("timings.log"))
+}
+
+/// Short hash of the current repo's git dir, so the log doesn't record paths
+fn current_repo_hash() -> Result<String> {
+    let mut hasher = DefaultHasher::new();
+    find_git_dir(&env::current_dir()?).hash(&mut hasher);
+    Ok(format!("{:016x}", hasher.finish()))
+}
+
+/// Timings collected for one repo
+#[derive(Debug, Default)]
+struct RepoTimings {
+    totals: Vec<f64>,
+    stages: HashMap<String, Vec<f64>>,
+}
+
+/// Print per-repo summary of the timings log, slowest repos first
+pub fn report<W: Write>(buf: &mut W) -> Result {
+    let path = log_path().ok_or_else(|| format_err!("cannot find data directory"))?;
+    let log = fs::read_to_string(&path)
+        .map_err(|_| format_err!("no timings recorded at {}", path.display()))?;
+    let mut repos: HashMap<&str, RepoTimings> = HashMap::new();
+    for line in log.lines() {
+        let fields = line.split('\t').collect::<Vec<_>>();
+        if fields.len() != 4 {
+            continue;
+        }
+        // Skip corrupt or hand-edited samples, ex: "NaN", which parses
+        let ms = |s: &str| s.parse::<f64>().ok().filter(|ms| ms.is_finite());
+        let total = match ms(fields[2]) {
+            Some(total) => total,
+            None => continue,
+        };
+        let entry = repos.entry(fields[1]).or_default();
+        entry.totals.push(total);
+        for stage in fields[3].split(',') {
+            let mut split = stage.splitn(2, '=');
+            if let (Some(name), Some(stage_ms)) = (split.next(), split.next().and_then(ms)) {
+                entry
+                    .stages
+                    .entry(name.to_string())
+                    .or_default()
+                    .push(stage_ms);
+            }
+        }
+    }
+    let current = current_repo_hash().unwrap_or_default();
+    let mut repos = repos.into_iter().collect::<Vec<_>>();
+    repos.sort_by(|a, b| mean(&b.1.totals).total_cmp(&mean(&a.1.totals)));
+    for (hash, mut t) in repos {
+        t.totals.sort_by(f64::total_cmp);
+        let p90 = t.totals[(t.totals.len() - 1) * 90 / 100];
+        let mut stages = t
+            .stages
+            .iter()
+            .map(|(name, ms)| format!("{} {:.2}ms", name, mean(ms)))
+            .collect::<Vec<_>>();
+        stages.sort();
+        writeln!(
+            buf,
+            "{}{}  runs {:>5}  mean {:>8.2}ms  p90 {:>8.2}ms  ({})",
+            hash,
+            if hash == current { "*" } else { " " },
+            t.totals.len(),
+            mean(&t.totals),
+            p90,
+            stages.join(", ")
+        )?;
+    }
+    writeln!(buf, "* current repo")?;
+    Ok(())
+}
+
+/// Arithmetic mean, or 0 if empty
+fn mean(v: &[f64]) -> f64 {
+    if v.is_empty() {
+        return 0.0;
+    }
+    v.iter().sum::<f64>() / v.len() as f64
+}
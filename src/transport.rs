@@ -0,0 +1,380 @@
+//! Connection between the daemon and its clients: a Unix domain socket, or a
+//! named pipe on Windows. Each connection carries one request line and one
+//! response line
+pub use imp::{connect, socket_path, Listener, Stream};
+
+#[cfg(unix)]
+mod imp {
+    use anyhow::format_err;
+    use gitpr::Result;
+    use log::debug;
+    use std::{
+        env,
+        fs::{self, DirBuilder, Permissions},
+        io::{self, BufRead, BufReader, Write},
+        os::unix::{
+            fs::{DirBuilderExt, MetadataExt, PermissionsExt},
+            io::{AsRawFd, FromRawFd},
+            net::{UnixListener, UnixStream},
+        },
+        path::{Path, PathBuf},
+        time::Duration,
+    };
+
+    /// Socket path: `$XDG_RUNTIME_DIR/gitpr.sock`, or else in a `gitpr-<uid>`
+    /// dir of the temp dir that only the user can enter
+    pub fn socket_path() -> PathBuf {
+        match env::var_os("XDG_RUNTIME_DIR") {
+            Some(dir) => PathBuf::from(dir).join("gitpr.sock"),
+            None => env::temp_dir()
+                .join(format!("gitpr-{}", uid()))
+                .join("gitpr.sock"),
+        }
+    }
+
+    /// Real user id of this process
+    fn uid() -> u32 {
+        // SAFETY: getuid has no preconditions and can't fail
+        unsafe { libc::getuid() }
+    }
+
+    /// Create dir of socket `path` if missing, only accessible to the user, and
+    /// check that an existing one is the user's and closed to others, since
+    /// anyone who can replace the socket can feed prompts fake output
+    fn private_dir(path: &Path) -> Result {
+        let dir = match path.parent() {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+        if !dir.exists() {
+            DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+        }
+        let meta = fs::metadata(dir)?;
+        if meta.uid() != uid() || meta.mode() & 0o077 != 0 {
+            return Err(format_err!(
+                "{} must be owned by the user and closed to others",
+                dir.display()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Listening socket of the daemon, removed when dropped unless systemd
+    /// made it
+    pub struct Listener {
+        listener: UnixListener,
+        path:     Option<PathBuf>,
+        timeout:  Duration,
+    }
+
+    impl Listener {
+        /// Socket passed by systemd socket activation, or else a new one at
+        /// `path` only the user can use. Accepted clients can stall for at
+        /// most `timeout` on each read or write
+        pub fn bind(path: &Path, timeout: Duration) -> Result<Listener> {
+            if let Some(listener) = activated() {
+                debug!("serving socket passed by systemd");
+                return Ok(Listener {
+                    listener,
+                    path: None,
+                    timeout,
+                });
+            }
+            private_dir(path)?;
+            if let Ok(meta) = fs::symlink_metadata(path) {
+                if UnixStream::connect(path).is_ok() {
+                    return Err(format_err!(
+                        "daemon already listening on {}",
+                        path.display()
+                    ));
+                }
+                if meta.uid() != uid() {
+                    return Err(format_err!("{} is owned by another user", path.display()));
+                }
+                // Left over from a daemon that didn't exit cleanly
+                fs::remove_file(path)?;
+            }
+            let listener = UnixListener::bind(path)?;
+            fs::set_permissions(path, Permissions::from_mode(0o600))?;
+            debug!("listening on {}", path.display());
+            Ok(Listener {
+                listener,
+                path: Some(path.to_path_buf()),
+                timeout,
+            })
+        }
+
+        /// Wait up to `timeout` for a client to connect, returning false if
+        /// none did
+        pub fn wait(&self, timeout: Duration) -> io::Result<bool> {
+            let mut fd = libc::pollfd {
+                fd:      self.listener.as_raw_fd(),
+                events:  libc::POLLIN,
+                revents: 0,
+            };
+            let ms = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+            loop {
+                // SAFETY: `fd` is a single valid `pollfd` that outlives the call
+                match unsafe { libc::poll(&mut fd, 1, ms) } {
+                    -1 => {
+                        let e = io::Error::last_os_error();
+                        if e.kind() != io::ErrorKind::Interrupted {
+                            return Err(e);
+                        }
+                    }
+                    n => return Ok(n > 0),
+                }
+            }
+        }
+
+        /// Next client
+        pub fn accept(&mut self) -> io::Result<Stream> {
+            let (stream, _) = self.listener.accept()?;
+            stream.set_read_timeout(Some(self.timeout))?;
+            stream.set_write_timeout(Some(self.timeout))?;
+            Ok(Stream(stream))
+        }
+    }
+
+    impl Drop for Listener {
+        fn drop(&mut self) {
+            // So the next daemon can bind the path
+            if let Some(path) = &self.path {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+
+    /// Listening socket passed by systemd socket activation as fd 3, if any
+    fn activated() -> Option<UnixListener> {
+        let pid = env::var("LISTEN_PID").ok()?.parse::<u32>().ok()?;
+        let fds = env::var("LISTEN_FDS").ok()?.parse::<u32>().ok()?;
+        if pid != std::process::id() || fds < 1 {
+            return None;
+        }
+        // Not for processes started by the daemon, ex: git
+        for key in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            env::remove_var(key);
+        }
+        // SAFETY: with `LISTEN_PID` naming this process, systemd passed it fd
+        // 3 as an open listening socket that nothing else owns
+        Some(unsafe { UnixListener::from_raw_fd(3) })
+    }
+
+    /// Connect to the daemon listening on `path`, waiting at most `timeout`
+    /// for its response. A socket of another user is never used
+    pub fn connect(path: &Path, timeout: Duration) -> Result<Stream> {
+        let no_daemon = || format_err!("no daemon listening on {}", path.display());
+        if fs::symlink_metadata(path).map_err(|_| no_daemon())?.uid() != uid() {
+            return Err(format_err!("{} is owned by another user", path.display()));
+        }
+        let stream = UnixStream::connect(path).map_err(|_| no_daemon())?;
+        stream.set_read_timeout(Some(timeout))?;
+        Ok(Stream(stream))
+    }
+
+    /// Connection to a client or the daemon
+    pub struct Stream(UnixStream);
+
+    impl Stream {
+        /// Read a line, or up to the end if the peer sends no newline
+        pub fn read_line(&mut self) -> io::Result<String> {
+            let mut line = String::new();
+            BufReader::new(&self.0).read_line(&mut line)?;
+            Ok(line)
+        }
+
+        /// Write `line`, which must not contain a newline, and end it
+        pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+            self.0.write_all(format!("{}\n", line).as_bytes())
+        }
+    }
+}
+
+#[cfg(windows)]
+mod imp {
+    use anyhow::format_err;
+    use gitpr::Result;
+    use log::debug;
+    use std::{
+        env,
+        ffi::c_void,
+        fs::{File, OpenOptions},
+        io::{self, BufRead, BufReader, Write},
+        iter, mem,
+        os::windows::{
+            ffi::OsStrExt,
+            io::{AsRawHandle, FromRawHandle, RawHandle},
+        },
+        path::{Path, PathBuf},
+        ptr,
+        sync::mpsc,
+        thread,
+        time::Duration,
+    };
+
+    const INVALID_HANDLE_VALUE: RawHandle = -1isize as RawHandle;
+    const PIPE_ACCESS_DUPLEX: u32 = 0x0000_0003;
+    const FILE_FLAG_FIRST_PIPE_INSTANCE: u32 = 0x0008_0000;
+    const PIPE_TYPE_BYTE: u32 = 0x0000_0000;
+    const PIPE_REJECT_REMOTE_CLIENTS: u32 = 0x0000_0008;
+    const PIPE_UNLIMITED_INSTANCES: u32 = 255;
+    const ERROR_ACCESS_DENIED: i32 = 5;
+    const ERROR_PIPE_CONNECTED: i32 = 535;
+
+    /// Pipe buffer size, well over any response
+    const BUFFER_SIZE: u32 = 64 * 1024;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn CreateNamedPipeW(
+            name: *const u16,
+            open_mode: u32,
+            pipe_mode: u32,
+            max_instances: u32,
+            out_buffer_size: u32,
+            in_buffer_size: u32,
+            default_timeout: u32,
+            security_attributes: *mut c_void,
+        ) -> RawHandle;
+        fn ConnectNamedPipe(pipe: RawHandle, overlapped: *mut c_void) -> i32;
+    }
+
+    /// Pipe name for the user, ex: `\\.\pipe\gitpr-alice`
+    pub fn socket_path() -> PathBuf {
+        let user = env::var("USERNAME").unwrap_or_default();
+        PathBuf::from(format!(r"\\.\pipe\gitpr-{}", user))
+    }
+
+    /// Named pipe of the daemon. Its default security only lets the user and
+    /// administrators write to it, and remote clients are refused. Unlike a
+    /// Unix socket, a pipe can't wait for a client with a timeout, so
+    /// `--idle-timeout` is ignored
+    pub struct Listener {
+        name:    Vec<u16>,
+        /// Pipe instance the next client connects to
+        next:    File,
+        timeout: Duration,
+    }
+
+    impl Listener {
+        /// First instance of pipe `path`, which fails if another daemon has
+        /// it. Accepted clients can stall for at most `timeout` on each read
+        pub fn bind(path: &Path, timeout: Duration) -> Result<Listener> {
+            let name = path
+                .as_os_str()
+                .encode_wide()
+                .chain(iter::once(0))
+                .collect::<Vec<_>>();
+            let next = match create_instance(&name, true) {
+                Ok(next) => next,
+                Err(e) if e.raw_os_error() == Some(ERROR_ACCESS_DENIED) => {
+                    return Err(format_err!(
+                        "daemon already listening on {}",
+                        path.display()
+                    ))
+                }
+                Err(e) => return Err(e.into()),
+            };
+            debug!("listening on {}", path.display());
+            Ok(Listener {
+                name,
+                next,
+                timeout,
+            })
+        }
+
+        /// Always true, since a pipe can't wait with a timeout
+        pub fn wait(&self, _timeout: Duration) -> io::Result<bool> {
+            Ok(true)
+        }
+
+        /// Next client, with a new instance made for the one after
+        pub fn accept(&mut self) -> io::Result<Stream> {
+            // SAFETY: `self.next` is an open pipe handle and the call is
+            // synchronous, so no overlapped struct is needed
+            if unsafe { ConnectNamedPipe(self.next.as_raw_handle(), ptr::null_mut()) } == 0 {
+                let e = io::Error::last_os_error();
+                // Client connected before the call, which is fine
+                if e.raw_os_error() != Some(ERROR_PIPE_CONNECTED) {
+                    return Err(e);
+                }
+            }
+            let next = create_instance(&self.name, false)?;
+            Ok(Stream {
+                file:    mem::replace(&mut self.next, next),
+                timeout: self.timeout,
+            })
+        }
+    }
+
+    /// New instance of NUL-terminated pipe `name`
+    fn create_instance(name: &[u16], first: bool) -> io::Result<File> {
+        let mut open_mode = PIPE_ACCESS_DUPLEX;
+        if first {
+            open_mode |= FILE_FLAG_FIRST_PIPE_INSTANCE;
+        }
+        // SAFETY: `name` is NUL-terminated and outlives the call; null
+        // security attributes select the default security
+        let handle = unsafe {
+            CreateNamedPipeW(
+                name.as_ptr(),
+                open_mode,
+                PIPE_TYPE_BYTE | PIPE_REJECT_REMOTE_CLIENTS,
+                PIPE_UNLIMITED_INSTANCES,
+                BUFFER_SIZE,
+                BUFFER_SIZE,
+                0,
+                ptr::null_mut(),
+            )
+        };
+        if handle == INVALID_HANDLE_VALUE {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `handle` is a new pipe handle that nothing else owns
+        Ok(unsafe { File::from_raw_handle(handle) })
+    }
+
+    /// Connect to the daemon listening on pipe `path`, waiting at most
+    /// `timeout` for its response
+    pub fn connect(path: &Path, timeout: Duration) -> Result<Stream> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(|_| format_err!("no daemon listening on {}", path.display()))?;
+        Ok(Stream { file, timeout })
+    }
+
+    /// Connection to a client or the daemon
+    pub struct Stream {
+        file:    File,
+        timeout: Duration,
+    }
+
+    impl Stream {
+        /// Read a line, or up to the end if the peer sends no newline. Pipe
+        /// reads can't time out, so a peer that stalls past the timeout only
+        /// holds up a helper thread
+        pub fn read_line(&mut self) -> io::Result<String> {
+            let file = self.file.try_clone()?;
+            let (tx, rx) = mpsc::channel();
+            thread::spawn(move || {
+                let mut line = String::new();
+                let result = BufReader::new(file).read_line(&mut line).map(|_| line);
+                let _ = tx.send(result);
+            });
+            rx.recv_timeout(self.timeout).unwrap_or_else(|_| {
+                Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "pipe read timed out",
+                ))
+            })
+        }
+
+        /// Write `line`, which must not contain a newline, and end it
+        pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+            self.file.write_all(format!("{}\n", line).as_bytes())
+        }
+    }
+}
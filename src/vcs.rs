@@ -0,0 +1,35 @@
+//! Version control systems prompts can be shown for. Each implements
+//! [`VcsDriver`] in its own module and is listed in [`DRIVERS`]
+use crate::{
+    hg::Hg,
+    jj::Jj,
+    repo::{GatherOptions, Git, Vcs},
+    Repo, Result,
+};
+use std::path::Path;
+
+/// How to spot repos of a version control system, read their status, and
+/// which format tokens it fills in
+pub trait VcsDriver: Sync {
+    /// System this driver reads, named by `%n`
+    fn vcs(&self) -> Vcs;
+
+    /// True if `dir` is the top of a working tree of this system
+    fn detect(&self, dir: &Path) -> bool;
+
+    /// Gather status of the repo in the current directory
+    fn collect(&self, opts: &GatherOptions<'_>) -> Result<Repo>;
+
+    /// Format tokens the system can fill in; the rest are left empty, and
+    /// nothing is run to look them up
+    fn tokens(&self) -> &'static [char];
+}
+
+/// Drivers in the order they're tried on each dir. jj comes before git, since
+/// colocated jj repos have a `.git` too
+pub static DRIVERS: &[&dyn VcsDriver] = &[&Jj, &Git, &Hg];
+
+/// Driver whose working tree `dir` is the top of, if any
+pub fn detect(dir: &Path) -> Option<&'static dyn VcsDriver> {
+    DRIVERS.iter().copied().find(|d| d.detect(dir))
+}
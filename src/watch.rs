@@ -0,0 +1,304 @@
+//! Keep printing status as it changes, for `--watch`
+use crate::{report_error, run, timings::Timer, Arg};
+use gitpr::{
+    repo::{find_git_dir, metadata_fingerprint},
+    Result,
+};
+use log::debug;
+use std::{
+    env,
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Longest wait for a change before checking whether a render is due
+const POLL: Duration = Duration::from_millis(200);
+
+/// How long the repo must stay unchanged before status is rendered, so a
+/// rebase or checkout in progress isn't caught halfway
+const SETTLE: Duration = Duration::from_millis(300);
+
+/// Render status whenever the repo changes and has settled, but at most once per
+/// `min_refresh`, and at least every `interval`. Each render that differs from
+/// the last is written to `buf` as a line. Runs until writing fails, ex: the
+/// reader of a pipe exits
+pub fn watch<W: Write>(
+    args: &Arg,
+    interval: Duration,
+    min_refresh: Duration,
+    buf: &mut W,
+) -> Result {
+    let mut changes = Changes::new(&env::current_dir()?);
+    let mut changed_at: Option<Instant> = None;
+    let mut rendered_at: Option<Instant> = None;
+    let mut last: Option<Vec<u8>> = None;
+    loop {
+        if changes.wait(POLL) {
+            changed_at = Some(Instant::now());
+        }
+        let settled = changed_at.is_some_and(|t| t.elapsed() >= SETTLE);
+        // Changes seen meanwhile stay pending until the limit passes
+        let limited = rendered_at.is_some_and(|t| t.elapsed() < min_refresh);
+        let due = rendered_at.is_none_or(|t| t.elapsed() >= interval);
+        if (settled && !limited) || due {
+            changed_at = None;
+            rendered_at = Some(Instant::now());
+            let mut out = vec![];
+            if let Err(e) = run(args, &mut out, &mut Timer::new()) {
+                report_error(args, &e);
+                out.clear();
+            }
+            // Git refreshing the index while rendering isn't a change to show
+            changes.discard();
+            if !out.ends_with(b"\n") {
+                out.push(b'\n');
+            }
+            if last.as_ref() != Some(&out) {
+                match buf.write_all(&out).and_then(|_| buf.flush()) {
+                    Err(e) if e.kind() == ErrorKind::BrokenPipe => return Ok(()),
+                    r => r?,
+                }
+                last = Some(out);
+            }
+        }
+    }
+}
+
+/// How changes to the repo are noticed
+enum Changes {
+    /// inotify watches on worktree dirs and git metadata
+    #[cfg(target_os = "linux")]
+    Events(events::Watcher),
+    /// Git metadata modification times, checked every `POLL`. Worktree edits
+    /// alone don't touch it, so they're only seen by the next `interval`
+    /// render; walking the worktree that often would cost more than a render
+    Poll {
+        git_dir: Option<PathBuf>,
+        seen:    Option<Vec<Option<SystemTime>>>,
+    },
+}
+
+impl Changes {
+    /// Watch the repo containing `dir` with inotify if it can, else poll
+    fn new(dir: &Path) -> Changes {
+        let git_dir = find_git_dir(dir);
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(git_dir) = &git_dir {
+                match events::Watcher::new(git_dir, dir) {
+                    Ok(watcher) => return Changes::Events(watcher),
+                    Err(e) => debug!("can't watch repo, polling instead: {}", e),
+                }
+            }
+        }
+        let seen = git_dir.as_deref().map(metadata_fingerprint);
+        Changes::Poll { git_dir, seen }
+    }
+
+    /// Wait up to `timeout`, returning whether the repo changed
+    fn wait(&mut self, timeout: Duration) -> bool {
+        match self {
+            #[cfg(target_os = "linux")]
+            Changes::Events(watcher) => watcher.wait(timeout),
+            Changes::Poll { git_dir, seen } => {
+                thread::sleep(timeout);
+                let now = git_dir.as_deref().map(metadata_fingerprint);
+                let changed = now != *seen;
+                *seen = now;
+                changed
+            }
+        }
+    }
+
+    /// Forget changes seen so far
+    fn discard(&mut self) {
+        match self {
+            #[cfg(target_os = "linux")]
+            Changes::Events(watcher) => {
+                watcher.wait(Duration::from_secs(0));
+            }
+            Changes::Poll { git_dir, seen } => {
+                *seen = git_dir.as_deref().map(metadata_fingerprint);
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod events {
+    use gitpr::{
+        git,
+        repo::{common_dir, find_work_tree},
+    };
+    use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+    use log::debug;
+    use std::{
+        collections::HashMap,
+        fs, io,
+        os::unix::io::AsRawFd,
+        path::{Path, PathBuf},
+        time::Duration,
+    };
+
+    /// inotify watches on every worktree dir git doesn't ignore, the git dir,
+    /// and the refs of its common dir
+    pub struct Watcher {
+        inotify: Inotify,
+        /// Dir of each watch whose new subdirs are watched too, and whether
+        /// it's in the worktree
+        dirs:    HashMap<WatchDescriptor, (PathBuf, bool)>,
+        buffer:  Vec<u8>,
+    }
+
+    impl Watcher {
+        /// Watch the repo with git dir `git_dir`, containing `dir`. Fails if
+        /// inotify is unavailable or out of watches
+        pub fn new(git_dir: &Path, dir: &Path) -> io::Result<Watcher> {
+            let mut watcher = Watcher {
+                inotify: Inotify::init()?,
+                dirs:    HashMap::new(),
+                buffer:  vec![0; 4096],
+            };
+            let common = common_dir(git_dir);
+            watcher.inotify.add_watch(git_dir, mask())?;
+            if common != git_dir {
+                watcher.inotify.add_watch(&common, mask())?;
+            }
+            watcher.add_tree(&common.join("refs"), false, &[])?;
+            if let Some(work_tree) = find_work_tree(dir) {
+                let ignored = ignored_dirs(&work_tree);
+                watcher.add_tree(&work_tree, true, &ignored)?;
+            }
+            Ok(watcher)
+        }
+
+        /// Wait up to `timeout` for events, returning whether there were any.
+        /// Dirs created since are watched too
+        pub fn wait(&mut self, timeout: Duration) -> bool {
+            let mut fd = libc::pollfd {
+                fd:      self.inotify.as_raw_fd(),
+                events:  libc::POLLIN,
+                revents: 0,
+            };
+            // SAFETY: `fd` is a single valid `pollfd` that outlives the call
+            let ready = unsafe { libc::poll(&mut fd, 1, timeout.as_millis() as libc::c_int) };
+            if ready <= 0 {
+                return false;
+            }
+            let mut new_dirs = vec![];
+            loop {
+                let events = match self.inotify.read_events(&mut self.buffer) {
+                    Ok(events) => events,
+                    Err(e) => {
+                        debug!("reading inotify events: {}", e);
+                        break;
+                    }
+                };
+                let mut read = 0;
+                for event in events {
+                    read += 1;
+                    if event.mask.contains(EventMask::IGNORED) {
+                        self.dirs.remove(&event.wd);
+                    } else if event.mask.contains(EventMask::ISDIR)
+                        && event
+                            .mask
+                            .intersects(EventMask::CREATE | EventMask::MOVED_TO)
+                    {
+                        if let (Some((dir, in_work_tree)), Some(name)) =
+                            (self.dirs.get(&event.wd), event.name)
+                        {
+                            new_dirs.push((dir.join(name), *in_work_tree));
+                        }
+                    }
+                }
+                if read == 0 {
+                    break;
+                }
+            }
+            for (dir, in_work_tree) in new_dirs {
+                if in_work_tree && is_ignored(&dir) {
+                    continue;
+                }
+                if let Err(e) = self.add_tree(&dir, in_work_tree, &[]) {
+                    debug!("can't watch {}: {}", dir.display(), e);
+                }
+            }
+            true
+        }
+
+        /// Watch `root` and its subdirs, other than `.git` and `ignored`
+        fn add_tree(
+            &mut self,
+            root: &Path,
+            in_work_tree: bool,
+            ignored: &[PathBuf],
+        ) -> io::Result<()> {
+            let mut dirs = vec![root.to_path_buf()];
+            while let Some(dir) = dirs.pop() {
+                let wd = match self.inotify.add_watch(&dir, mask()) {
+                    Ok(wd) => wd,
+                    // Removed since it was listed
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e),
+                };
+                for entry in fs::read_dir(&dir).into_iter().flatten().flatten() {
+                    let path = entry.path();
+                    if entry.file_type().is_ok_and(|t| t.is_dir())
+                        && entry.file_name() != ".git"
+                        && !ignored.contains(&path)
+                    {
+                        dirs.push(path);
+                    }
+                }
+                self.dirs.insert(wd, (dir, in_work_tree));
+            }
+            Ok(())
+        }
+    }
+
+    /// Events that can change status, on dirs only, without following symlinks
+    fn mask() -> WatchMask {
+        WatchMask::MODIFY
+            | WatchMask::ATTRIB
+            | WatchMask::CREATE
+            | WatchMask::DELETE
+            | WatchMask::MOVE
+            | WatchMask::ONLYDIR
+            | WatchMask::DONT_FOLLOW
+            | WatchMask::EXCL_UNLINK
+    }
+
+    /// Dirs git ignores in `work_tree`, ex: build output, which can hold many
+    /// dirs and change constantly without changing status
+    fn ignored_dirs(work_tree: &Path) -> Vec<PathBuf> {
+        git!(
+            "ls-files",
+            "--others",
+            "--ignored",
+            "--exclude-standard",
+            "--directory",
+            "-z"
+        )
+        .dir(work_tree)
+        .stderr_null()
+        .unchecked()
+        .read()
+        .unwrap_or_default()
+        .split('\0')
+        .filter_map(|p| p.strip_suffix('/'))
+        .map(|p| work_tree.join(p))
+        .collect()
+    }
+
+    /// Whether git ignores new dir `dir`
+    fn is_ignored(dir: &Path) -> bool {
+        git!("check-ignore", "-q", dir)
+            .dir(dir.parent().unwrap_or(dir))
+            .stderr_null()
+            .unchecked()
+            .run()
+            .is_ok_and(|out| out.status.success())
+    }
+}